@@ -0,0 +1,234 @@
+use crate::mem_table::MemTable;
+use arrayvec::ArrayVec;
+use std::cmp::Ordering::{Equal, Greater, Less};
+
+/// the index of a missing child
+const NIL: u32 = u32::MAX;
+/// an AVL tree of fewer than 2^32 nodes is less than 1.45 * 32 levels high
+const MAX_DEPTH: usize = 48;
+/// the bytes every entry of an `ArenaTree` takes up
+pub const ARENA_NODE_SIZE: usize = std::mem::size_of::<ArenaNode>();
+
+struct ArenaNode {
+    key: i64,
+    value: i64,
+    left: u32,
+    right: u32,
+    height: u8,
+}
+
+/// The same AVL tree as `MemoryTable`, with its nodes in one `Vec` (the arena) that link to each
+/// other by index instead of each being boxed.
+///
+/// The arena is allocated up front for the whole capacity, so an insert is a bump of its length
+/// and a flush frees every node at once. Inserts walk down and back up the tree with an explicit
+/// path instead of recursing, so no tree is too deep.
+pub struct ArenaTree {
+    nodes: Vec<ArenaNode>,
+    root: u32,
+    /// the most nodes the arena holds
+    max_nodes: usize,
+}
+
+impl ArenaTree {
+    /// an empty tree whose nodes take up to `capacity` bytes
+    pub fn new(capacity: usize) -> ArenaTree {
+        let max_nodes = (capacity / ARENA_NODE_SIZE).min(NIL as usize);
+        ArenaTree {
+            nodes: Vec::with_capacity(max_nodes),
+            root: NIL,
+            max_nodes,
+        }
+    }
+
+    fn height(&self, node: u32) -> i32 {
+        if node == NIL {
+            0
+        } else {
+            self.nodes[node as usize].height as i32
+        }
+    }
+
+    fn fix_height(&mut self, node: u32) {
+        let height = 1 + self
+            .height(self.nodes[node as usize].left)
+            .max(self.height(self.nodes[node as usize].right));
+        self.nodes[node as usize].height = height as u8;
+    }
+
+    fn rotate_left(&mut self, node: u32) -> u32 {
+        let top = self.nodes[node as usize].right;
+        self.nodes[node as usize].right = self.nodes[top as usize].left;
+        self.fix_height(node);
+        self.nodes[top as usize].left = node;
+        self.fix_height(top);
+        top
+    }
+
+    fn rotate_right(&mut self, node: u32) -> u32 {
+        let top = self.nodes[node as usize].left;
+        self.nodes[node as usize].left = self.nodes[top as usize].right;
+        self.fix_height(node);
+        self.nodes[top as usize].right = node;
+        self.fix_height(top);
+        top
+    }
+
+    /// restores the balance of the subtree at `node`, returns its new root
+    fn balance(&mut self, node: u32) -> u32 {
+        self.fix_height(node);
+        let (left, right) = (
+            self.nodes[node as usize].left,
+            self.nodes[node as usize].right,
+        );
+        let balance = self.height(right) - self.height(left);
+        if balance > 1 {
+            let (inner, outer) = (
+                self.nodes[right as usize].left,
+                self.nodes[right as usize].right,
+            );
+            if self.height(outer) < self.height(inner) {
+                self.nodes[node as usize].right = self.rotate_right(right);
+            }
+            self.rotate_left(node)
+        } else if balance < -1 {
+            let (outer, inner) = (
+                self.nodes[left as usize].left,
+                self.nodes[left as usize].right,
+            );
+            if self.height(outer) < self.height(inner) {
+                self.nodes[node as usize].left = self.rotate_left(left);
+            }
+            self.rotate_right(node)
+        } else {
+            node
+        }
+    }
+
+    /// pushes the path from `node` to the first key at least `lower_bound`
+    fn descend(&self, mut node: u32, lower_bound: i64, stack: &mut Vec<u32>) {
+        while node != NIL {
+            let current = &self.nodes[node as usize];
+            if current.key >= lower_bound {
+                stack.push(node);
+                node = current.left;
+            } else {
+                node = current.right;
+            }
+        }
+    }
+
+    /// the next pair of an in order walk whose path is on `stack`
+    fn next_in_order(&self, stack: &mut Vec<u32>, upper_bound: i64) -> Option<(i64, i64)> {
+        let node = &self.nodes[stack.pop()? as usize];
+        if node.key > upper_bound {
+            stack.clear();
+            return None;
+        }
+        self.descend(node.right, i64::MIN, stack);
+        Some((node.key, node.value))
+    }
+}
+
+impl MemTable for ArenaTree {
+    fn insert(&mut self, key: i64, value: i64) -> bool {
+        let mut path = ArrayVec::<u32, MAX_DEPTH>::new();
+        let mut node = self.root;
+        while node != NIL {
+            let current = &mut self.nodes[node as usize];
+            path.push(node);
+            node = match key.cmp(&current.key) {
+                Less => current.left,
+                Greater => current.right,
+                Equal => {
+                    current.value = value;
+                    return true;
+                }
+            };
+        }
+        if self.nodes.len() >= self.max_nodes {
+            return false;
+        }
+        let mut child = self.nodes.len() as u32;
+        self.nodes.push(ArenaNode {
+            key,
+            value,
+            left: NIL,
+            right: NIL,
+            height: 1,
+        });
+        while let Some(parent) = path.pop() {
+            if key < self.nodes[parent as usize].key {
+                self.nodes[parent as usize].left = child;
+            } else {
+                self.nodes[parent as usize].right = child;
+            }
+            child = self.balance(parent);
+        }
+        self.root = child;
+        true
+    }
+
+    fn get(&self, key: i64) -> Option<i64> {
+        let mut node = self.root;
+        while node != NIL {
+            let current = &self.nodes[node as usize];
+            node = match key.cmp(&current.key) {
+                Less => current.left,
+                Greater => current.right,
+                Equal => return Some(current.value),
+            };
+        }
+        None
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        self.nodes.len() * ARENA_NODE_SIZE
+    }
+
+    fn scan(
+        &self,
+        lower_bound: i64,
+        upper_bound: i64,
+    ) -> Box<dyn Iterator<Item = (i64, i64)> + '_> {
+        let mut stack = Vec::new();
+        self.descend(self.root, lower_bound, &mut stack);
+        Box::new(std::iter::from_fn(move || {
+            self.next_in_order(&mut stack, upper_bound)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+
+    #[test]
+    fn test_stays_balanced() {
+        let mut tree = ArenaTree::new(1 << 20);
+        for i in 0..10_000 {
+            assert!(tree.insert(i, i));
+        }
+        for i in (-10_000..0).rev() {
+            assert!(tree.insert(i, i));
+        }
+        // an AVL tree of n nodes is less than 1.45 log2(n) high
+        assert!(tree.height(tree.root) <= 20);
+        assert_eq!(tree.nodes.capacity(), (1 << 20) / ARENA_NODE_SIZE);
+        assert!((-10_000..10_000).all(|i| tree.get(i) == Some(i)));
+        assert!(tree
+            .scan(i64::MIN, i64::MAX)
+            .eq((-10_000..10_000).map(|i| (i, i))));
+    }
+
+    #[bench]
+    fn bench_insert(b: &mut test::Bencher) {
+        b.iter(|| {
+            let mut tree = ArenaTree::new(1000000 * ARENA_NODE_SIZE);
+            for i in 0..1000000 {
+                tree.insert(i, i);
+            }
+        });
+    }
+}
@@ -1,7 +1,9 @@
-use crate::compaction::LevelIterator;
+use crate::mem_table::MemTable;
 use std::cmp::Ordering::{Equal, Greater, Less};
 
 type Child = Option<Box<Node>>;
+/// the bytes every entry of a `MemoryTable` takes up, each node being an allocation of its own
+pub const NODE_SIZE: usize = std::mem::size_of::<Node>();
 /// iterates over every element in the range [lower_bound, upper_bound]
 /// Does not consume the tree
 /// by default lower_bound is i64::MIN and upper_bound is i64::MAX
@@ -207,16 +209,18 @@ impl Node {
 }
 #[derive(Debug)]
 pub struct MemoryTable {
-    mem_table_size: usize,
-    pub(crate) cur_size: usize,
+    /// the most bytes the nodes can take up
+    capacity: usize,
+    /// the number of nodes in the tree
+    pub(crate) len: usize,
     root: Child,
 }
 
 impl MemoryTable {
-    pub(crate) fn new(mem_table_size: usize) -> Self {
+    pub(crate) fn new(capacity: usize) -> Self {
         MemoryTable {
-            mem_table_size: mem_table_size,
-            cur_size: 0,
+            capacity,
+            len: 0,
             root: None,
         }
     }
@@ -225,28 +229,30 @@ impl MemoryTable {
         ScanIter::new(&self.root)
     }
 
-    pub fn into_level_iter(mut self) -> LevelIterator {
-        LevelIterator::Memtable(self.get_iter().unwrap())
-    }
-
     fn get_iter(&mut self) -> Option<NodeIter> {
         if self.root.is_none() {
             None
         } else {
-            self.cur_size = 0;
+            self.len = 0;
             Some(self.root.take().unwrap().into_iter())
         }
     }
 
+    /// overwriting a key takes no more space, so it works even when the table is full.
     pub(crate) fn insert(&mut self, key: i64, value: i64) -> bool {
-        if self.cur_size >= self.mem_table_size {
+        let is_new = self.get(key).is_none();
+        if is_new && self.size_in_bytes() + NODE_SIZE > self.capacity {
             return false;
         }
         self.root = Some(Node::insert(self.root.take(), key, value));
-        self.cur_size += 1;
+        self.len += is_new as usize;
         true
     }
 
+    pub(crate) fn size_in_bytes(&self) -> usize {
+        self.len * NODE_SIZE
+    }
+
     pub fn scan(&self, key1: i64, key2: i64) -> ScanIter {
         Node::scan(&self.root, key1, key2)
     }
@@ -256,6 +262,28 @@ impl MemoryTable {
     }
 }
 
+impl MemTable for MemoryTable {
+    fn insert(&mut self, key: i64, value: i64) -> bool {
+        MemoryTable::insert(self, key, value)
+    }
+
+    fn get(&self, key: i64) -> Option<i64> {
+        MemoryTable::get(self, key)
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        MemoryTable::size_in_bytes(self)
+    }
+
+    fn scan(
+        &self,
+        lower_bound: i64,
+        upper_bound: i64,
+    ) -> Box<dyn Iterator<Item = (i64, i64)> + '_> {
+        Box::new(MemoryTable::scan(self, lower_bound, upper_bound))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate test;
@@ -264,19 +292,24 @@ mod tests {
 
     #[test]
     fn test_insert() {
-        let mut mem_table = MemoryTable::new(100);
+        let mut mem_table = MemoryTable::new(100 * NODE_SIZE);
         for i in 0..100 {
             assert!(mem_table.insert(i, i));
         }
         assert!(!mem_table.insert(100, 100));
+        // overwrites don't need any more space
+        for i in 0..100 {
+            assert!(mem_table.insert(i, -i));
+        }
+        assert_eq!(mem_table.size_in_bytes(), 100 * NODE_SIZE);
         for i in 0..100 {
-            assert_eq!(mem_table.get(i), Some(i));
+            assert_eq!(mem_table.get(i), Some(-i));
         }
     }
 
     #[bench]
     fn bench_insert(b: &mut test::Bencher) {
-        let mut mem_table = MemoryTable::new(1000000);
+        let mut mem_table = MemoryTable::new(1000000 * NODE_SIZE);
         b.iter(|| {
             for i in 0..1000000 {
                 mem_table.insert(i, i);
@@ -288,7 +321,7 @@ mod tests {
     pub fn into_iter2() {
         let num = 2;
         let mut root = Node::new(0, 0);
-        let mut mem_table = MemoryTable::new(100);
+        let mut mem_table = MemoryTable::new(100 * NODE_SIZE);
         for i in 0..num {
             mem_table.insert(i, i);
         }
@@ -334,7 +367,7 @@ mod tests {
 
     #[test]
     fn test_scan() {
-        let mut mem_table = MemoryTable::new(1000);
+        let mut mem_table = MemoryTable::new(1000 * NODE_SIZE);
         for i in 500..1000 {
             assert!(mem_table.insert(i, i));
         }
@@ -344,7 +377,7 @@ mod tests {
     #[cfg_attr(miri, ignore)]
     #[test]
     fn test_scan_on_insert_backwards() {
-        let mut mem_table = MemoryTable::new(10000);
+        let mut mem_table = MemoryTable::new(10000 * NODE_SIZE);
 
         for i in (500..10000).rev() {
             assert!(mem_table.insert(i, i));
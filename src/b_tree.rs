@@ -1,10 +1,20 @@
 use crate::buffer::PAGE_SIZE;
-use crate::buffer::{Buffer, PAGE_SIZE_I64};
-use crate::cache_trait::Cache;
+use crate::buffer::{Buffer, DELETED, KEYS_PER_PAGE, TOMBSTONE};
+use crate::cache_trait::{Cache, PageCache};
+use crate::codec::{
+    read_encoded_page, read_offsets, write_offsets, Codec, CodecKind, USABLE_PAGE_SIZE,
+};
 use crate::compaction::{LevelIterator, ReaderIterator, ScanIterator, PAGE_SIZE_AS_PAIR};
+use crate::compressed_page::{CompressedPage, CompressedPageBuilder};
+use crate::error::{Error, Result};
+use crate::footer::{Footer, MAX_HEIGHT};
+use crate::learned_index::{LearnedIndex, ModelBuilder};
+use crate::options::{IndexKind, PageFormat};
 use crate::write_and_read::Reader;
 use crate::write_and_read::Writer;
 use arrayvec::ArrayVec;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
 use std::{fs, mem};
 
 /// used for writing the bottom level of the BTree
@@ -14,37 +24,124 @@ pub struct Level0Writer {
     writer: Writer,
     buffer: Buffer,
     size_in_pairs: u64,
+    /// the page being filled when writing compressed pages
+    compressed: Option<CompressedPageBuilder>,
+    /// the number of pages written so far
+    pages: u64,
+    /// encodes every full plain page before it's written, if set
+    codec: Option<&'static dyn Codec>,
+    /// the page on disk being filled with encoded pages
+    stored: Buffer,
+    /// the number of pages written to disk so far, the same as `pages` without a codec
+    stored_pages: u64,
+    /// where every encoded page starts, counting only the usable bytes of the pages on disk
+    offsets: Vec<u64>,
+    /// the number of usable bytes taken by encoded pages so far
+    stored_bytes: u64,
+    encoded: Vec<u8>,
 }
 
 impl Level0Writer {
-    fn new(file_name: String) -> Level0Writer {
+    pub(crate) fn new(file_name: String) -> Level0Writer {
+        Self::new_with_format(file_name, PageFormat::Plain, CodecKind::None)
+    }
+
+    /// a codec is only used with plain pages, compressed ones are written as they are.
+    pub(crate) fn new_with_format(
+        file_name: String,
+        page_format: PageFormat,
+        codec: CodecKind,
+    ) -> Level0Writer {
         let writer = Writer::new(file_name);
         let buffer = Buffer::new();
         Level0Writer {
             writer,
             buffer,
             size_in_pairs: 0,
+            compressed: (page_format == PageFormat::Compressed)
+                .then(|| CompressedPageBuilder::new(true)),
+            pages: 0,
+            codec: codec.codec().filter(|_| page_format == PageFormat::Plain),
+            stored: Buffer::new_0(),
+            stored_pages: 0,
+            offsets: Vec::new(),
+            stored_bytes: 0,
+            encoded: Vec::new(),
+        }
+    }
+
+    /// writes out the plain page in `buffer`, which holds `pairs` pairs
+    fn write_plain_page(&mut self, pairs: usize) {
+        self.pages += 1;
+        let Some(codec) = self.codec else {
+            self.writer.write_page(&mut self.buffer);
+            self.stored_pages += 1;
+            return;
+        };
+        let pairs: Vec<_> = (0..pairs).map(|i| self.buffer.pair(i)).collect();
+        self.encoded.clear();
+        codec.encode(&pairs, &mut self.encoded);
+        self.offsets.push(self.stored_bytes);
+        let mut written = 0;
+        while written < self.encoded.len() {
+            let in_page = (self.stored_bytes % USABLE_PAGE_SIZE as u64) as usize;
+            let length = (self.encoded.len() - written).min(USABLE_PAGE_SIZE - in_page);
+            self.stored.as_mut_slice_u8()[in_page..in_page + length]
+                .copy_from_slice(&self.encoded[written..written + length]);
+            written += length;
+            self.stored_bytes += length as u64;
+            if in_page + length == USABLE_PAGE_SIZE {
+                self.writer.write_page(&mut self.stored);
+                self.stored_pages += 1;
+            }
         }
     }
-    fn add_pair(&mut self, item: (i64, i64)) -> bool {
-        self.buffer.as_mut_slice_pair()[self.size_in_pairs as usize] = item;
+    /// returns the largest key of the page that was written out, if adding `item` finished one.
+    pub(crate) fn add_pair(&mut self, item: (i64, i64)) -> Option<i64> {
+        if let Some(page) = &mut self.compressed {
+            if page.add(item.0, item.1) {
+                return None;
+            }
+            let last_key = page.last_key();
+            page.write_to(&mut self.buffer);
+            self.writer.write_page(&mut self.buffer);
+            self.pages += 1;
+            self.stored_pages += 1;
+            assert!(page.add(item.0, item.1));
+            return Some(last_key);
+        }
+        self.buffer.set_pair(self.size_in_pairs as usize, item);
         self.size_in_pairs += 1;
-        if self.size_in_pairs == PAGE_SIZE_I64 as u64 / 2 {
-            self.writer.write_page(&self.buffer);
+        if self.size_in_pairs == PAGE_SIZE_AS_PAIR {
+            self.write_plain_page(PAGE_SIZE_AS_PAIR as usize);
             self.size_in_pairs = 0;
-            true
+            Some(item.0)
         } else {
-            false
+            None
         }
     }
 
-    fn finish(&mut self) {
-        if self.size_in_pairs != 0 {
+    pub(crate) fn finish(&mut self) {
+        if let Some(page) = &mut self.compressed {
+            if !page.is_empty() {
+                page.write_to(&mut self.buffer);
+                self.writer.write_page(&mut self.buffer);
+                self.pages += 1;
+                self.stored_pages += 1;
+            }
+        } else if self.size_in_pairs != 0 {
             let pair = (i64::MAX, i64::MAX);
-            for i in self.size_in_pairs..PAGE_SIZE_I64 as u64 / 2 {
-                self.buffer.as_mut_slice_pair()[i as usize] = pair;
+            for i in self.size_in_pairs..PAGE_SIZE_AS_PAIR {
+                self.buffer.set_pair(i as usize, pair);
+            }
+            self.write_plain_page(self.size_in_pairs as usize);
+        }
+        if self.codec.is_some() {
+            if !self.stored_bytes.is_multiple_of(USABLE_PAGE_SIZE as u64) {
+                self.writer.write_page(&mut self.stored);
+                self.stored_pages += 1;
             }
-            self.writer.write_page(&self.buffer);
+            self.offsets.push(self.stored_bytes);
         }
     }
 }
@@ -55,128 +152,455 @@ pub struct LevelWriter {
     writer: Writer,
     size_in_i64: u64,
     buffer: Buffer,
+    /// the page being filled when writing compressed pages
+    compressed: Option<CompressedPageBuilder>,
+    /// the number of pages written so far
+    pages: u64,
 }
 
 impl LevelWriter {
-    fn new(file_name: String) -> LevelWriter {
+    fn new(file_name: String, page_format: PageFormat) -> LevelWriter {
         let writer = Writer::new(file_name);
         LevelWriter {
             writer,
             size_in_i64: 0,
             buffer: Buffer::new(),
+            compressed: (page_format == PageFormat::Compressed)
+                .then(|| CompressedPageBuilder::new(false)),
+            pages: 0,
         }
     }
-    fn add_i64<A: Cache>(&mut self, item: i64, cache: &mut A) -> bool {
-        let index_in_buffer = self.size_in_i64 % PAGE_SIZE_I64 as u64;
-        self.buffer.as_mut_slice_i64()[index_in_buffer as usize] = item;
+    /// like `Level0Writer::add_pair`, returns the largest key of a page that was written out.
+    fn add_i64<A: Cache>(&mut self, item: i64, cache: &PageCache<A>) -> Option<i64> {
+        if let Some(page) = &mut self.compressed {
+            if page.is_empty() {
+                // the children of a page are the ones the keys before it didn't point to yet
+                page.first_child = self.size_in_i64;
+            }
+            self.size_in_i64 += 1;
+            if page.add(item, 0) {
+                return None;
+            }
+            let last_key = page.last_key();
+            let mut old_buffer = Buffer::new();
+            page.write_to(&mut old_buffer);
+            cache.write_page(&mut self.writer, self.pages, old_buffer);
+            self.pages += 1;
+            page.first_child = self.size_in_i64 - 1;
+            assert!(page.add(item, 0));
+            return Some(last_key);
+        }
+        let index_in_buffer = self.size_in_i64 % KEYS_PER_PAGE as u64;
+        self.buffer.set_key(index_in_buffer as usize, item);
         self.size_in_i64 += 1;
-        if self.size_in_i64 % PAGE_SIZE_I64 as u64 == 0 {
+        if self.size_in_i64.is_multiple_of(KEYS_PER_PAGE as u64) {
             let mut old_buffer = Buffer::new();
             mem::swap(&mut self.buffer, &mut old_buffer);
             cache.write_page(
                 &mut self.writer,
-                self.size_in_i64 / PAGE_SIZE_I64 as u64 - 1,
+                self.size_in_i64 / KEYS_PER_PAGE as u64 - 1,
                 old_buffer,
             );
-            true
+            self.pages += 1;
+            Some(item)
         } else {
-            false
+            None
         }
     }
 
-    fn finish<A: Cache>(&mut self, cache: &mut A) {
-        if self.size_in_i64 % PAGE_SIZE_I64 as u64 != 0 {
-            let index = self.size_in_i64 % PAGE_SIZE_I64 as u64;
-            for i in index..PAGE_SIZE_I64 as u64 {
-                self.buffer.as_mut_slice_i64()[i as usize] = i64::MAX;
+    fn pages(&self) -> u64 {
+        self.pages
+    }
+
+    fn finish<A: Cache>(&mut self, cache: &PageCache<A>) {
+        if let Some(page) = &mut self.compressed {
+            if !page.is_empty() {
+                let mut old_buffer = Buffer::new();
+                page.write_to(&mut old_buffer);
+                cache.write_page(&mut self.writer, self.pages, old_buffer);
+                self.pages += 1;
+            }
+        } else if !self.size_in_i64.is_multiple_of(KEYS_PER_PAGE as u64) {
+            let index = self.size_in_i64 % KEYS_PER_PAGE as u64;
+            for i in index..KEYS_PER_PAGE as u64 {
+                self.buffer.set_key(i as usize, i64::MAX);
             }
             let mut old_buffer = Buffer::new();
             mem::swap(&mut self.buffer, &mut old_buffer);
             cache.write_page(
                 &mut self.writer,
-                self.size_in_i64 / PAGE_SIZE_I64 as u64,
+                self.size_in_i64 / KEYS_PER_PAGE as u64,
                 old_buffer,
             );
+            self.pages += 1;
+        }
+    }
+}
+/// bookkeeping about the contents of a BTree, used to decide when it should be compacted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct LevelStats {
+    /// number of entries in the BTree including tombstones
+    pub entries: u64,
+    /// number of entries whose value is `DELETED`
+    pub tombstones: u64,
+    /// when the oldest tombstone in the BTree was issued, in seconds since the unix epoch
+    pub oldest_tombstone: Option<u64>,
+}
+
+impl LevelStats {
+    pub fn tombstone_density(&self) -> f64 {
+        if self.entries == 0 {
+            0.0
+        } else {
+            self.tombstones as f64 / self.entries as f64
         }
     }
 }
+
 #[derive(Debug)]
 pub struct BTreeReader {
+    file_name_prefix: String,
     /// the readers for all levels of the BTree. The first reader is the bottom level and the last reader is the top level
-    readers: ArrayVec<Reader, 10>,
+    readers: ArrayVec<Reader, { MAX_HEIGHT + 1 }>,
+    pub(crate) footer: Footer,
+    pub(crate) stats: LevelStats,
+    /// the largest key of every full page of the bottom level, when using `IndexKind::Fences`
+    pub(crate) fences: Option<Vec<i64>>,
+    /// the model used to find pages when using `IndexKind::Learned`
+    pub(crate) model: Option<LearnedIndex>,
+    /// where each page of the bottom level is on disk, when it was written with a codec
+    offsets: Option<Arc<[u64]>>,
 }
 //
 impl BTreeReader {
-    pub(crate) fn new(file_name_prefix: String, levels: usize) -> BTreeReader {
+    /// opens the BTree written by a `BTreeWriter` with the same prefix.
+    /// Everything about its shape is read from the footer, which is checked against the files.
+    pub fn open(file_name_prefix: String) -> Result<BTreeReader> {
+        Self::open_with_index(file_name_prefix, IndexKind::BTree)
+    }
+
+    /// like `open` but chooses how lookups find their page of the bottom level.
+    pub fn open_with_index(file_name_prefix: String, index: IndexKind) -> Result<BTreeReader> {
         let mut readers = ArrayVec::new();
         let file_name = format!("{}.items.btree", file_name_prefix);
-        let reader = Reader::new(file_name.as_str());
+        let reader = Reader::open(file_name.as_str())?;
+        let file_size = reader.file_size();
+        if file_size == 0 || file_size % PAGE_SIZE as u64 != 0 {
+            return Err(Error::invalid_format(
+                &file_name,
+                "size isn't a whole number of pages",
+            ));
+        }
+        let mut buffer = Buffer::new();
+        reader.read_page(&mut buffer, file_size / PAGE_SIZE as u64 - 1)?;
+        let footer = Footer::from_buffer(&buffer, &file_name)?;
+        if footer.stored_pages + 1 != file_size / PAGE_SIZE as u64 {
+            return Err(Error::invalid_format(
+                &file_name,
+                "page count doesn't match the footer",
+            ));
+        }
         readers.push(reader);
         // let level = 0;
-        for i in 0..levels {
+        for (i, pages) in footer.level_pages.iter().enumerate() {
             let file_name = format!("{}.level{}.btree", file_name_prefix, i);
-            let reader = Reader::new(file_name.as_str());
+            let reader = Reader::open(file_name.as_str())?;
+            if reader.file_size() != pages * PAGE_SIZE as u64 {
+                return Err(Error::invalid_format(
+                    &file_name,
+                    "page count doesn't match the footer",
+                ));
+            }
             readers.push(reader);
         }
-        BTreeReader { readers }
+        let mut b_tree = BTreeReader {
+            file_name_prefix,
+            readers,
+            stats: LevelStats {
+                entries: footer.entries,
+                ..LevelStats::default()
+            },
+            footer,
+            fences: None,
+            model: None,
+            offsets: None,
+        };
+        if b_tree.footer.codec != CodecKind::None {
+            let offsets = read_offsets(
+                &offsets_file_name(&b_tree.file_name_prefix),
+                b_tree.footer.data_pages,
+            )?;
+            b_tree.offsets = Some(offsets.into());
+        }
+        match index {
+            IndexKind::BTree => {}
+            IndexKind::Fences => b_tree.load_fences()?,
+            IndexKind::Learned { .. } if b_tree.footer.model_segments > 0 => {
+                b_tree.model = Some(LearnedIndex::read(
+                    &model_file_name(&b_tree.file_name_prefix),
+                    b_tree.footer.model_segments,
+                    b_tree.footer.model_max_error,
+                )?);
+            }
+            IndexKind::Learned { .. } => {}
+        }
+        Ok(b_tree)
+    }
+
+    /// reads the fence keys from the lowest upper level, which already holds the largest key of
+    /// every full page of the bottom level. The last page, if it isn't full, has no fence key.
+    fn load_fences(&mut self) -> Result<()> {
+        let mut fences = Vec::with_capacity(self.footer.data_pages as usize);
+        let mut buffer = Buffer::new();
+        let pages = self.footer.level_pages.first().copied().unwrap_or(0);
+        for page in 0..pages {
+            self.readers[1].read_page(&mut buffer, page)?;
+            match self.footer.page_format {
+                PageFormat::Plain => fences.extend(
+                    (0..KEYS_PER_PAGE)
+                        .map(|i| buffer.key(i))
+                        .take_while(|key| *key != TOMBSTONE),
+                ),
+                PageFormat::Compressed => fences.extend(
+                    CompressedPage::new(&buffer, false)
+                        .iter()
+                        .map(|entry| entry.0),
+                ),
+            }
+        }
+        self.fences = Some(fences);
+        Ok(())
+    }
+
+    pub fn file_name_prefix(&self) -> &str {
+        &self.file_name_prefix
+    }
+
+    /// the number of levels above the bottom level
+    pub fn levels(&self) -> usize {
+        self.readers.len() - 1
+    }
+
+    /// the smallest and largest key in the BTree
+    pub fn key_range(&self) -> (i64, i64) {
+        (self.footer.min_key, self.footer.max_key)
+    }
+
+    /// whether `key` lies within the keys of the BTree, if it doesn't it can be skipped.
+    pub fn may_contain(&self, key: i64) -> bool {
+        self.footer.min_key <= key && key <= self.footer.max_key
+    }
+
+    /// whether any key of the BTree could be in `lower_bound..=upper_bound`
+    pub fn overlaps(&self, lower_bound: i64, upper_bound: i64) -> bool {
+        self.footer.min_key <= upper_bound && lower_bound <= self.footer.max_key
+    }
+
+    fn data_end(&self) -> u64 {
+        self.footer.data_pages
     }
     /// gets the index of the buffer in the bottom level such that buffer[0] <= key <= buffer[last]
     /// in other words the index of the bottom level buffer that could potentially contain that key
-    fn get_bottom_index<A: Cache>(&mut self, key: i64, cache: &mut A) -> usize {
+    fn get_bottom_index<A: Cache>(&self, key: i64, cache: &PageCache<A>) -> Result<usize> {
+        if let Some(fences) = &self.fences {
+            return Ok(fences.partition_point(|fence| *fence < key));
+        }
+        if let Some(model) = &self.model {
+            let positions = model.predict(key, self.footer.entries);
+            let first_page = positions.start / PAGE_SIZE_AS_PAIR;
+            let last_page = (positions.end - 1) / PAGE_SIZE_AS_PAIR;
+            // the key is on the first page of the window that ends with a key at least as large
+            for page in first_page..last_page {
+                let buffer = self.data_page(page, cache)?;
+                if buffer.pair(PAGE_SIZE_AS_PAIR as usize - 1).0 >= key {
+                    return Ok(page as usize);
+                }
+            }
+            return Ok(last_page as usize);
+        }
         debug_assert!(self.readers.len() >= 1);
         let mut level = self.readers.len() - 1;
         let mut index: usize = 0;
-        debug_assert!(self.readers[level].file_size() <= PAGE_SIZE as u64);
-        debug_assert!(self.readers[level].file_size() == PAGE_SIZE as u64);
+        // the root is a single page (unless the bottom level is the root, then it has the footer)
+        debug_assert!(level == 0 || self.readers[level].file_size() <= PAGE_SIZE as u64);
+        debug_assert!(level == 0 || self.readers[level].file_size() == PAGE_SIZE as u64);
         while level != 0 {
-            let buffer = cache.get_page(&mut self.readers[level], index as u64, true, true);
-            let index_intermediate = buffer
-                .as_slice_i64()
-                .binary_search(&key)
-                .unwrap_or_else(|i| i);
-            index = index_intermediate + index * PAGE_SIZE_I64;
+            let buffer = cache.get_page(&self.readers[level], index as u64, true, true)?;
+            index = match self.footer.page_format {
+                PageFormat::Plain => {
+                    let index_intermediate = buffer.search_keys(key).unwrap_or_else(|i| i);
+                    index_intermediate + index * KEYS_PER_PAGE
+                }
+                PageFormat::Compressed => {
+                    let page = CompressedPage::new(&buffer, false);
+                    page.first_child() as usize + page.lower_bound(key).0
+                }
+            };
             level -= 1;
         }
-        index
+        Ok(index)
+    }
+    /// reads page `page` of the bottom level, decoding it into a plain page if the BTree was
+    /// written with a codec.
+    fn data_page<A: Cache>(&self, page: u64, cache: &PageCache<A>) -> Result<Buffer> {
+        let Some(offsets) = &self.offsets else {
+            return cache.get_page(&self.readers[0], page, true, true);
+        };
+        let reader = &self.readers[0];
+        let mut bytes = Vec::new();
+        read_encoded_page(
+            offsets,
+            page as usize,
+            |stored| cache.get_page(reader, stored, true, true),
+            &mut bytes,
+        )?;
+        let mut pairs = Vec::with_capacity(PAGE_SIZE_AS_PAIR as usize);
+        self.footer
+            .codec
+            .codec()
+            .unwrap()
+            .decode(&bytes, &mut pairs);
+        let mut buffer = Buffer::new();
+        for (i, pair) in pairs.into_iter().enumerate() {
+            buffer.set_pair(i, pair);
+        }
+        Ok(buffer)
+    }
+
+    /// iterates over the bottom level from page `page`
+    fn data_iter(&self, upper_bound: i64, lower_bound: i64, page: u64) -> ReaderIterator {
+        let iter = ReaderIterator::new_with_upper_bound(
+            self.readers[0].clone(),
+            upper_bound,
+            lower_bound,
+            page,
+            self.data_end(),
+            self.footer.page_format,
+        );
+        match (&self.offsets, self.footer.codec.codec()) {
+            (Some(offsets), Some(codec)) => iter.decoding(codec, offsets.clone()),
+            _ => iter,
+        }
     }
+
     /// gets the item with the given key if it exists.
-    pub(crate) fn get_item<A: Cache>(&mut self, key: i64, cache: &mut A) -> Option<i64> {
-        let index = self.get_bottom_index(key, cache);
-        let buffer = cache.get_page(&mut self.readers[0], index as u64, true, true);
-        let item = buffer
-            .as_slice_pair()
-            .binary_search_by_key(&key, |i| i.0)
-            .ok();
-        item.map(|i| buffer.as_slice_pair()[i].1)
+    pub(crate) fn get_item<A: Cache>(&self, key: i64, cache: &PageCache<A>) -> Result<Option<i64>> {
+        // past the largest key the search would run off the end of the bottom level
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        let index = self.get_bottom_index(key, cache)?;
+        let buffer = self.data_page(index as u64, cache)?;
+        Ok(match self.footer.page_format {
+            PageFormat::Plain => buffer.search_pairs(key).ok().map(|i| buffer.pair(i).1),
+            PageFormat::Compressed => match CompressedPage::new(&buffer, true).lower_bound(key) {
+                (_, Some((found, value))) if found == key => Some(value),
+                _ => None,
+            },
+        })
+    }
+
+    pub fn into_level_iter(self) -> LevelIterator<'static> {
+        self.level_iter()
     }
 
-    pub fn into_level_iter(self) -> LevelIterator {
-        LevelIterator::LevelN(ReaderIterator::new(self.readers[0].file_name.clone()))
+    /// like `into_level_iter` but keeps the BTree around so its files can be deleted afterwards.
+    pub fn level_iter(&self) -> LevelIterator<'static> {
+        LevelIterator::LevelN(self.data_iter(TOMBSTONE, i64::MIN, 0))
     }
 
+    /// iterates over the pairs in `lower_bound..=upper_bound`, reading the bottom level through
+    /// `cache` as a scan.
     pub fn range<A: Cache>(
-        &mut self,
+        &self,
         lower_bound: i64,
         upper_bound: i64,
-        cache: &mut A,
-    ) -> ScanIterator {
-        let lower_index = self.get_bottom_index(lower_bound, cache) * PAGE_SIZE_AS_PAIR as usize;
+        cache: &Arc<PageCache<A>>,
+    ) -> Result<ScanIterator<'static>> {
+        let lower_page = self.get_bottom_index(lower_bound, cache)?;
+        let iter = self.data_iter(upper_bound, lower_bound, lower_page as u64);
+        Ok(ScanIterator::LevelN(iter.scanning(cache.clone())))
+    }
 
-        let mut iter = ReaderIterator::new_with_upper_bound(
-            self.readers[0].file_name.clone(),
-            upper_bound,
-            lower_bound,
-            lower_index as u64,
-            Buffer::new(),
-        );
-        ScanIterator::LevelN(iter)
+    /// evicts the pages of each file of the BTree from `cache` once the file is closed, that is
+    /// when the BTree and every iterator reading it are gone.
+    pub(crate) fn evict_on_close<A: Cache>(&self, cache: &Arc<PageCache<A>>) {
+        for reader in self.readers.iter() {
+            reader.on_close(cache.clone());
+        }
+    }
+
+    /// pins the pages of the upper levels in `cache`, so finding the page of the bottom level
+    /// that holds a key never reads from disk. Either they are all pinned or none are: returns
+    /// false if they didn't all fit, they are then cached like any other page. Lookups with
+    /// fences or a model don't read the upper levels, so nothing is pinned for those.
+    pub(crate) fn pin_upper_levels<A: Cache>(&self, cache: &PageCache<A>) -> Result<bool> {
+        if self.fences.is_some() || self.model.is_some() {
+            return Ok(true);
+        }
+        let pinned = self.pin_upper_pages(cache);
+        if !matches!(pinned, Ok(true)) {
+            for reader in self.readers[1..].iter() {
+                cache.unpin_file(reader);
+            }
+        }
+        pinned
+    }
+
+    /// pins the pages of the upper levels until one doesn't fit
+    fn pin_upper_pages<A: Cache>(&self, cache: &PageCache<A>) -> Result<bool> {
+        for reader in self.readers[1..].iter() {
+            for page in 0..reader.file_size() / PAGE_SIZE as u64 {
+                if !cache.pin_page(reader, page)? {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
     }
 
-    fn delete(&mut self) {
-        for i in 0..self.readers.len() {
-            fs::remove_file(self.readers[i].file_name.as_str()).unwrap();
+    pub(crate) fn delete(&self) -> Result<()> {
+        for reader in self.readers.iter() {
+            fs::remove_file(reader.file_name.as_str())?;
+        }
+        if self.footer.model_segments > 0 {
+            fs::remove_file(model_file_name(&self.file_name_prefix))?;
         }
+        if self.offsets.is_some() {
+            fs::remove_file(offsets_file_name(&self.file_name_prefix))?;
+        }
+        Ok(())
+    }
+}
+
+/// moves the files of a BTree with `levels` upper levels from one prefix to another.
+/// Used to write a BTree under a temporary name and then move it in place of the one it replaces.
+pub(crate) fn rename_files(old_prefix: &str, new_prefix: &str, levels: usize) -> Result<()> {
+    debug_assert!(levels <= MAX_HEIGHT);
+    let mut suffixes: Vec<String> = std::iter::once("items".to_string())
+        .chain((0..levels).map(|i| format!("level{}", i)))
+        .collect();
+    if fs::metadata(model_file_name(old_prefix)).is_ok() {
+        suffixes.push("model".to_string());
+    }
+    if fs::metadata(offsets_file_name(old_prefix)).is_ok() {
+        suffixes.push("offsets".to_string());
     }
+    for suffix in suffixes {
+        fs::rename(
+            format!("{}.{}.btree", old_prefix, suffix),
+            format!("{}.{}.btree", new_prefix, suffix),
+        )?;
+    }
+    Ok(())
+}
+
+fn model_file_name(file_name_prefix: &str) -> String {
+    format!("{}.model.btree", file_name_prefix)
+}
+
+fn offsets_file_name(file_name_prefix: &str) -> String {
+    format!("{}.offsets.btree", file_name_prefix)
 }
 
 /// the writer for the Btree. The interface allows you to add a single item at a time.
@@ -186,41 +610,130 @@ impl BTreeReader {
 pub struct BTreeWriter {
     file_name_prefix: String,
     /// the buffers for all upper levels of the BTree
-    pub buffers: ArrayVec<LevelWriter, 10>,
+    pub buffers: ArrayVec<LevelWriter, MAX_HEIGHT>,
     /// the buffers for the bottom level of the BTree
     /// They are a different type because we don't need to reallocate the buffer for the bottom level
     /// (since it's not writing to the cache.
     pub top_level: Level0Writer,
+    /// number of items added so far
+    pub(crate) entries: u64,
+    /// number of items added so far whose value is `DELETED`
+    pub(crate) tombstones: u64,
+    /// the first and last key added so far
+    min_key: i64,
+    max_key: i64,
+    /// builds the learned index as the items go by, if the BTree gets one
+    model: Option<ModelBuilder>,
+    page_format: PageFormat,
+    codec: CodecKind,
 }
 impl BTreeWriter {
     pub(crate) fn new(file_name_prefix: String) -> BTreeWriter {
+        Self::with_options(
+            file_name_prefix,
+            IndexKind::BTree,
+            PageFormat::Plain,
+            CodecKind::None,
+        )
+    }
+
+    /// like `new` but also builds a learned index when `index` asks for one (it only works with
+    /// plain pages, where the position of a pair gives its page), and picks the page format and
+    /// the codec for the bottom level.
+    pub(crate) fn with_options(
+        file_name_prefix: String,
+        index: IndexKind,
+        page_format: PageFormat,
+        codec: CodecKind,
+    ) -> BTreeWriter {
         let file_name = format!("{}.items.btree", file_name_prefix);
         let s = BTreeWriter {
             buffers: ArrayVec::new(),
             file_name_prefix,
-            top_level: Level0Writer::new(file_name),
+            top_level: Level0Writer::new_with_format(file_name, page_format, codec),
+            entries: 0,
+            tombstones: 0,
+            min_key: i64::MAX,
+            max_key: i64::MIN,
+            model: match index {
+                IndexKind::Learned { max_error } if page_format == PageFormat::Plain => {
+                    Some(ModelBuilder::new(max_error))
+                }
+                _ => None,
+            },
+            page_format,
+            codec,
         };
         s
     }
-    fn add_item_level<A: Cache>(&mut self, item: i64, level: usize, cache: &mut A) {
+    fn add_item_level<A: Cache>(&mut self, item: i64, level: usize, cache: &PageCache<A>) {
         if level == self.buffers.len() {
             let file_name = format!("{}.level{}.btree", self.file_name_prefix, level);
-            self.buffers.push(LevelWriter::new(file_name));
+            self.buffers
+                .push(LevelWriter::new(file_name, self.page_format));
         }
-        if self.buffers[level].add_i64(item, cache) {
-            self.add_item_level(item, level + 1, cache);
+        if let Some(last_key) = self.buffers[level].add_i64(item, cache) {
+            self.add_item_level(last_key, level + 1, cache);
+        }
+    }
+    /// deletes the files written so far, for a BTree that won't be finished
+    pub(crate) fn abandon(self) -> Result<()> {
+        fs::remove_file(format!("{}.items.btree", self.file_name_prefix))?;
+        for level in 0..self.buffers.len() {
+            fs::remove_file(format!("{}.level{}.btree", self.file_name_prefix, level))?;
         }
+        Ok(())
     }
-    pub fn add_item<A: Cache>(&mut self, item: (i64, i64), cache: &mut A) {
-        if self.top_level.add_pair(item) {
-            self.add_item_level(item.0, 0, cache);
+
+    pub fn add_item<A: Cache>(&mut self, item: (i64, i64), cache: &PageCache<A>) {
+        debug_assert!(self.entries == 0 || item.0 >= self.max_key);
+        self.min_key = self.min_key.min(item.0);
+        self.max_key = item.0;
+        if let Some(model) = &mut self.model {
+            model.add(item.0, self.entries);
+        }
+        self.entries += 1;
+        if item.1 == DELETED {
+            self.tombstones += 1;
+        }
+        if let Some(last_key) = self.top_level.add_pair(item) {
+            self.add_item_level(last_key, 0, cache);
         }
     }
-    pub fn finish<A: Cache>(&mut self, cache: &mut A) -> usize {
+    /// writes out the partially filled pages and the footer.
+    /// Returns the number of levels above the bottom level.
+    pub fn finish<A: Cache>(&mut self, cache: &PageCache<A>) -> usize {
         self.top_level.finish();
         for i in 0..self.buffers.len() {
             self.buffers[i].finish(cache);
         }
+        let mut footer = Footer {
+            height: self.buffers.len() as u32,
+            entries: self.entries,
+            min_key: self.min_key,
+            max_key: self.max_key,
+            data_pages: self.top_level.pages,
+            stored_pages: self.top_level.stored_pages,
+            model_segments: 0,
+            model_max_error: 0,
+            page_format: self.page_format,
+            codec: CodecKind::None,
+            level_pages: self.buffers.iter().map(LevelWriter::pages).collect(),
+        };
+        if let Some(model) = self.model.take().filter(|_| self.entries > 0) {
+            let model = model.finish();
+            model.write(model_file_name(&self.file_name_prefix));
+            footer.model_segments = model.segments().len() as u64;
+            footer.model_max_error = model.max_error();
+        }
+        if self.top_level.codec.is_some() {
+            write_offsets(
+                offsets_file_name(&self.file_name_prefix),
+                &self.top_level.offsets,
+            );
+            footer.codec = self.codec;
+        }
+        self.top_level.writer.write_page(&mut footer.to_buffer());
         self.buffers.len()
     }
 }
@@ -229,45 +742,45 @@ impl BTreeWriter {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::cache_trait::NoCache;
+    use crate::cache_trait::{NoCache, PageCache};
 
     #[test]
     fn test_writer_one_level() {
-        let mut cache = NoCache;
+        let cache = PageCache::<NoCache>::default();
         let mut writer = BTreeWriter::new("testing/test1".to_string());
         for i in 0..PAGE_SIZE / 16 {
-            writer.add_item((i as i64, (i + 1) as i64), &mut cache);
+            writer.add_item((i as i64, (i + 1) as i64), &cache);
         }
-        assert_eq!(writer.finish(&mut cache), 1);
+        assert_eq!(writer.finish(&cache), 1);
         // let mut file = Reader::new("test.level0.btree");
         // let mut buffer = Buffer::new();
         // file.read_page(&mut buffer, 0);
         // dbg!(buffer.as_slice_i64());
-        let mut reader = BTreeReader::new("testing/test1".to_string(), 0);
+        let reader = BTreeReader::open("testing/test1".to_string()).unwrap();
         for i in 0..PAGE_SIZE / 16 {
-            let item = reader.get_item(i as i64, &mut cache);
+            let item = reader.get_item(i as i64, &cache).unwrap();
             assert_eq!(item, Some((i + 1) as i64));
         }
-        reader.delete();
+        reader.delete().unwrap();
     }
 
     #[test]
     fn test_writer_level_2() {
-        let mut cache = NoCache;
+        let cache = PageCache::<NoCache>::default();
         let mut writer = BTreeWriter::new("testing/test2".to_string());
-        for i in 0..PAGE_SIZE / 16 * PAGE_SIZE / 8 - 10 {
-            writer.add_item((i as i64, (i + 1) as i64), &mut cache);
+        for i in 0..PAGE_SIZE_AS_PAIR as usize * KEYS_PER_PAGE - 10 {
+            writer.add_item((i as i64, (i + 1) as i64), &cache);
         }
-        assert_eq!(writer.finish(&mut cache), 1);
-        let mut reader = BTreeReader::new("testing/test2".to_string(), 1);
+        assert_eq!(writer.finish(&cache), 1);
+        let reader = BTreeReader::open("testing/test2".to_string()).unwrap();
 
-        let item = reader.get_item(256, &mut cache);
+        let item = reader.get_item(256, &cache).unwrap();
         assert_eq!(item, Some(257));
-        let item = reader.get_item(256 * 230, &mut cache);
+        let item = reader.get_item(256 * 230, &cache).unwrap();
         assert_eq!(item, Some(256 * 230 + 1));
-        let item = reader.get_item(256 * 210 + 70, &mut cache);
+        let item = reader.get_item(256 * 210 + 70, &cache).unwrap();
         assert_eq!(item, Some(256 * 210 + 70 + 1));
-        reader.delete();
+        reader.delete().unwrap();
     }
 
     #[test]
@@ -277,16 +790,16 @@ mod tests {
         }
         fs::create_dir("testing").unwrap();
         let mut writer = BTreeWriter::new("testing/test3".to_string());
-        let mut cache = NoCache;
+        let cache = PageCache::<NoCache>::default();
         let length = PAGE_SIZE / 16 * PAGE_SIZE / 8 * 10;
         for i in 0..length {
-            writer.add_item((i as i64, (i + 1) as i64), &mut cache);
+            writer.add_item((i as i64, (i + 1) as i64), &cache);
         }
-        // writer.add_item(((length - 1) as i64, length as i64), &mut cache);
-        // assert_eq!(writer.top_level.size_in_pairs, length as u64 % (PAGE_SIZE_I64 as u64 / 2));
-        assert_eq!(writer.finish(&mut cache), 2);
-        let mut reader = BTreeReader::new("testing/test3".to_string(), 2);
-        let iter: Vec<_> = reader.into_level_iter().collect();
+        // writer.add_item(((length - 1) as i64, length as i64), &cache);
+        // assert_eq!(writer.top_level.size_in_pairs, length as u64 % (KEYS_PER_PAGE as u64 / 2));
+        assert_eq!(writer.finish(&cache), 2);
+        let reader = BTreeReader::open("testing/test3".to_string()).unwrap();
+        let iter: Vec<_> = reader.into_level_iter().collect::<Result<_>>().unwrap();
         dbg!(iter.len());
         dbg!(iter[iter.len() - 1]);
         dbg!(length);
@@ -294,32 +807,452 @@ mod tests {
             assert_eq!(iter[i], (i as i64, (i + 1) as i64));
         }
         assert_eq!(iter.len(), PAGE_SIZE / 16 * PAGE_SIZE / 8 * 10);
-        let mut reader = BTreeReader::new("testing/test3".to_string(), 2);
+        let reader = BTreeReader::open("testing/test3".to_string()).unwrap();
         assert_eq!(
-            reader.get_item(length as i64 - 10, &mut cache),
+            reader.get_item(length as i64 - 10, &cache).unwrap(),
             Some((length - 9) as i64)
         );
-        assert_eq!(reader.readers[0].file_size() / 16, length as u64);
+        // the footer takes up the last page
+        assert_eq!(
+            (reader.readers[0].file_size() / PAGE_SIZE as u64 - 1),
+            (length as u64).div_ceil(PAGE_SIZE_AS_PAIR)
+        );
+        assert_eq!(reader.footer.entries, length as u64);
+        assert_eq!(reader.key_range(), (0, length as i64 - 1));
 
-        let item = reader.get_item(256 * 256 * 5 + 70 + 1, &mut cache);
+        let item = reader.get_item(256 * 256 * 5 + 70 + 1, &cache).unwrap();
         assert_eq!(item, Some(256 * 256 * 5 + 70 + 2));
-        let item = reader.get_item(256, &mut cache);
+        let item = reader.get_item(256, &cache).unwrap();
         assert_eq!(item, Some(257));
-        let item = reader.get_item(256 * 230, &mut cache);
+        let item = reader.get_item(256 * 230, &cache).unwrap();
         assert_eq!(item, Some(256 * 230 + 1));
-        let item = reader.get_item(256 * 210 + 70, &mut cache);
+        let item = reader.get_item(256 * 210 + 70, &cache).unwrap();
         assert_eq!(item, Some(256 * 210 + 70 + 1));
         // reader.delete();
     }
 }
 
+/// the setup shared by the tests of the BTree files
 #[cfg(test)]
-mod tests2 {
+mod fixture {
+    use super::*;
+    use crate::cache_trait::{NoCache, PageCache};
+
+    /// a directory for the BTrees of one test, deleted along with them once it's dropped
+    pub(super) struct TestDirectory {
+        path: String,
+        pub cache: Arc<PageCache<NoCache>>,
+    }
+
+    impl TestDirectory {
+        pub fn new(path: &str) -> TestDirectory {
+            let _ = fs::remove_dir_all(path);
+            fs::create_dir(path).unwrap();
+            TestDirectory {
+                path: path.to_string(),
+                cache: Arc::new(PageCache::default()),
+            }
+        }
+
+        /// the prefix of the files of the BTree `name`
+        pub fn prefix(&self, name: &str) -> String {
+            format!("{}/{}", self.path, name)
+        }
+
+        /// writes `items` as the BTree `name` with the default options. Returns its number of
+        /// upper levels.
+        pub fn write(&self, name: &str, items: impl IntoIterator<Item = (i64, i64)>) -> usize {
+            self.write_with_options(
+                name,
+                items,
+                IndexKind::BTree,
+                PageFormat::Plain,
+                CodecKind::None,
+            )
+        }
+
+        pub fn write_with_options(
+            &self,
+            name: &str,
+            items: impl IntoIterator<Item = (i64, i64)>,
+            index: IndexKind,
+            page_format: PageFormat,
+            codec: CodecKind,
+        ) -> usize {
+            let mut writer =
+                BTreeWriter::with_options(self.prefix(name), index, page_format, codec);
+            for item in items {
+                writer.add_item(item, &self.cache);
+            }
+            writer.finish(&self.cache)
+        }
+
+        pub fn open(&self, name: &str, index: IndexKind) -> BTreeReader {
+            BTreeReader::open_with_index(self.prefix(name), index).unwrap()
+        }
+
+        /// the number of files in the directory
+        pub fn files(&self) -> usize {
+            fs::read_dir(&self.path).unwrap().count()
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_footer {
+    use super::fixture::TestDirectory;
+    use super::*;
+
+    #[test]
+    fn test_open_validates_files() {
+        let directory = TestDirectory::new("testing_footer");
+        assert_eq!(directory.write("b_tree", (0..100_000).map(|i| (i, i))), 1);
+        let reader = directory.open("b_tree", IndexKind::BTree);
+        assert_eq!(reader.levels(), 1);
+        assert_eq!(
+            reader.footer.data_pages,
+            100_000_u64.div_ceil(PAGE_SIZE_AS_PAIR)
+        );
+        assert_eq!(reader.key_range(), (0, 99_999));
+
+        // a missing upper level
+        fs::remove_file("testing_footer/b_tree.level0.btree").unwrap();
+        assert!(matches!(
+            BTreeReader::open(directory.prefix("b_tree")),
+            Err(Error::Io(_))
+        ));
+        // a file that was cut short
+        let items = fs::read("testing_footer/b_tree.items.btree").unwrap();
+        fs::write("testing_footer/b_tree.items.btree", &items[PAGE_SIZE..]).unwrap();
+        assert!(matches!(
+            BTreeReader::open(directory.prefix("b_tree")),
+            Err(Error::InvalidFormat { .. })
+        ));
+        // not a BTree at all
+        fs::write("testing_footer/b_tree.items.btree", &items[..PAGE_SIZE]).unwrap();
+        assert!(matches!(
+            BTreeReader::open(directory.prefix("b_tree")),
+            Err(Error::InvalidFormat { .. })
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests_fences {
+    use super::fixture::TestDirectory;
+    use super::*;
+
+    #[test]
+    fn test_fences() {
+        let directory = TestDirectory::new("testing_fences");
+        let length = PAGE_SIZE_AS_PAIR as i64 * KEYS_PER_PAGE as i64 * 3 + 100;
+        assert_eq!(
+            directory.write("b_tree", (0..length).map(|i| (i * 2, i))),
+            2
+        );
+        let reader = directory.open("b_tree", IndexKind::Fences);
+        let fences = reader.fences.as_ref().unwrap();
+        assert_eq!(fences.len() as u64, length as u64 / PAGE_SIZE_AS_PAIR);
+        assert_eq!(fences[0], (PAGE_SIZE_AS_PAIR as i64 - 1) * 2);
+        for i in (0..length).step_by(997).chain([length - 1]) {
+            assert_eq!(reader.get_item(i * 2, &directory.cache).unwrap(), Some(i));
+            assert_eq!(reader.get_item(i * 2 + 1, &directory.cache).unwrap(), None);
+        }
+        let result: Vec<_> = reader
+            .range(1000, 1010, &directory.cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(result, (500..=505).map(|i| (i * 2, i)).collect::<Vec<_>>());
+    }
+}
+
+#[cfg(test)]
+mod tests_learned_index {
+    use super::fixture::TestDirectory;
+    use super::*;
+
+    #[test]
+    fn test_learned_index() {
+        let directory = TestDirectory::new("testing_learned");
+        // mostly dense ids with a few holes
+        let keys: Vec<i64> = (0..300_000)
+            .filter(|i| i % 1000 > 100 || i / 1000 % 7 != 0)
+            .collect();
+        let index = IndexKind::Learned { max_error: 32 };
+        let levels = directory.write_with_options(
+            "b_tree",
+            keys.iter().map(|key| (*key, -key)),
+            index,
+            PageFormat::Plain,
+            CodecKind::None,
+        );
+        rename_files(
+            &directory.prefix("b_tree"),
+            &directory.prefix("renamed"),
+            levels,
+        )
+        .unwrap();
+        let reader = directory.open("renamed", index);
+        let model = reader.model.as_ref().unwrap();
+        assert!(model.segments().len() < 100);
+        for key in 0..300_000 {
+            let expected = keys.binary_search(&key).ok().map(|_| -key);
+            assert_eq!(reader.get_item(key, &directory.cache).unwrap(), expected);
+        }
+        let result: Vec<_> = reader
+            .range(500, 520, &directory.cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(result, (500..=520).map(|i| (i, -i)).collect::<Vec<_>>());
+        // without asking for it the model is ignored
+        let reader = directory.open("renamed", IndexKind::BTree);
+        assert!(reader.model.is_none());
+        reader.delete().unwrap();
+        assert_eq!(directory.files(), 0);
+    }
+}
+
+#[cfg(test)]
+mod tests_pinning {
+    use super::fixture::TestDirectory;
     use super::*;
     use crate::cache_trait::NoCache;
 
-    fn setup_btree(items: Vec<(i64, i64)>) -> (BTreeReader, NoCache) {
-        let mut cache = NoCache;
+    #[test]
+    fn test_pin_all_or_nothing() {
+        let directory = TestDirectory::new("testing_b_tree_pinning");
+        directory.write("small", (0..1000).map(|i| (i, i)));
+        directory.write("large", (0..300_000).map(|i| (i, i)));
+        let small = directory.open("small", IndexKind::BTree);
+        let large = directory.open("large", IndexKind::BTree);
+        let upper_pages = |b_tree: &BTreeReader| b_tree.footer.level_pages.iter().sum::<u64>();
+        assert!(upper_pages(&large) > 1);
+        // room for the small BTree and all but one page of the large one
+        let capacity = (upper_pages(&small) + upper_pages(&large) - 1) as usize;
+        let cache = PageCache::with_pinned_capacity(NoCache, capacity);
+        assert!(small.pin_upper_levels(&cache).unwrap());
+        assert_eq!(cache.pinned_pages(), upper_pages(&small) as usize);
+        assert!(!large.pin_upper_levels(&cache).unwrap());
+        assert_eq!(cache.pinned_pages(), upper_pages(&small) as usize);
+        assert_eq!(large.get_item(150_000, &cache).unwrap(), Some(150_000));
+    }
+}
+
+#[cfg(test)]
+mod tests_checksum {
+    use super::fixture::TestDirectory;
+    use super::*;
+
+    #[test]
+    fn test_get_detects_corruption() {
+        let directory = TestDirectory::new("testing_checksum");
+        directory.write("b_tree", (0..1000).map(|i| (i, i)));
+        let mut items = fs::read("testing_checksum/b_tree.items.btree").unwrap();
+        items[PAGE_SIZE * 2 + 16] ^= 1;
+        fs::write("testing_checksum/b_tree.items.btree", items).unwrap();
+        let reader = directory.open("b_tree", IndexKind::BTree);
+        assert_eq!(reader.get_item(5, &directory.cache).unwrap(), Some(5));
+        match reader.get_item(PAGE_SIZE_AS_PAIR as i64 * 2 + 1, &directory.cache) {
+            Err(Error::Corruption { file_name, page }) => {
+                assert_eq!(file_name, "testing_checksum/b_tree.items.btree");
+                assert_eq!(page, 2);
+            }
+            result => panic!("expected a corruption error, got {:?}", result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_key_range {
+    use super::fixture::TestDirectory;
+    use super::*;
+
+    #[test]
+    fn test_key_range_pruning() {
+        let directory = TestDirectory::new("testing_key_range");
+        directory.write("b_tree", (1000..100_000).map(|i| (i, i)));
+        let reader = directory.open("b_tree", IndexKind::BTree);
+        assert!(reader.may_contain(1000) && reader.may_contain(99_999));
+        assert!(!reader.may_contain(999) && !reader.may_contain(100_000));
+        assert_eq!(reader.get_item(5, &directory.cache).unwrap(), None);
+        assert_eq!(
+            reader.get_item(i64::MAX - 1, &directory.cache).unwrap(),
+            None
+        );
+        assert!(reader.overlaps(0, 1000));
+        assert!(reader.overlaps(99_999, i64::MAX));
+        assert!(!reader.overlaps(0, 999));
+        assert!(!reader.overlaps(100_000, 200_000));
+    }
+}
+
+#[cfg(test)]
+mod tests_compressed_pages {
+    use super::fixture::TestDirectory;
+    use super::*;
+
+    #[test]
+    fn test_compressed_pages() {
+        let directory = TestDirectory::new("testing_compressed");
+        let length = 2_000_000;
+        let write = |name, items: &dyn Fn(i64) -> (i64, i64)| {
+            directory.write_with_options(
+                name,
+                (0..length).map(items),
+                IndexKind::BTree,
+                PageFormat::Compressed,
+                CodecKind::None,
+            )
+        };
+        // plain pages would need two upper levels
+        assert_eq!(write("b_tree", &|i| (i * 3, i % 100)), 1);
+        let reader = directory.open("b_tree", IndexKind::BTree);
+        assert_eq!(reader.footer.page_format, PageFormat::Compressed);
+        // three bytes a pair instead of sixteen
+        assert!(reader.footer.data_pages * 4 < (length as u64).div_ceil(PAGE_SIZE_AS_PAIR));
+        for i in (0..length).step_by(1009).chain([length - 1]) {
+            assert_eq!(
+                reader.get_item(i * 3, &directory.cache).unwrap(),
+                Some(i % 100)
+            );
+            assert_eq!(reader.get_item(i * 3 + 2, &directory.cache).unwrap(), None);
+        }
+        let result: Vec<_> = reader
+            .range(299, 310, &directory.cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            result,
+            (100..=103).map(|i| (i * 3, i % 100)).collect::<Vec<_>>()
+        );
+        let mut count = 0;
+        for (i, pair) in reader.level_iter().enumerate() {
+            assert_eq!(pair.unwrap(), (i as i64 * 3, i as i64 % 100));
+            count += 1;
+        }
+        assert_eq!(count, length);
+
+        let reader = directory.open("b_tree", IndexKind::Fences);
+        assert_eq!(
+            reader.fences.as_ref().unwrap().len() as u64,
+            reader.footer.data_pages - 1
+        );
+        for i in (0..length).step_by(1009).chain([length - 1]) {
+            assert_eq!(
+                reader.get_item(i * 3, &directory.cache).unwrap(),
+                Some(i % 100)
+            );
+        }
+
+        // sparse keys and large values need more pages and more levels
+        assert_eq!(write("sparse", &|i| (i * 1_000_003, i * 7919)), 2);
+        let reader = directory.open("sparse", IndexKind::BTree);
+        for i in (0..length).step_by(997).chain([length - 1]) {
+            assert_eq!(
+                reader.get_item(i * 1_000_003, &directory.cache).unwrap(),
+                Some(i * 7919)
+            );
+            assert_eq!(
+                reader
+                    .get_item(i * 1_000_003 + 1, &directory.cache)
+                    .unwrap(),
+                None
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_codec {
+    use super::fixture::TestDirectory;
+    use super::*;
+
+    #[test]
+    fn test_codec() {
+        let directory = TestDirectory::new("testing_codec");
+        let length = 100_000;
+        let levels = directory.write_with_options(
+            "b_tree",
+            (0..length).map(|i| (i * 3, i % 100)),
+            IndexKind::learned(),
+            PageFormat::Plain,
+            CodecKind::FrameOfReference,
+        );
+        let reader = directory.open("b_tree", IndexKind::BTree);
+        assert_eq!(reader.footer.codec, CodecKind::FrameOfReference);
+        assert_eq!(
+            reader.footer.data_pages,
+            (length as u64).div_ceil(PAGE_SIZE_AS_PAIR)
+        );
+        // 9 bits a key and 7 a value instead of 128 bits a pair
+        assert!(reader.footer.stored_pages * 6 < reader.footer.data_pages);
+        for index in [IndexKind::BTree, IndexKind::Fences, IndexKind::learned()] {
+            let reader = directory.open("b_tree", index);
+            for i in (0..length).step_by(101).chain([length - 1]) {
+                assert_eq!(
+                    reader.get_item(i * 3, &directory.cache).unwrap(),
+                    Some(i % 100)
+                );
+                assert_eq!(reader.get_item(i * 3 + 1, &directory.cache).unwrap(), None);
+            }
+            let result: Vec<_> = reader
+                .range(760, 770, &directory.cache)
+                .unwrap()
+                .collect::<Result<_>>()
+                .unwrap();
+            assert_eq!(
+                result,
+                (254..=256).map(|i| (i * 3, i % 100)).collect::<Vec<_>>()
+            );
+        }
+        let mut count = 0;
+        for (i, pair) in reader.level_iter().enumerate() {
+            assert_eq!(pair.unwrap(), (i as i64 * 3, i as i64 % 100));
+            count += 1;
+        }
+        assert_eq!(count, length);
+
+        // the offsets move along with the rest of the files
+        rename_files(
+            &directory.prefix("b_tree"),
+            &directory.prefix("renamed"),
+            levels,
+        )
+        .unwrap();
+        let reader = directory.open("renamed", IndexKind::BTree);
+        assert_eq!(reader.get_item(3000, &directory.cache).unwrap(), Some(0));
+        reader.delete().unwrap();
+        assert_eq!(directory.files(), 0);
+
+        // compressed pages are never encoded
+        directory.write_with_options(
+            "compressed",
+            [(1, 1)],
+            IndexKind::BTree,
+            PageFormat::Compressed,
+            CodecKind::FrameOfReference,
+        );
+        let reader = directory.open("compressed", IndexKind::BTree);
+        assert_eq!(reader.footer.codec, CodecKind::None);
+    }
+}
+
+#[cfg(test)]
+mod tests2 {
+    use super::*;
+    use crate::cache_trait::{NoCache, PageCache};
+
+    fn setup_btree(items: Vec<(i64, i64)>) -> (BTreeReader, Arc<PageCache<NoCache>>) {
+        let cache = Arc::new(PageCache::<NoCache>::default());
         // remove the testing directory if it exists and create a new one
         if fs::metadata("testing").is_ok() {
             fs::remove_dir_all("testing").unwrap();
@@ -328,64 +1261,92 @@ mod tests2 {
 
         let mut writer = BTreeWriter::new("testing/range_test".to_string());
         for item in items {
-            writer.add_item(item, &mut cache);
+            writer.add_item(item, &cache);
         }
-        let levels = writer.finish(&mut cache);
-        let reader = BTreeReader::new("testing/range_test".to_string(), levels);
+        writer.finish(&cache);
+        let reader = BTreeReader::open("testing/range_test".to_string()).unwrap();
         (reader, cache)
     }
 
     #[test]
     fn test_range_single_item() {
-        let (mut reader, mut cache) = setup_btree(vec![(5, 50)]);
-        let result: Vec<_> = reader.range(0, 10, &mut cache).collect();
+        let (reader, cache) = setup_btree(vec![(5, 50)]);
+        let result: Vec<_> = reader
+            .range(0, 10, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(result, vec![(5, 50)]);
     }
 
     #[test]
     fn test_range_multiple_items() {
-        let (mut reader, mut cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
-        let result: Vec<_> = reader.range(2, 6, &mut cache).collect();
+        let (reader, cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
+        let result: Vec<_> = reader
+            .range(2, 6, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(result, vec![(3, 30), (5, 50)]);
     }
 
     #[test]
     fn test_range_lower_bound() {
-        let (mut reader, mut cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
-        let result: Vec<_> = reader.range(3, 10, &mut cache).collect();
+        let (reader, cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
+        let result: Vec<_> = reader
+            .range(3, 10, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(result, vec![(3, 30), (5, 50), (7, 70)]);
     }
 
     #[test]
     fn test_range_upper_bound() {
-        let (mut reader, mut cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
-        let result: Vec<_> = reader.range(0, 5, &mut cache).collect();
+        let (reader, cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
+        let result: Vec<_> = reader
+            .range(0, 5, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(result, vec![(1, 10), (3, 30), (5, 50)]);
     }
 
     #[test]
     fn test_range_exact_bounds() {
-        let (mut reader, mut cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
-        let result: Vec<_> = reader.range(1, 7, &mut cache).collect();
+        let (reader, cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
+        let result: Vec<_> = reader
+            .range(1, 7, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(result, vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
     }
 
     #[test]
     fn test_range_out_of_bounds() {
-        let (mut reader, mut cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
-        let result: Vec<_> = reader.range(10, 20, &mut cache).collect();
+        let (reader, cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
+        let result: Vec<_> = reader
+            .range(10, 20, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(result, vec![]);
     }
 
     #[test]
     fn test_range_large_dataset() {
         let items: Vec<_> = (0..10000).map(|i| (i, i * 10)).collect();
-        let (mut reader, mut cache) = setup_btree(items);
+        let (reader, cache) = setup_btree(items);
 
-        let mut item = reader.get_item(5000, &mut cache);
+        let item = reader.get_item(5000, &cache).unwrap();
         assert_eq!(item, Some(50000));
         dbg!(item);
-        let result: Vec<_> = reader.range(5000, 5010, &mut cache).collect();
+        let result: Vec<_> = reader
+            .range(5000, 5010, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(
             result,
             vec![
@@ -404,32 +1365,60 @@ mod tests2 {
         );
     }
 
+    #[test]
+    fn test_range_across_pages() {
+        let items: Vec<_> = (0..1000).map(|i| (i, -i)).collect();
+        let (reader, cache) = setup_btree(items);
+        let result: Vec<_> = reader
+            .range(500, 520, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(result, (500..=520).map(|i| (i, -i)).collect::<Vec<_>>());
+    }
+
     #[test]
     fn test_range_reverse_bounds() {
-        let (mut reader, mut cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
-        let result: Vec<_> = reader.range(7, 1, &mut cache).collect();
+        let (reader, cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
+        let result: Vec<_> = reader
+            .range(7, 1, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(result, vec![]);
     }
 
     #[test]
     fn test_range_same_bounds() {
-        let (mut reader, mut cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
-        let result: Vec<_> = reader.range(5, 5, &mut cache).collect();
+        let (reader, cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
+        let result: Vec<_> = reader
+            .range(5, 5, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(result, vec![(5, 50)]);
     }
 
     #[test]
     fn test_range_non_existent_bounds() {
-        let (mut reader, mut cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
-        let result: Vec<_> = reader.range(2, 6, &mut cache).collect();
+        let (reader, cache) = setup_btree(vec![(1, 10), (3, 30), (5, 50), (7, 70)]);
+        let result: Vec<_> = reader
+            .range(2, 6, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(result, vec![(3, 30), (5, 50)]);
     }
 
     #[test]
     fn test_range_with_duplicates() {
-        let (mut reader, mut cache) =
+        let (reader, cache) =
             setup_btree(vec![(1, 10), (3, 30), (3, 31), (5, 50), (5, 51), (7, 70)]);
-        let result: Vec<_> = reader.range(2, 6, &mut cache).collect();
+        let result: Vec<_> = reader
+            .range(2, 6, &cache)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
         assert_eq!(result, vec![(3, 30), (3, 31), (5, 50), (5, 51)]);
     }
 
@@ -1,5 +1,6 @@
-use crate::buffer::{Buffer, PAGE_SIZE};
-use crate::cache_trait::Cache;
+use crate::buffer::{Buffer, PAGE_SIZE, PAGE_TRAILER_SIZE};
+use crate::cache_trait::{Cache, PageCache};
+use crate::error::Result;
 use crate::write_and_read::{Reader, Writer};
 use bitset_core::BitSet;
 use siphasher::sip128::SipHasher13;
@@ -8,6 +9,9 @@ pub const CACHE_LINE_SIZE_BYTES: u8 = 64;
 pub const NUM_CACHE_LINES: usize = PAGE_SIZE / CACHE_LINE_SIZE_BYTES as usize;
 const_assert!(NUM_CACHE_LINES == 64);
 const CACHE_LINE_SIZE_BITS: u64 = CACHE_LINE_SIZE_BYTES as u64 * 8;
+/// the last cache line of a page holds the checksum trailer so it can't hold bits
+const USABLE_CACHE_LINES: u64 = NUM_CACHE_LINES as u64 - 1;
+const_assert!(PAGE_TRAILER_SIZE <= CACHE_LINE_SIZE_BYTES as usize);
 
 const BITS_PER_PAGE: usize = 4096 * 8;
 struct BloomFilterWriter {
@@ -35,7 +39,7 @@ impl BloomFilterWriter {
         cache_line.bit_set(hashes.cache_line_offset4.into());
     }
 
-    fn write_to_disk<A: Cache>(self, file_name: String, cache: &mut A) {
+    fn write_to_disk<A: Cache>(self, file_name: String, cache: &PageCache<A>) {
         let mut writer = Writer::new(file_name);
         for (i, buffer) in self.bloom_filter.into_iter().enumerate() {
             cache.write_page(&mut writer, i as u64, buffer)
@@ -57,14 +61,14 @@ impl BloomFilterReader {
             num_pages,
         }
     }
-    fn check_item<A: Cache>(&mut self, key: i64, cache: &mut A) -> bool {
+    fn check_item<A: Cache>(&self, key: i64, cache: &PageCache<A>) -> Result<bool> {
         let hashes = Hashes::new(self.num_pages, key);
-        let disk_sector = cache.get_page(&mut self.file_reader, hashes.disk_sector, true, false);
+        let disk_sector = cache.get_page(&self.file_reader, hashes.disk_sector, true, false)?;
         let cache_line = &disk_sector.as_cache_lines()[hashes.cache_line as usize];
-        cache_line.bit_test(hashes.cache_line_offset1.into())
+        Ok(cache_line.bit_test(hashes.cache_line_offset1.into())
             && cache_line.bit_test(hashes.cache_line_offset2.into())
             && cache_line.bit_test(hashes.cache_line_offset3.into())
-            && cache_line.bit_test(hashes.cache_line_offset4.into())
+            && cache_line.bit_test(hashes.cache_line_offset4.into()))
     }
 }
 
@@ -79,10 +83,12 @@ struct Hashes {
 impl Hashes {
     fn new(num_disk_sectors: u64, key: i64) -> Hashes {
         let hasher = SipHasher13::new();
-        let hash1 = hasher.hash(&key.to_ne_bytes());
+        // hash the little endian bytes so every machine sets the same bits. The bits themselves
+        // are addressed byte by byte so they don't depend on endianness either.
+        let hash1 = hasher.hash(&key.to_le_bytes());
         let disk_sector = hash1.h1 % num_disk_sectors;
-        // use the last 8 bits of the second hash to determine which cache line to use
-        let cache_line = (hash1.h2 & CACHE_LINE_SIZE_BYTES as u64 - 1) as u8;
+        // use what's left of the first hash to determine which cache line to use
+        let cache_line = (hash1.h1 / num_disk_sectors % USABLE_CACHE_LINES) as u8;
         // use next 11
         // dbg!(CACHE_LINE_SIZE_BITS);
         // dbg!(2_u64.pow(9) as u64);
@@ -105,26 +111,26 @@ impl Hashes {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::cache_trait::NoCache;
+    use crate::cache_trait::{NoCache, PageCache};
     use std::fs;
     #[test]
     fn test_bloom_filter_one_page() {
         if fs::metadata("bloom_filter1").is_ok() {
             fs::remove_file("bloom_filter1").unwrap();
         }
-        let mut cache = NoCache;
+        let cache = PageCache::<NoCache>::default();
         let mut bloom_filter_writer = BloomFilterWriter::new(100);
         for i in 0..100 {
             bloom_filter_writer.add_key(i);
         }
-        bloom_filter_writer.write_to_disk("bloom_filter1".to_string(), &mut cache);
-        let mut bloom_filter_reader = BloomFilterReader::new("bloom_filter1".to_string());
+        bloom_filter_writer.write_to_disk("bloom_filter1".to_string(), &cache);
+        let bloom_filter_reader = BloomFilterReader::new("bloom_filter1".to_string());
         assert_eq!(bloom_filter_reader.num_pages, 1);
         for i in 0..100 {
-            assert!(bloom_filter_reader.check_item(i, &mut cache));
+            assert!(bloom_filter_reader.check_item(i, &cache).unwrap());
         }
         for i in 100..200 {
-            assert!(!bloom_filter_reader.check_item(i, &mut cache));
+            assert!(!bloom_filter_reader.check_item(i, &cache).unwrap());
         }
     }
 
@@ -133,19 +139,19 @@ mod tests {
         if fs::metadata("bloom_filter2").is_ok() {
             fs::remove_file("bloom_filter2").unwrap();
         }
-        let mut cache = NoCache;
+        let cache = PageCache::<NoCache>::default();
         let mut bloom_filter_writer = BloomFilterWriter::new((PAGE_SIZE * 2) as u64);
         for i in 0..1000 {
             bloom_filter_writer.add_key(i);
         }
-        bloom_filter_writer.write_to_disk("bloom_filter2".to_string(), &mut cache);
-        let mut bloom_filter_reader = BloomFilterReader::new("bloom_filter2".to_string());
+        bloom_filter_writer.write_to_disk("bloom_filter2".to_string(), &cache);
+        let bloom_filter_reader = BloomFilterReader::new("bloom_filter2".to_string());
         assert_eq!(bloom_filter_reader.num_pages, 2);
         for i in 0..1000 {
-            assert!(bloom_filter_reader.check_item(i, &mut cache));
+            assert!(bloom_filter_reader.check_item(i, &cache).unwrap());
         }
         for i in 1000..2000 {
-            assert!(!bloom_filter_reader.check_item(i, &mut cache));
+            assert!(!bloom_filter_reader.check_item(i, &cache).unwrap());
         }
     }
 }
@@ -1,7 +1,7 @@
 use crate::bloom_filter::{CACHE_LINE_SIZE_BYTES, NUM_CACHE_LINES};
 use std::ops::{Deref, DerefMut};
-use std::rc::Rc;
 use std::slice::{from_raw_parts, from_raw_parts_mut};
+use std::sync::Arc;
 
 /// represents a cache line
 type CacheLine = [u8; CACHE_LINE_SIZE_BYTES as usize];
@@ -13,9 +13,19 @@ const_assert!(PAGE_SIZE % NUM_CACHE_LINES == 0);
 
 pub const PAGE_SIZE: usize = 4096;
 pub const PAGE_SIZE_I64: usize = 4096 / 8;
+/// every page written to disk ends with a trailer holding the CRC32C of the rest of the page.
+/// It's 8 bytes rather than 4 so that the data before it stays a whole number of i64s.
+pub const PAGE_TRAILER_SIZE: usize = 8;
+/// the number of keys that fit in a page of an upper level of a BTree
+pub const KEYS_PER_PAGE: usize = (PAGE_SIZE - PAGE_TRAILER_SIZE) / 8;
+/// the number of pairs that fit in a page of the bottom level of a BTree
+pub const PAIRS_PER_PAGE: usize = KEYS_PER_PAGE / 2;
 
 pub const TOMBSTONE_U8: u8 = u8::MAX;
 pub const TOMBSTONE: i64 = i64::MAX;
+/// the value stored for a key that has been deleted. It shadows older values of the key until
+/// it reaches the bottom level, where it is dropped along with the data it covers.
+pub const DELETED: i64 = i64::MIN;
 
 // Note that we shouldn't need to use repr(C, align(4096)) because the buffer is 4096 bytes and
 // it should always be aligned to 4096 bytes. However, I'm not sure if rust guarantees this. So
@@ -67,37 +77,38 @@ impl InnerBuffer {
 /// being dropped (or copying it).
 ///
 /// It also has a bunch of helper methods for transmuting the buffer to different types.
-/// This is very unsafe to read data from disk that has been written on a different endian machine.
-/// So don't do that.
+/// On disk every key and value is stored little endian, whatever machine wrote it. The transmuted
+/// slices show the raw stored words, so pages of a BTree should be read and written through
+/// `key`, `pair` and friends, which do the conversion (it's free on little endian machines).
 #[derive(Debug, Clone)]
 pub struct Buffer {
-    pub(crate) inner_buffer: Rc<InnerBuffer>,
+    pub(crate) inner_buffer: Arc<InnerBuffer>,
 }
 
 impl Buffer {
     pub fn new() -> Self {
         let mut result = Self {
-            inner_buffer: Rc::new(InnerBuffer {
+            inner_buffer: Arc::new(InnerBuffer {
                 buffer: [0; PAGE_SIZE],
             }),
         };
-        result.as_mut_slice_i64().fill(TOMBSTONE);
+        result.as_mut_slice_i64().fill(TOMBSTONE.to_le());
         result
     }
 
     pub fn new_0() -> Self {
         let mut result = Self {
-            inner_buffer: Rc::new(InnerBuffer {
+            inner_buffer: Arc::new(InnerBuffer {
                 buffer: [0; PAGE_SIZE],
             }),
         };
         result
     }
     pub fn as_mut_slice<A>(&mut self) -> &mut [A] {
-        debug_assert!(Rc::strong_count(&self.inner_buffer) == 1);
+        debug_assert!(Arc::strong_count(&self.inner_buffer) == 1);
         // Safety: this function should only ever be called in the context where we haven't added it to the
         // cache yet. And thus never called clone so the strong count is 1.
-        unsafe { Rc::get_mut_unchecked(&mut self.inner_buffer).as_mut_slice() }
+        unsafe { Arc::get_mut_unchecked(&mut self.inner_buffer).as_mut_slice() }
     }
     pub fn as_slice<A>(&self) -> &[A] {
         self.inner_buffer.as_slice()
@@ -127,6 +138,50 @@ impl Buffer {
     pub fn as_cache_lines(&self) -> &[CacheLine] {
         self.as_slice()
     }
+    /// the key at `index` of a page of an upper level
+    pub fn key(&self, index: usize) -> i64 {
+        debug_assert!(index < KEYS_PER_PAGE);
+        i64::from_le(self.as_slice_i64()[index])
+    }
+    pub fn set_key(&mut self, index: usize, key: i64) {
+        debug_assert!(index < KEYS_PER_PAGE);
+        self.as_mut_slice_i64()[index] = key.to_le();
+    }
+    /// the pair at `index` of a page of the bottom level
+    pub fn pair(&self, index: usize) -> (i64, i64) {
+        debug_assert!(index < PAIRS_PER_PAGE);
+        let (key, value) = self.as_slice_pair()[index];
+        (i64::from_le(key), i64::from_le(value))
+    }
+    pub fn set_pair(&mut self, index: usize, pair: (i64, i64)) {
+        debug_assert!(index < PAIRS_PER_PAGE);
+        self.as_mut_slice_pair()[index] = (pair.0.to_le(), pair.1.to_le());
+    }
+    /// binary searches the keys of a page of an upper level, like `slice::binary_search`
+    pub fn search_keys(&self, key: i64) -> std::result::Result<usize, usize> {
+        self.as_slice_i64()[..KEYS_PER_PAGE].binary_search_by(|k| i64::from_le(*k).cmp(&key))
+    }
+    /// binary searches the pairs of a page of the bottom level by key
+    pub fn search_pairs(&self, key: i64) -> std::result::Result<usize, usize> {
+        self.as_slice_pair()[..PAIRS_PER_PAGE].binary_search_by(|p| i64::from_le(p.0).cmp(&key))
+    }
+
+    /// the CRC32C of everything in the page but the trailer
+    pub fn checksum(&self) -> u32 {
+        crc32c::crc32c(&self[..PAGE_SIZE - PAGE_TRAILER_SIZE])
+    }
+    /// writes the checksum into the trailer. Done by the `Writer` right before the page hits disk.
+    pub fn seal(&mut self) {
+        let checksum = self.checksum();
+        let trailer = &mut self[PAGE_SIZE - PAGE_TRAILER_SIZE..];
+        trailer[..4].copy_from_slice(&checksum.to_le_bytes());
+        trailer[4..].fill(0);
+    }
+    /// whether the trailer matches the contents of the page
+    pub fn is_intact(&self) -> bool {
+        let trailer = &self[PAGE_SIZE - PAGE_TRAILER_SIZE..];
+        trailer[..4] == self.checksum().to_le_bytes() && trailer[4..].iter().all(|b| *b == 0)
+    }
 }
 
 impl Deref for Buffer {
@@ -173,7 +228,41 @@ mod tests {
     #[test]
     fn check_empty_buffer() {
         let mut buffer = Buffer::new();
-        assert_eq!(buffer.as_slice_i64(), [TOMBSTONE; PAGE_SIZE_I64]);
+        assert!(buffer
+            .as_slice_i64()
+            .iter()
+            .all(|k| i64::from_le(*k) == TOMBSTONE));
+    }
+    #[test]
+    fn test_little_endian() {
+        let mut buffer = Buffer::new();
+        buffer.set_pair(0, (1, -2));
+        buffer.set_pair(1, (0x0102, 0));
+        let bytes = buffer.as_slice_u8();
+        assert_eq!(bytes[..8], [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            bytes[8..16],
+            [0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(bytes[16..24], [2, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(buffer.pair(1), (0x0102, 0));
+        assert_eq!(buffer.search_pairs(0x0102), Ok(1));
+        assert_eq!(buffer.search_pairs(5), Err(1));
+        let mut buffer = Buffer::new();
+        buffer.set_key(0, 256);
+        assert_eq!(buffer.as_slice_u8()[..2], [0, 1]);
+        assert_eq!(buffer.search_keys(300), Err(1));
+    }
+    #[test]
+    fn test_checksum() {
+        let mut buffer = Buffer::new();
+        buffer.set_pair(0, (1, 2));
+        assert!(!buffer.is_intact());
+        buffer.seal();
+        assert!(buffer.is_intact());
+        assert_eq!(buffer.pair(PAIRS_PER_PAGE - 1), (TOMBSTONE, TOMBSTONE));
+        buffer.as_mut_slice_u8()[100] ^= 1;
+        assert!(!buffer.is_intact());
     }
     // use super::*;
     // #[test]
@@ -1,154 +1,358 @@
-use std::cell::RefCell;
+use crate::buffer::Buffer;
+use crate::cache_trait::{self, Access, PageId};
 use std::fmt::{Debug, Formatter};
-use std::ops::Deref;
-use std::ptr;
-use std::rc::Rc;
 
+mod clock;
 mod linked_list;
+mod lru_k;
+mod row_cache;
+mod two_queue;
 
+pub use clock::ClockCache;
 use linked_list::{LinkListNode, LinkedList};
+pub use lru_k::LruKCache;
+pub use row_cache::{RowCache, RowLookup};
+pub use two_queue::TwoQueueCache;
 
-struct CacheItem<T> {
-    item: T,
-    node: *mut LinkListNode<LRUItem<T>>,
-    head_of_node: *mut LinkedList<CacheItem<T>>,
-}
-
-#[derive(Debug)]
-struct ItemTester {
-    hash: u64,
-    value: u64,
-}
-
-impl ItemTester {
-    fn new(hash: u64, value: u64) -> Self {
-        ItemTester { hash, value }
-    }
-}
+/// the pages an `LruCache` holds by default, 16 MiB worth
+pub const DEFAULT_CAPACITY_PAGES: usize = 4096;
+/// the most entries in a bucket before it is split
+const MAX_BUCKET_SIZE: usize = 8;
+/// past this many hash bits a bucket takes any number of entries. Only keys with the same hash
+/// would get there.
+const MAX_DEPTH: usize = 48;
 
 trait KeyHash {
     fn hash(&self) -> u64;
 }
 
-impl KeyHash for ItemTester {
+impl KeyHash for PageId {
     fn hash(&self) -> u64 {
-        self.hash
+        // the low bits pick the bucket, so both halves are mixed into all of them
+        let mut hash = self.file.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ self.page;
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+        hash ^ (hash >> 33)
     }
 }
 
-impl PartialEq<Self> for ItemTester {
-    fn eq(&self, other: &Self) -> bool {
-        self.value == other.value
-    }
+struct CacheItem<K, V> {
+    key: K,
+    value: V,
+    /// the node of the item in the LRU list
+    node: *mut LinkListNode<K>,
 }
 
-impl Eq for ItemTester {}
-
-impl<T: Debug> Debug for CacheItem<T> {
+impl<K: Debug, V> Debug for CacheItem<K, V> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        self.item.fmt(f)
+        self.key.fmt(f)
     }
 }
 
-struct LRUItem<T> {
-    node: *mut LinkListNode<CacheItem<T>>,
-}
-
-impl<T> LRUItem<T> {
-    fn pop_in_cache(&mut self) -> Box<LinkListNode<CacheItem<T>>> {
-        unsafe { (*(*self.node).data.head_of_node).pop(self.node) }
-    }
-}
-
-impl<T: Debug> Debug for LRUItem<T> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        unsafe { (*self.node).data.fmt(f) }
-    }
+#[derive(Debug)]
+struct Bucket<K, V> {
+    /// the number of low bits of the hash shared by every item in the bucket
+    local_depth: usize,
+    items: Vec<CacheItem<K, V>>,
 }
 
-type LRU<T> = LinkedList<LRUItem<T>>;
-type RCcacheItem<T> = Rc<RefCell<LinkedList<CacheItem<T>>>>;
+/// A bounded map that forgets the least recently used item when it's full.
+///
+/// Items are found through an extendible hash table: the low `exponent` bits of a hash index the
+/// directory, which points to the bucket holding the item. Several entries of the directory can
+/// share a bucket, and a full bucket is split in two (doubling the directory first if no entries
+/// share it) so the table grows a bucket at a time instead of being rehashed all at once.
+/// The items are also linked into an LRU list, most recently used first.
 #[derive(Debug)]
-struct Cache<T: KeyHash> {
-    directory: Vec<RCcacheItem<T>>,
+struct Cache<K, V> {
+    /// for every value of the low `exponent` bits of a hash, the bucket holding those items
+    directory: Vec<usize>,
+    buckets: Vec<Bucket<K, V>>,
     exponent: usize,
     max_bucket_size: usize,
     max_length: usize,
-    lru: LRU<T>,
+    lru: LinkedList<K>,
 }
 
-impl<T: KeyHash + Eq> Cache<T> {
-    fn new(exponent: usize, max_bucket_size: usize) -> Self {
+// The raw pointers only point to the nodes of `lru`, which the cache owns.
+unsafe impl<K: Send, V: Send> Send for Cache<K, V> {}
+
+impl<K: KeyHash + Eq + Copy, V> Cache<K, V> {
+    fn new(exponent: usize, max_bucket_size: usize, max_length: usize) -> Self {
         Cache {
-            directory: (0..2 ^ exponent)
-                .map(|_| Rc::new(RefCell::new(LinkedList::new())))
-                .collect(),
+            directory: vec![0; 1 << exponent],
+            buckets: vec![Bucket {
+                local_depth: 0,
+                items: Vec::new(),
+            }],
             exponent,
             max_bucket_size,
-            max_length: 100,
+            max_length,
             lru: LinkedList::new(),
         }
     }
 
-    fn create_new_cache_item(&mut self, item: T) -> Box<LinkListNode<CacheItem<T>>> {
-        let mut cache_item = Box::new(LinkListNode::new(CacheItem {
-            item: item,
-            node: ptr::null_mut(),
-            head_of_node: ptr::null_mut(),
-        }));
+    fn bucket(&self, hash: u64) -> usize {
+        self.directory[hash as usize & ((1 << self.exponent) - 1)]
+    }
+
+    /// the item with `key`, which becomes the most recently used one
+    fn get(&mut self, key: &K) -> Option<&V> {
+        let bucket = self.bucket(key.hash());
+        let item = self.buckets[bucket]
+            .items
+            .iter()
+            .find(|item| item.key == *key)?;
+        self.lru.pop_and_push_front(item.node);
+        Some(&item.value)
+    }
+
+    /// the item with `key`, without counting as a use of it
+    fn peek(&self, key: &K) -> Option<&V> {
+        let bucket = self.bucket(key.hash());
+        self.buckets[bucket]
+            .items
+            .iter()
+            .find(|item| item.key == *key)
+            .map(|item| &item.value)
+    }
+
+    fn remove(&mut self, key: &K) {
+        let bucket = self.bucket(key.hash());
+        let items = &mut self.buckets[bucket].items;
+        if let Some(position) = items.iter().position(|item| item.key == *key) {
+            let item = items.swap_remove(position);
+            self.lru.pop(item.node);
+        }
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.peek(key).is_some()
+    }
 
-        let mut lru_node = Box::new(LinkListNode::new(LRUItem {
-            node: &mut *cache_item,
-        }));
+    fn len(&self) -> usize {
+        self.lru.len()
+    }
 
-        cache_item.data.node = &mut *lru_node;
-        self.lru.push_node_front(lru_node);
-        cache_item
+    /// removes every item whose key matches `predicate`
+    fn remove_if(&mut self, mut predicate: impl FnMut(&K) -> bool) {
+        for bucket in self.buckets.iter_mut() {
+            let mut i = 0;
+            while i < bucket.items.len() {
+                if predicate(&bucket.items[i].key) {
+                    let item = bucket.items.swap_remove(i);
+                    self.lru.pop(item.node);
+                } else {
+                    i += 1;
+                }
+            }
+        }
     }
 
-    fn add_to_index(&mut self, index: usize, mut node: Box<LinkListNode<CacheItem<T>>>) {
-        let mut bucket = self.directory[index].deref().borrow_mut();
-        if bucket.len > self.max_bucket_size && Rc::strong_count(&self.directory[index]) > 1 {
-            bucket.valid = false;
+    /// splits the bucket at `bucket` in two on the next bit of the hash
+    fn split(&mut self, bucket: usize) {
+        let local_depth = self.buckets[bucket].local_depth;
+        if local_depth == self.exponent {
+            self.directory.extend_from_within(..);
+            self.exponent += 1;
         }
-        if !bucket.valid {
-            let copy = self.directory[index].clone();
-            drop(bucket);
-            self.directory[index] = Rc::new(RefCell::new(LinkedList::new()));
-            let mut bucket = copy.deref().borrow_mut();
-            while let Some(item) = bucket.pop_front_node() {
-                self.add_node(item);
+        let bit = 1 << local_depth;
+        let (moved, kept) = std::mem::take(&mut self.buckets[bucket].items)
+            .into_iter()
+            .partition(|item| item.key.hash() & bit != 0);
+        self.buckets[bucket] = Bucket {
+            local_depth: local_depth + 1,
+            items: kept,
+        };
+        self.buckets.push(Bucket {
+            local_depth: local_depth + 1,
+            items: moved,
+        });
+        let new_bucket = self.buckets.len() - 1;
+        for (i, entry) in self.directory.iter_mut().enumerate() {
+            if *entry == bucket && i as u64 & bit != 0 {
+                *entry = new_bucket;
             }
-            self.add_to_index(index, node);
+        }
+    }
+
+    /// adds an item, evicting the least recently used one if the cache is full.
+    /// The key must not be in the cache yet.
+    fn add_item(&mut self, key: K, value: V) {
+        self.add(key, value, false);
+    }
+
+    /// adds an item as if it was the least recently used one, so it's the next to be evicted
+    /// unless it gets used. If the cache is full that's right away.
+    fn add_cold_item(&mut self, key: K, value: V) {
+        self.add(key, value, true);
+    }
+
+    fn add(&mut self, key: K, value: V, cold: bool) {
+        debug_assert!(!self.contains(&key));
+        let hash = key.hash();
+        let mut bucket = self.bucket(hash);
+        while self.buckets[bucket].items.len() >= self.max_bucket_size
+            && self.buckets[bucket].local_depth < MAX_DEPTH
+        {
+            self.split(bucket);
+            bucket = self.bucket(hash);
+        }
+        let node = if cold {
+            self.lru.push_back(key)
         } else {
-            node.data.head_of_node = self.directory[index].as_ptr();
-            bucket.push_node_front(node);
+            self.lru.push_front(key)
+        };
+        self.buckets[bucket]
+            .items
+            .push(CacheItem { key, value, node });
+        if self.lru.len() > self.max_length {
+            let evicted = self.lru.pop_back().data;
+            let bucket = self.bucket(evicted.hash());
+            let items = &mut self.buckets[bucket].items;
+            let position = items.iter().position(|item| item.key == evicted).unwrap();
+            items.swap_remove(position);
+        }
+    }
+}
+
+/// A page cache of a fixed number of pages that evicts the least recently used one.
+/// Pages read by scans are looked up without being moved up and are added as the least recently
+/// used page, so a scan only ever takes up the pages that would be evicted next anyway.
+pub struct LruCache {
+    pages: Cache<PageId, Buffer>,
+}
+
+impl LruCache {
+    pub fn new(capacity_pages: usize) -> LruCache {
+        LruCache {
+            pages: Cache::new(0, MAX_BUCKET_SIZE, capacity_pages),
         }
     }
-    fn add_node(&mut self, node: Box<LinkListNode<CacheItem<T>>>) {
-        let index = node.data.item.hash() as usize & ((2 ^ self.exponent) - 1);
-        self.add_to_index(index, node);
+
+    /// the number of pages cached
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for LruCache {
+    fn default() -> Self {
+        LruCache::new(DEFAULT_CAPACITY_PAGES)
+    }
+}
+
+impl cache_trait::Cache for LruCache {
+    fn with_capacity(capacity_pages: usize) -> Self {
+        LruCache::new(capacity_pages)
     }
-    fn add_item(&mut self, item: T) {
-        let node = self.create_new_cache_item(item);
-        self.add_node(node);
-        if self.lru.len >= self.max_length {
-            let mut lru_node = self.lru.pop_back();
-            lru_node.data.pop_in_cache();
-            debug_assert!(self.lru.len == self.max_length - 1);
+
+    fn get(&mut self, page: PageId, access: Access) -> Option<Buffer> {
+        match access {
+            Access::Point => self.pages.get(&page).cloned(),
+            Access::Scan => self.pages.peek(&page).cloned(),
+        }
+    }
+
+    fn insert(&mut self, page: PageId, buffer: Buffer, access: Access) {
+        if self.pages.contains(&page) {
+            return;
+        }
+        match access {
+            Access::Point => self.pages.add_item(page, buffer),
+            Access::Scan => self.pages.add_cold_item(page, buffer),
         }
     }
+
+    fn invalidate_file(&mut self, file: u64) {
+        self.pages.remove_if(|page| page.file == file);
+    }
 }
 
 #[cfg(test)]
-mod tests2 {
-    // use super::*;
+mod tests {
+    use super::*;
+    use crate::cache_trait::PageCache;
+    use crate::write_and_read::{Reader, Writer};
+
+    fn page(page: u64) -> PageId {
+        PageId { file: 0, page }
+    }
 
     #[test]
-    fn test() {
-        // let mut cache = Cache::new(10, 10);
-        // cache.add_to_index(0, ItemTester::new(1, 2));
-        // cache.add_to_index(0, ItemTester::new(2, 5));
-        // dbg!(cache);
+    fn test_evicts_least_recently_used() {
+        let mut cache = Cache::new(0, 2, 100);
+        for i in 0..100 {
+            cache.add_item(page(i), i);
+        }
+        // the buckets split as they fill up
+        assert!(cache.exponent >= 6);
+        assert!(cache
+            .buckets
+            .iter()
+            .all(|bucket| bucket.items.len() <= 2 && bucket.local_depth <= cache.exponent));
+        assert_eq!(cache.get(&page(0)), Some(&0));
+        for i in 100..150 {
+            cache.add_item(page(i), i);
+        }
+        assert_eq!(cache.len(), 100);
+        assert_eq!(cache.get(&page(0)), Some(&0));
+        assert!((1..51).all(|i| cache.get(&page(i)).is_none()));
+        assert!((51..150).all(|i| cache.get(&page(i)) == Some(&i)));
+        assert_eq!(
+            cache
+                .buckets
+                .iter()
+                .map(|bucket| bucket.items.len())
+                .sum::<usize>(),
+            100
+        );
+        // every directory entry points to the bucket its items hash to
+        for (i, bucket) in cache.directory.iter().enumerate() {
+            let bucket = &cache.buckets[*bucket];
+            let mask = (1 << bucket.local_depth) - 1;
+            assert!(bucket
+                .items
+                .iter()
+                .all(|item| item.key.hash() & mask == i as u64 & mask));
+        }
+    }
+
+    #[test]
+    fn test_caches_pages() {
+        let _ = std::fs::remove_file("test_lru_cache");
+        let mut writer = Writer::new("test_lru_cache".to_string());
+        for i in 0..10 {
+            let mut buffer = Buffer::new();
+            buffer.set_key(0, i);
+            writer.write_page(&mut buffer);
+        }
+        let reader = Reader::new("test_lru_cache");
+        let cache = PageCache::new(LruCache::new(4));
+        for i in 0..10 {
+            let buffer = cache.get_page(&reader, i as u64, true, i % 2 == 0).unwrap();
+            assert_eq!(buffer.key(0), i);
+        }
+        assert_eq!(cache.lock().len(), 4);
+        // served from the cache, even when the file is gone
+        std::fs::remove_file("test_lru_cache").unwrap();
+        let buffer = cache.get_page(&reader, 8, true, true).unwrap();
+        assert_eq!(buffer.key(0), 8);
+        cache.invalidate(&reader);
+        assert!(cache.lock().is_empty());
+        // the reader still has the file open, so it reads the page again
+        cache.get_page(&reader, 8, true, true).unwrap();
+        assert_eq!(cache.lock().len(), 1);
+        // a file that takes the name of a deleted one doesn't see its pages
+        let mut writer = Writer::new("test_lru_cache".to_string());
+        writer.write_page(&mut Buffer::new());
+        let reader = Reader::new("test_lru_cache");
+        let buffer = cache.get_page(&reader, 0, true, true).unwrap();
+        assert_ne!(buffer.key(0), 0);
+        std::fs::remove_file("test_lru_cache").unwrap();
     }
 }
@@ -0,0 +1,142 @@
+use super::DEFAULT_CAPACITY_PAGES;
+use crate::buffer::Buffer;
+use crate::cache_trait::{Access, Cache, PageId};
+use std::collections::HashMap;
+
+struct Frame {
+    page: PageId,
+    buffer: Buffer,
+    /// set when the page is used, a page is only evicted once the hand finds it unset
+    referenced: bool,
+}
+
+/// A page cache of a fixed number of frames that approximates LRU with a clock: a hand sweeps
+/// over the frames, giving pages that were used since its last pass another round and evicting
+/// the first one that wasn't. Using a page only sets a bit, so hits are cheap.
+///
+/// Pages read by scans don't set the bit, and are added without it, so they are the first to go.
+pub struct ClockCache {
+    frames: Vec<Option<Frame>>,
+    /// the frame of every cached page
+    frame_of: HashMap<PageId, usize>,
+    hand: usize,
+    capacity: usize,
+}
+
+impl ClockCache {
+    pub fn new(capacity_pages: usize) -> ClockCache {
+        assert!(capacity_pages > 0);
+        ClockCache {
+            frames: Vec::with_capacity(capacity_pages),
+            frame_of: HashMap::with_capacity(capacity_pages),
+            hand: 0,
+            capacity: capacity_pages,
+        }
+    }
+
+    /// the number of pages cached
+    pub fn len(&self) -> usize {
+        self.frame_of.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// a frame to put a new page in, evicting a page if they're all taken
+    fn free_frame(&mut self) -> usize {
+        if self.frames.len() < self.capacity {
+            self.frames.push(None);
+            return self.frames.len() - 1;
+        }
+        loop {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % self.frames.len();
+            match &mut self.frames[frame] {
+                None => return frame,
+                Some(used) if used.referenced => used.referenced = false,
+                Some(evicted) => {
+                    self.frame_of.remove(&evicted.page);
+                    return frame;
+                }
+            }
+        }
+    }
+}
+
+impl Default for ClockCache {
+    fn default() -> Self {
+        ClockCache::new(DEFAULT_CAPACITY_PAGES)
+    }
+}
+
+impl Cache for ClockCache {
+    fn with_capacity(capacity_pages: usize) -> Self {
+        ClockCache::new(capacity_pages)
+    }
+
+    fn get(&mut self, page: PageId, access: Access) -> Option<Buffer> {
+        let frame = self.frames[*self.frame_of.get(&page)?].as_mut().unwrap();
+        if access == Access::Point {
+            frame.referenced = true;
+        }
+        Some(frame.buffer.clone())
+    }
+
+    fn insert(&mut self, page: PageId, buffer: Buffer, access: Access) {
+        if self.frame_of.contains_key(&page) {
+            return;
+        }
+        let frame = self.free_frame();
+        self.frames[frame] = Some(Frame {
+            page,
+            buffer,
+            referenced: access == Access::Point,
+        });
+        self.frame_of.insert(page, frame);
+    }
+
+    fn invalidate_file(&mut self, file: u64) {
+        let frames = &mut self.frames;
+        self.frame_of.retain(|page, frame| {
+            if page.file == file {
+                frames[*frame] = None;
+            }
+            page.file != file
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page: u64) -> PageId {
+        PageId { file: 0, page }
+    }
+
+    #[test]
+    fn test_second_chance() {
+        let mut cache = ClockCache::new(4);
+        for i in 0..4 {
+            cache.insert(page(i), Buffer::new(), Access::Point);
+        }
+        // the hand clears every bit on its first pass and then evicts page 0, by the time it
+        // gets back round page 1 has been used again
+        cache.insert(page(4), Buffer::new(), Access::Point);
+        assert!(cache.get(page(0), Access::Point).is_none());
+        assert!(cache.get(page(1), Access::Point).is_some());
+        cache.insert(page(5), Buffer::new(), Access::Point);
+        assert!(cache.get(page(2), Access::Point).is_none());
+        assert!(cache.get(page(1), Access::Point).is_some());
+        // scanned pages are evicted before any page that was used
+        cache.insert(page(6), Buffer::new(), Access::Scan);
+        cache.insert(page(7), Buffer::new(), Access::Point);
+        assert!(cache.get(page(6), Access::Scan).is_none());
+        assert_eq!(cache.len(), 4);
+        cache.invalidate_file(0);
+        assert!(cache.is_empty());
+        cache.insert(page(8), Buffer::new(), Access::Point);
+        assert!(cache.get(page(8), Access::Point).is_some());
+    }
+}
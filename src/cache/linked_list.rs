@@ -128,7 +128,7 @@ impl<T> LinkedList<T> {
         self.len += 1;
     }
 
-    fn push_front(&mut self, data: T) -> *mut LinkListNode<T> {
+    pub(crate) fn push_front(&mut self, data: T) -> *mut LinkListNode<T> {
         let node = Box::new(LinkListNode::new(data));
         let result = Box::into_raw(node);
         unsafe {
@@ -137,6 +137,22 @@ impl<T> LinkedList<T> {
         result
     }
 
+    pub(crate) fn push_back(&mut self, data: T) -> *mut LinkListNode<T> {
+        let node = Box::into_raw(Box::new(LinkListNode::new(data)));
+        if self.tail.is_null() {
+            debug_assert!(self.len == 0);
+            self.head = node;
+        } else {
+            unsafe {
+                (*self.tail).next = node;
+                (*node).prev = self.tail;
+            }
+        }
+        self.tail = node;
+        self.len += 1;
+        node
+    }
+
     pub(crate) fn pop(&mut self, node: *mut LinkListNode<T>) -> Box<LinkListNode<T>> {
         debug_assert!(self.len > 0);
         debug_assert!(node != ptr::null_mut());
@@ -177,12 +193,12 @@ impl<T> LinkedList<T> {
         }
     }
 
-    fn pop_and_push_front(&mut self, node: *mut LinkListNode<T>) {
+    pub(crate) fn pop_and_push_front(&mut self, node: *mut LinkListNode<T>) {
         let item = self.pop(node);
         self.push_node_front(item);
     }
 
-    fn len(&self) -> usize {
+    pub(crate) fn len(&self) -> usize {
         self.len
     }
 
@@ -0,0 +1,213 @@
+use super::DEFAULT_CAPACITY_PAGES;
+use crate::buffer::Buffer;
+use crate::cache_trait::{Access, Cache, PageId};
+use std::collections::{BTreeMap, HashMap};
+
+/// two uses tell a page that's used over and over from one that was used once, more remember the
+/// past for longer and make the cache slower to adapt
+pub const DEFAULT_K: usize = 2;
+
+struct Entry {
+    buffer: Buffer,
+    /// when the page was last used, the last `k` times at most, oldest first
+    uses: Vec<u64>,
+    /// when the page was added, breaks ties between pages with the same uses
+    added: u64,
+}
+
+/// the key of an entry in `LruKCache::order`
+type Rank = (u64, u64, u64);
+
+/// A page cache that evicts the page whose `k`-th most recent use is the oldest (the LRU-K
+/// algorithm of O'Neil, O'Neil and Weikum). A page used fewer than `k` times counts as having been
+/// used at the beginning of time, so it goes before any page that was used `k` times however long
+/// ago, and pages used once by a burst of reads don't push out the ones used steadily.
+///
+/// The uses of evicted pages are remembered for a while, so a page doesn't have to start over
+/// when it's read again soon after. Reads by scans aren't counted as uses at all.
+pub struct LruKCache {
+    entries: HashMap<PageId, Entry>,
+    /// the cached pages, the first is the next one to evict
+    order: BTreeMap<Rank, PageId>,
+    /// the uses of the pages evicted last and when they were evicted, their key in
+    /// `retained_order` which holds them oldest first
+    retained: HashMap<PageId, (u64, Vec<u64>)>,
+    retained_order: BTreeMap<u64, PageId>,
+    /// counts the operations, to order the uses
+    clock: u64,
+    capacity: usize,
+    k: usize,
+}
+
+impl LruKCache {
+    pub fn new(capacity_pages: usize, k: usize) -> LruKCache {
+        assert!(capacity_pages > 0 && k > 0);
+        LruKCache {
+            entries: HashMap::with_capacity(capacity_pages),
+            order: BTreeMap::new(),
+            retained: HashMap::new(),
+            retained_order: BTreeMap::new(),
+            clock: 0,
+            capacity: capacity_pages,
+            k,
+        }
+    }
+
+    /// the number of pages cached
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// the `k`-th most recent use of the page, then its most recent one, both 0 if there's none
+    fn rank(&self, entry: &Entry) -> Rank {
+        let kth = match entry.uses.len() {
+            len if len >= self.k => entry.uses[len - self.k],
+            _ => 0,
+        };
+        (kth, entry.uses.last().copied().unwrap_or(0), entry.added)
+    }
+
+    fn record_use(&self, uses: &mut Vec<u64>, now: u64) {
+        if uses.len() == self.k {
+            uses.remove(0);
+        }
+        uses.push(now);
+    }
+
+    fn evict(&mut self) {
+        let (_, page) = self.order.pop_first().unwrap();
+        let entry = self.entries.remove(&page).unwrap();
+        if !entry.uses.is_empty() {
+            let stamp = self.tick();
+            // a page added by a scan can have uses retained from before
+            if let Some((old_stamp, _)) = self.retained.insert(page, (stamp, entry.uses)) {
+                self.retained_order.remove(&old_stamp);
+            }
+            self.retained_order.insert(stamp, page);
+            if self.retained_order.len() > self.capacity {
+                let (_, forgotten) = self.retained_order.pop_first().unwrap();
+                self.retained.remove(&forgotten);
+            }
+        }
+    }
+}
+
+impl Default for LruKCache {
+    fn default() -> Self {
+        LruKCache::new(DEFAULT_CAPACITY_PAGES, DEFAULT_K)
+    }
+}
+
+impl Cache for LruKCache {
+    fn with_capacity(capacity_pages: usize) -> Self {
+        LruKCache::new(capacity_pages, DEFAULT_K)
+    }
+
+    fn get(&mut self, page: PageId, access: Access) -> Option<Buffer> {
+        if access == Access::Scan {
+            return self.entries.get(&page).map(|entry| entry.buffer.clone());
+        }
+        let now = self.clock + 1;
+        let mut entry = self.entries.remove(&page)?;
+        self.clock = now;
+        self.order.remove(&self.rank(&entry));
+        self.record_use(&mut entry.uses, now);
+        self.order.insert(self.rank(&entry), page);
+        let buffer = entry.buffer.clone();
+        self.entries.insert(page, entry);
+        Some(buffer)
+    }
+
+    fn insert(&mut self, page: PageId, buffer: Buffer, access: Access) {
+        if self.entries.contains_key(&page) {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.evict();
+        }
+        let now = self.tick();
+        let mut uses = Vec::with_capacity(self.k);
+        if access == Access::Point {
+            if let Some((stamp, retained)) = self.retained.remove(&page) {
+                self.retained_order.remove(&stamp);
+                uses = retained;
+            }
+            self.record_use(&mut uses, now);
+        }
+        let entry = Entry {
+            buffer,
+            uses,
+            added: now,
+        };
+        self.order.insert(self.rank(&entry), page);
+        self.entries.insert(page, entry);
+    }
+
+    fn invalidate_file(&mut self, file: u64) {
+        let pages: Vec<PageId> = self
+            .entries
+            .keys()
+            .filter(|page| page.file == file)
+            .copied()
+            .collect();
+        for page in pages {
+            let entry = self.entries.remove(&page).unwrap();
+            self.order.remove(&self.rank(&entry));
+        }
+        self.retained.retain(|page, _| page.file != file);
+        self.retained_order.retain(|_, page| page.file != file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page: u64) -> PageId {
+        PageId { file: 0, page }
+    }
+
+    #[test]
+    fn test_evicts_oldest_kth_use() {
+        let mut cache = LruKCache::new(4, 2);
+        for i in 0..4 {
+            cache.insert(page(i), Buffer::new(), Access::Point);
+        }
+        cache.get(page(0), Access::Point);
+        cache.get(page(1), Access::Point);
+        // 2 and 3 were only used once, 2 before 3
+        cache.insert(page(4), Buffer::new(), Access::Point);
+        assert!(cache.get(page(2), Access::Scan).is_none());
+        cache.insert(page(5), Buffer::new(), Access::Point);
+        assert!(cache.get(page(3), Access::Scan).is_none());
+        // scanned pages go first and scans don't count as uses
+        for i in 100..200 {
+            if cache.get(page(i), Access::Scan).is_none() {
+                cache.insert(page(i), Buffer::new(), Access::Scan);
+            }
+        }
+        assert!(cache.get(page(0), Access::Point).is_some());
+        assert!(cache.get(page(1), Access::Point).is_some());
+        assert_eq!(cache.len(), 4);
+        // 2 comes back with the use it had, so it has been used twice and outlasts the pages
+        // used once
+        cache.insert(page(2), Buffer::new(), Access::Point);
+        cache.insert(page(6), Buffer::new(), Access::Point);
+        cache.insert(page(7), Buffer::new(), Access::Point);
+        assert!(cache.get(page(2), Access::Scan).is_some());
+        assert!(cache.get(page(4), Access::Scan).is_none());
+        assert_eq!(cache.order.len(), cache.entries.len());
+        assert_eq!(cache.retained.len(), cache.retained_order.len());
+        cache.invalidate_file(0);
+        assert!(cache.is_empty() && cache.order.is_empty() && cache.retained.is_empty());
+    }
+}
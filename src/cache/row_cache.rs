@@ -0,0 +1,126 @@
+use super::{Cache, KeyHash, MAX_BUCKET_SIZE};
+use std::sync::Mutex;
+
+impl KeyHash for i64 {
+    fn hash(&self) -> u64 {
+        // keys are often dense, so their bits are mixed before the low ones pick the bucket
+        let mut hash = (*self as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xFF51_AFD7_ED55_8CCD);
+        hash ^ (hash >> 33)
+    }
+}
+
+/// what a `RowCache` knows about a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RowLookup {
+    /// the value of the key, None if it isn't in the database
+    Cached(Option<i64>),
+    /// the key isn't cached. The lookup should pass `writes` on to `RowCache::fill`.
+    Missing { writes: u64 },
+}
+
+struct Rows {
+    values: Cache<i64, Option<i64>>,
+    /// counts the writes, so a lookup can tell whether one happened while it read
+    writes: u64,
+}
+
+/// Caches what looking a key up in a database returned, keys that weren't found included, so a
+/// hot key costs a hash lookup instead of a search of the memtable and of every level.
+///
+/// A write drops the row of its key, rather than updating it, as writes to the same key that
+/// race could update it out of order. A lookup that missed only caches its result if no write
+/// happened while it read, as a write that went in after it read would be lost.
+/// Flushes and compactions move pairs around without changing what a lookup returns, so they
+/// leave the rows alone.
+pub struct RowCache {
+    rows: Mutex<Rows>,
+}
+
+impl RowCache {
+    pub fn new(capacity_rows: usize) -> RowCache {
+        RowCache {
+            rows: Mutex::new(Rows {
+                values: Cache::new(0, MAX_BUCKET_SIZE, capacity_rows),
+                writes: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, key: i64) -> RowLookup {
+        let mut rows = self.rows.lock().unwrap();
+        match rows.values.get(&key) {
+            Some(value) => RowLookup::Cached(*value),
+            None => RowLookup::Missing {
+                writes: rows.writes,
+            },
+        }
+    }
+
+    /// caches `value` for `key`, looked up after `writes` writes, unless another write happened
+    /// since
+    pub fn fill(&self, key: i64, value: Option<i64>, writes: u64) {
+        let mut rows = self.rows.lock().unwrap();
+        if rows.writes == writes && !rows.values.contains(&key) {
+            rows.values.add_item(key, value);
+        }
+    }
+
+    /// records a write to `key`, once it's visible to lookups
+    pub fn write(&self, key: i64) {
+        let mut rows = self.rows.lock().unwrap();
+        rows.writes += 1;
+        rows.values.remove(&key);
+    }
+
+    /// forgets every row, after the database changed other than through writes
+    pub fn clear(&self) {
+        let mut rows = self.rows.lock().unwrap();
+        rows.writes += 1;
+        rows.values.remove_if(|_| true);
+    }
+
+    /// the number of rows cached
+    pub fn len(&self) -> usize {
+        self.rows.lock().unwrap().values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookups_racing_writes() {
+        let rows = RowCache::new(2);
+        let RowLookup::Missing { writes } = rows.get(1) else {
+            panic!()
+        };
+        rows.fill(1, None, writes);
+        assert_eq!(rows.get(1), RowLookup::Cached(None));
+        rows.write(1);
+        let RowLookup::Missing { writes } = rows.get(1) else {
+            panic!()
+        };
+        rows.fill(1, Some(10), writes);
+        assert_eq!(rows.get(1), RowLookup::Cached(Some(10)));
+        // a lookup that read before a write doesn't cache what it read
+        let RowLookup::Missing { writes } = rows.get(2) else {
+            panic!()
+        };
+        rows.write(2);
+        rows.fill(2, None, writes);
+        assert!(matches!(rows.get(2), RowLookup::Missing { .. }));
+        rows.fill(2, Some(20), writes + 1);
+        rows.fill(3, Some(30), writes + 1);
+        assert_eq!(rows.len(), 2);
+        assert!(matches!(rows.get(1), RowLookup::Missing { .. }));
+        rows.clear();
+        assert!(rows.is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+use super::DEFAULT_CAPACITY_PAGES;
+use crate::buffer::Buffer;
+use crate::cache_trait::{Access, Cache, PageId};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Queue {
+    Recent,
+    Frequent,
+}
+
+struct Entry {
+    buffer: Buffer,
+    queue: Queue,
+    /// when the page entered `Recent` or was last used in `Frequent`, its key in that queue
+    stamp: u64,
+}
+
+/// A page cache that only lets a page into its main LRU list once it has shown it's used more
+/// than once (the 2Q algorithm of Johnson and Shasha).
+///
+/// A page read for the first time goes into a small FIFO queue, and is evicted from there unless
+/// the main list has nothing to give up. Its id is then remembered for a while, and a page that is
+/// read again within that time goes into the main list. So pages that are read once, like those
+/// of a scan, cycle through the FIFO queue without evicting anything from the main list.
+///
+/// Scans don't move pages between the queues at all: their pages always go into the FIFO queue.
+pub struct TwoQueueCache {
+    entries: HashMap<PageId, Entry>,
+    /// the pages read once, oldest first
+    recent: BTreeMap<u64, PageId>,
+    /// the pages read again after being evicted from `recent`, least recently used first
+    frequent: BTreeMap<u64, PageId>,
+    /// the pages recently evicted from `recent`, oldest first
+    ghosts: BTreeMap<u64, PageId>,
+    ghost_stamps: HashMap<PageId, u64>,
+    /// counts the operations, to order the queues
+    clock: u64,
+    capacity: usize,
+    /// `recent` gives up its pages first once it's over this many
+    recent_capacity: usize,
+    ghost_capacity: usize,
+}
+
+impl TwoQueueCache {
+    /// a cache of `capacity_pages` pages, a quarter of which are for pages read once. The ids of
+    /// half as many pages as it holds are remembered, as the paper recommends.
+    pub fn new(capacity_pages: usize) -> TwoQueueCache {
+        assert!(capacity_pages > 0);
+        TwoQueueCache {
+            entries: HashMap::with_capacity(capacity_pages),
+            recent: BTreeMap::new(),
+            frequent: BTreeMap::new(),
+            ghosts: BTreeMap::new(),
+            ghost_stamps: HashMap::new(),
+            clock: 0,
+            capacity: capacity_pages,
+            recent_capacity: (capacity_pages / 4).max(1),
+            ghost_capacity: capacity_pages / 2,
+        }
+    }
+
+    /// the number of pages cached
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// makes room for one more page
+    fn evict(&mut self) {
+        if self.recent.len() > self.recent_capacity || self.frequent.is_empty() {
+            let (_, page) = self.recent.pop_first().unwrap();
+            self.entries.remove(&page);
+            if self.ghost_capacity > 0 {
+                let stamp = self.tick();
+                self.ghosts.insert(stamp, page);
+                self.ghost_stamps.insert(page, stamp);
+                if self.ghosts.len() > self.ghost_capacity {
+                    let (_, forgotten) = self.ghosts.pop_first().unwrap();
+                    self.ghost_stamps.remove(&forgotten);
+                }
+            }
+        } else {
+            let (_, page) = self.frequent.pop_first().unwrap();
+            self.entries.remove(&page);
+        }
+    }
+}
+
+impl Default for TwoQueueCache {
+    fn default() -> Self {
+        TwoQueueCache::new(DEFAULT_CAPACITY_PAGES)
+    }
+}
+
+impl Cache for TwoQueueCache {
+    fn with_capacity(capacity_pages: usize) -> Self {
+        TwoQueueCache::new(capacity_pages)
+    }
+
+    fn get(&mut self, page: PageId, access: Access) -> Option<Buffer> {
+        let stamp = self.clock + 1;
+        let entry = self.entries.get_mut(&page)?;
+        // a page in `recent` stays in line, its first reads are likely close together
+        if access == Access::Point && entry.queue == Queue::Frequent {
+            self.frequent.remove(&entry.stamp);
+            self.frequent.insert(stamp, page);
+            entry.stamp = stamp;
+            self.clock = stamp;
+        }
+        Some(entry.buffer.clone())
+    }
+
+    fn insert(&mut self, page: PageId, buffer: Buffer, access: Access) {
+        if self.entries.contains_key(&page) {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.evict();
+        }
+        let ghost = match access {
+            Access::Point => self.ghost_stamps.remove(&page),
+            Access::Scan => None,
+        };
+        let stamp = self.tick();
+        let queue = match ghost {
+            Some(ghost) => {
+                self.ghosts.remove(&ghost);
+                self.frequent.insert(stamp, page);
+                Queue::Frequent
+            }
+            None => {
+                self.recent.insert(stamp, page);
+                Queue::Recent
+            }
+        };
+        self.entries.insert(
+            page,
+            Entry {
+                buffer,
+                queue,
+                stamp,
+            },
+        );
+    }
+
+    fn invalidate_file(&mut self, file: u64) {
+        let (recent, frequent) = (&mut self.recent, &mut self.frequent);
+        self.entries.retain(|page, entry| {
+            if page.file == file {
+                match entry.queue {
+                    Queue::Recent => recent.remove(&entry.stamp),
+                    Queue::Frequent => frequent.remove(&entry.stamp),
+                };
+            }
+            page.file != file
+        });
+        self.ghosts.retain(|_, page| page.file != file);
+        self.ghost_stamps.retain(|page, _| page.file != file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(page: u64) -> PageId {
+        PageId { file: 0, page }
+    }
+
+    #[test]
+    fn test_scans_dont_evict_frequent_pages() {
+        let mut cache = TwoQueueCache::new(8);
+        // pages 0 and 1 are read, evicted and then read again
+        for i in 0..4 {
+            cache.insert(page(i), Buffer::new(), Access::Point);
+        }
+        for i in 100..106 {
+            cache.insert(page(i), Buffer::new(), Access::Point);
+        }
+        assert!(cache.get(page(0), Access::Point).is_none());
+        cache.insert(page(0), Buffer::new(), Access::Point);
+        cache.insert(page(1), Buffer::new(), Access::Point);
+        assert_eq!(cache.frequent.len(), 2);
+        // a long scan only goes through the pages read once
+        for i in 1000..2000 {
+            if cache.get(page(i), Access::Scan).is_none() {
+                cache.insert(page(i), Buffer::new(), Access::Scan);
+            }
+        }
+        assert!(cache.get(page(0), Access::Point).is_some());
+        assert!(cache.get(page(1), Access::Point).is_some());
+        assert_eq!(cache.len(), 8);
+        assert!(cache.ghosts.len() <= 4);
+        assert_eq!(cache.ghosts.len(), cache.ghost_stamps.len());
+        // rereading a scanned page doesn't make it frequent
+        cache.insert(page(1990), Buffer::new(), Access::Scan);
+        assert_eq!(cache.frequent.len(), 2);
+        cache.invalidate_file(0);
+        assert!(cache.is_empty() && cache.recent.is_empty() && cache.frequent.is_empty());
+        assert!(cache.ghosts.is_empty());
+    }
+}
@@ -1,35 +1,217 @@
-use crate::buffer::Buffer;
-use crate::write_and_read::{Reader, Writer};
+use crate::buffer::{Buffer, PAGE_SIZE};
+use crate::error::Result;
+use crate::write_and_read::{FileClosed, Reader, Writer};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock};
 
-pub trait Cache: Sized + Default {
-    /// at least one of check_cache and add_to_cache should be true
-    fn get_page(
-        &self,
-        file_reader: &mut Reader,
-        page_num: u64,
-        check_cache: bool,
-        add_to_cache: bool,
-    ) -> Buffer;
-    fn write_page(&mut self, file_writer: &mut Writer, page_num: u64, buffer: Buffer);
+/// the pages `PageCache::new` reserves for pinned pages, 4 MiB worth
+pub const DEFAULT_PINNED_PAGES: usize = 1024;
+
+/// a page of a file: the `Reader::id` of the file and the page number in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageId {
+    pub file: u64,
+    pub page: u64,
+}
+
+/// How a page is read. A range scan reads every page of a range once, so a policy shouldn't
+/// treat those reads as a sign the page is hot, or a long scan would push out the internal pages
+/// every lookup goes through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Point,
+    Scan,
+}
+
+/// A page replacement policy: decides which pages are kept and which are evicted.
+/// It sits behind the lock of a `PageCache`, so lookups are free to update its state.
+pub trait Cache: Default + Send + 'static {
+    /// a policy holding at most `capacity_pages` pages
+    fn with_capacity(capacity_pages: usize) -> Self;
+    /// the page if it's cached, which counts as a use of it
+    fn get(&mut self, page: PageId, access: Access) -> Option<Buffer>;
+    /// caches a page that was just read from disk. Two threads can read the same page at once,
+    /// so the page may be cached already.
+    fn insert(&mut self, page: PageId, buffer: Buffer, access: Access);
+    /// forgets every page of `file`, called when the file is deleted
+    fn invalidate_file(&mut self, file: u64);
 }
 
 #[derive(Default)]
 pub struct NoCache;
 
 impl Cache for NoCache {
-    fn get_page(
+    fn with_capacity(_capacity_pages: usize) -> Self {
+        NoCache
+    }
+
+    fn get(&mut self, _page: PageId, _access: Access) -> Option<Buffer> {
+        None
+    }
+
+    fn insert(&mut self, _page: PageId, _buffer: Buffer, _access: Access) {}
+
+    fn invalidate_file(&mut self, _file: u64) {}
+}
+
+/// The pages of the database, shared by every thread using it. Reads from disk happen without
+/// holding the lock of the cache, only looking pages up and adding them does.
+///
+/// Besides the pages the policy manages, part of the cache is reserved for pinned pages, which
+/// stay in memory for as long as their file is open whatever the policy thinks of them.
+pub struct PageCache<A: Cache> {
+    cache: Mutex<A>,
+    pinned: RwLock<HashMap<PageId, Buffer>>,
+    /// the most pages `pinned` holds
+    pinned_capacity: usize,
+    /// the pages read from disk, cached or not
+    disk_reads: AtomicU64,
+}
+
+impl<A: Cache> Default for PageCache<A> {
+    fn default() -> Self {
+        PageCache::new(A::default())
+    }
+}
+
+impl<A: Cache> PageCache<A> {
+    pub fn new(cache: A) -> Self {
+        Self::with_pinned_capacity(cache, DEFAULT_PINNED_PAGES)
+    }
+
+    pub fn with_pinned_capacity(cache: A, pinned_pages: usize) -> Self {
+        PageCache {
+            cache: Mutex::new(cache),
+            pinned: RwLock::new(HashMap::new()),
+            pinned_capacity: pinned_pages,
+            disk_reads: AtomicU64::new(0),
+        }
+    }
+
+    /// a cache holding `capacity_bytes` of pages in all, an eighth of which are reserved for
+    /// pinned pages. Databases sharing the cache share that capacity.
+    pub fn with_capacity_bytes(capacity_bytes: usize) -> Self {
+        let pages = capacity_bytes / PAGE_SIZE;
+        Self::with_pinned_capacity(A::with_capacity((pages - pages / 8).max(1)), pages / 8)
+    }
+
+    /// at least one of check_cache and add_to_cache should be true
+    /// Pages read from disk are checked against their checksum before being added to the cache.
+    pub fn get_page(
         &self,
-        file_reader: &mut Reader,
+        file_reader: &Reader,
         page_num: u64,
-        _check_cache: bool,
-        _add_to_cache: bool,
-    ) -> Buffer {
+        check_cache: bool,
+        add_to_cache: bool,
+    ) -> Result<Buffer> {
+        self.read_page(
+            file_reader,
+            page_num,
+            Access::Point,
+            check_cache,
+            add_to_cache,
+        )
+    }
+
+    fn read_page(
+        &self,
+        file_reader: &Reader,
+        page_num: u64,
+        access: Access,
+        check_cache: bool,
+        add_to_cache: bool,
+    ) -> Result<Buffer> {
+        let page = PageId {
+            file: file_reader.id,
+            page: page_num,
+        };
+        if let Some(buffer) = self.pinned.read().unwrap().get(&page) {
+            return Ok(buffer.clone());
+        }
+        if check_cache {
+            if let Some(buffer) = self.lock().get(page, access) {
+                return Ok(buffer);
+            }
+        }
+        let mut buffer = Buffer::new();
+        file_reader.read_page(&mut buffer, page_num)?;
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        if add_to_cache {
+            self.lock().insert(page, buffer.clone(), access);
+        }
+        Ok(buffer)
+    }
+
+    pub fn write_page(&self, file_writer: &mut Writer, _page_num: u64, mut buffer: Buffer) {
+        file_writer.write_page(&mut buffer);
+    }
+
+    /// forgets the pages of the file `file_reader` reads, once it's deleted
+    pub fn invalidate(&self, file_reader: &Reader) {
+        self.file_closed(file_reader.id);
+    }
+
+    /// reads a page into the reserved part of the cache, where it stays until its file is
+    /// closed. Returns false, without reading it, if there's no room left there.
+    pub fn pin_page(&self, file_reader: &Reader, page_num: u64) -> Result<bool> {
+        let page = PageId {
+            file: file_reader.id,
+            page: page_num,
+        };
+        if self.pinned.read().unwrap().contains_key(&page) {
+            return Ok(true);
+        }
+        if self.pinned_pages() >= self.pinned_capacity {
+            return Ok(false);
+        }
         let mut buffer = Buffer::new();
-        file_reader.read_page(&mut buffer, page_num);
-        buffer
+        file_reader.read_page(&mut buffer, page_num)?;
+        self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        // threads pinning at once can all take the last spot, going over by a page or so
+        self.pinned.write().unwrap().insert(page, buffer);
+        Ok(true)
     }
 
-    fn write_page(&mut self, file_writer: &mut Writer, _page_num: u64, buffer: Buffer) {
-        file_writer.write_page(&buffer);
+    /// gives back the pinned pages of the file `file_reader` reads, leaving it open
+    pub fn unpin_file(&self, file_reader: &Reader) {
+        self.pinned
+            .write()
+            .unwrap()
+            .retain(|page, _| page.file != file_reader.id);
+    }
+
+    pub fn pinned_pages(&self) -> usize {
+        self.pinned.read().unwrap().len()
+    }
+
+    pub fn disk_reads(&self) -> u64 {
+        self.disk_reads.load(Ordering::Relaxed)
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, A> {
+        self.cache.lock().unwrap()
+    }
+}
+
+impl<A: Cache> FileClosed for PageCache<A> {
+    fn file_closed(&self, file_id: u64) {
+        self.lock().invalidate_file(file_id);
+        self.pinned
+            .write()
+            .unwrap()
+            .retain(|page, _| page.file != file_id);
+    }
+}
+
+/// Reads the pages of a range scan. The iterator of a scan outlives the call that created it, so
+/// it holds on to the cache through this instead of naming its policy.
+pub trait ScanPages: Send + Sync {
+    fn scan_page(&self, file_reader: &Reader, page_num: u64) -> Result<Buffer>;
+}
+
+impl<A: Cache> ScanPages for PageCache<A> {
+    fn scan_page(&self, file_reader: &Reader, page_num: u64) -> Result<Buffer> {
+        self.read_page(file_reader, page_num, Access::Scan, true, true)
     }
 }
@@ -0,0 +1,222 @@
+use crate::buffer::{Buffer, KEYS_PER_PAGE, PAGE_SIZE, PAGE_TRAILER_SIZE};
+use crate::error::Result;
+use crate::write_and_read::{Reader, Writer};
+use serde::{Deserialize, Serialize};
+
+/// the bytes of a page that can hold encoded pages, the rest is the checksum trailer
+pub const USABLE_PAGE_SIZE: usize = PAGE_SIZE - PAGE_TRAILER_SIZE;
+
+/// Turns the pairs of one page of the bottom level into bytes and back.
+///
+/// The encoded pages are written one after the other, so a page can end up anywhere in the file
+/// (and span two pages on disk). The offset of every page is written to the `.offsets.btree`
+/// file of the BTree, which `BTreeReader` loads to find them.
+pub trait Codec {
+    /// appends the encoding of `pairs` to `bytes`
+    fn encode(&self, pairs: &[(i64, i64)], bytes: &mut Vec<u8>);
+    /// appends the pairs encoded in `bytes` to `pairs`
+    fn decode(&self, bytes: &[u8], pairs: &mut Vec<(i64, i64)>);
+}
+
+/// the codecs a BTree can be written with. The discriminant is stored in the footer, so a new
+/// codec gets a new variant rather than reusing one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CodecKind {
+    /// pages are written as they are, at fixed offsets
+    #[default]
+    None = 0,
+    FrameOfReference = 1,
+}
+
+impl CodecKind {
+    pub fn codec(self) -> Option<&'static dyn Codec> {
+        match self {
+            CodecKind::None => None,
+            CodecKind::FrameOfReference => Some(&FrameOfReference),
+        }
+    }
+
+    pub fn from_id(id: u32) -> Option<CodecKind> {
+        match id {
+            0 => Some(CodecKind::None),
+            1 => Some(CodecKind::FrameOfReference),
+            _ => None,
+        }
+    }
+}
+
+/// Stores the keys and the values of a page as the difference from their smallest one, using
+/// just enough bits for the largest difference. Dense keys take a few bits each, and so do values
+/// from a small range.
+///
+/// The layout: the number of pairs (u16), then for the keys and then the values the smallest one
+/// (i64) and the number of bits per difference (u8), then the packed differences of the keys
+/// followed by those of the values. Everything is little endian.
+pub struct FrameOfReference;
+
+impl FrameOfReference {
+    fn pack(numbers: impl Iterator<Item = i64> + Clone, bytes: &mut Vec<u8>) {
+        let reference = numbers.clone().min().unwrap_or(0);
+        let largest = numbers
+            .clone()
+            .map(|number| number.wrapping_sub(reference) as u64)
+            .max()
+            .unwrap_or(0);
+        let bits = 64 - largest.leading_zeros();
+        bytes.extend_from_slice(&reference.to_le_bytes());
+        bytes.push(bits as u8);
+        let mut pending: u128 = 0;
+        let mut pending_bits = 0;
+        for number in numbers {
+            pending |= ((number.wrapping_sub(reference) as u64) as u128) << pending_bits;
+            pending_bits += bits;
+            while pending_bits >= 8 {
+                bytes.push(pending as u8);
+                pending >>= 8;
+                pending_bits -= 8;
+            }
+        }
+        if pending_bits > 0 {
+            bytes.push(pending as u8);
+        }
+    }
+
+    /// returns where the packed numbers end
+    fn unpack(bytes: &[u8], count: usize, numbers: &mut Vec<i64>) -> usize {
+        let reference = i64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let bits = bytes[8] as u32;
+        let mask = if bits == 64 {
+            u64::MAX
+        } else {
+            (1 << bits) - 1
+        };
+        let mut offset = 9;
+        let mut pending: u128 = 0;
+        let mut pending_bits = 0;
+        for _ in 0..count {
+            while pending_bits < bits {
+                pending |= (bytes[offset] as u128) << pending_bits;
+                offset += 1;
+                pending_bits += 8;
+            }
+            numbers.push(reference.wrapping_add((pending as u64 & mask) as i64));
+            pending = pending.checked_shr(bits).unwrap_or(0);
+            pending_bits -= bits;
+        }
+        offset
+    }
+}
+
+impl Codec for FrameOfReference {
+    fn encode(&self, pairs: &[(i64, i64)], bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&(pairs.len() as u16).to_le_bytes());
+        Self::pack(pairs.iter().map(|pair| pair.0), bytes);
+        Self::pack(pairs.iter().map(|pair| pair.1), bytes);
+    }
+
+    fn decode(&self, bytes: &[u8], pairs: &mut Vec<(i64, i64)>) {
+        let count = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+        let mut keys = Vec::with_capacity(count);
+        let mut values = Vec::with_capacity(count);
+        let offset = 2 + Self::unpack(&bytes[2..], count, &mut keys);
+        Self::unpack(&bytes[offset..], count, &mut values);
+        pairs.extend(keys.into_iter().zip(values));
+    }
+}
+
+/// collects the bytes of encoded page `page` into `bytes`. `offsets` holds where every encoded
+/// page starts in the usable part of the pages on disk, plus where the last one ends, and
+/// `read_page` reads a page from disk.
+pub fn read_encoded_page(
+    offsets: &[u64],
+    page: usize,
+    mut read_page: impl FnMut(u64) -> Result<Buffer>,
+    bytes: &mut Vec<u8>,
+) -> Result<()> {
+    let end = offsets[page + 1];
+    let mut position = offsets[page];
+    bytes.clear();
+    while position < end {
+        let in_page = (position % USABLE_PAGE_SIZE as u64) as usize;
+        let buffer = read_page(position / USABLE_PAGE_SIZE as u64)?;
+        let length = ((end - position) as usize).min(USABLE_PAGE_SIZE - in_page);
+        bytes.extend_from_slice(&buffer.as_slice_u8()[in_page..in_page + length]);
+        position += length as u64;
+    }
+    Ok(())
+}
+
+/// writes where every encoded page starts, and where the last one ends, to a file of their own.
+pub fn write_offsets(file_name: String, offsets: &[u64]) {
+    let mut writer = Writer::new(file_name);
+    for chunk in offsets.chunks(KEYS_PER_PAGE) {
+        let mut buffer = Buffer::new();
+        for (i, offset) in chunk.iter().enumerate() {
+            buffer.set_key(i, *offset as i64);
+        }
+        writer.write_page(&mut buffer);
+    }
+}
+
+/// reads the offsets of `pages` encoded pages written by `write_offsets`.
+pub fn read_offsets(file_name: &str, pages: u64) -> Result<Vec<u64>> {
+    let count = pages as usize + 1;
+    let reader = Reader::open(file_name)?;
+    let mut buffer = Buffer::new();
+    let mut offsets = Vec::with_capacity(count);
+    for page in 0..count.div_ceil(KEYS_PER_PAGE) {
+        reader.read_page(&mut buffer, page as u64)?;
+        let in_page = (count - offsets.len()).min(KEYS_PER_PAGE);
+        offsets.extend((0..in_page).map(|i| buffer.key(i) as u64));
+    }
+    Ok(offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(pairs: &[(i64, i64)]) -> usize {
+        let mut bytes = Vec::new();
+        FrameOfReference.encode(pairs, &mut bytes);
+        let mut decoded = Vec::new();
+        FrameOfReference.decode(&bytes, &mut decoded);
+        assert_eq!(decoded, pairs);
+        bytes.len()
+    }
+
+    #[test]
+    fn test_frame_of_reference() {
+        // dense keys and small values take a byte or two a pair
+        let pairs: Vec<_> = (0..255).map(|i| (1_000_000 + i, i % 16)).collect();
+        assert!(round_trip(&pairs) < 2 + 2 * 9 + 255 * 2);
+        let pairs: Vec<_> = (0..255).map(|i| (i, 7)).collect();
+        assert_eq!(round_trip(&pairs), 2 + 9 + 255 + 9);
+        round_trip(&[(i64::MIN + 1, i64::MIN), (0, 0), (i64::MAX - 1, i64::MAX)]);
+        round_trip(&[(5, -5)]);
+        round_trip(&[]);
+    }
+
+    #[test]
+    fn test_read_encoded_page() {
+        let mut page = Buffer::new_0();
+        for (i, byte) in page.as_mut_slice_u8().iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let offsets = [0, USABLE_PAGE_SIZE as u64 - 3, USABLE_PAGE_SIZE as u64 + 5];
+        let mut bytes = Vec::new();
+        let mut pages_read = Vec::new();
+        let read_page = |number| {
+            pages_read.push(number);
+            Ok(page.clone())
+        };
+        read_encoded_page(&offsets, 1, read_page, &mut bytes).unwrap();
+        assert_eq!(pages_read, [0, 1]);
+        assert_eq!(bytes.len(), 8);
+        assert_eq!(
+            bytes[..3],
+            page.as_slice_u8()[USABLE_PAGE_SIZE - 3..USABLE_PAGE_SIZE]
+        );
+        assert_eq!(bytes[3..], page.as_slice_u8()[..5]);
+    }
+}
@@ -1,101 +1,303 @@
-use crate::avl_tree::{NodeIter, ScanIter};
-use crate::buffer::{Buffer, PAGE_SIZE_I64, TOMBSTONE};
+use crate::buffer::{Buffer, PAGE_SIZE, PAIRS_PER_PAGE, TOMBSTONE};
+use crate::cache_trait::ScanPages;
+use crate::codec::{read_encoded_page, Codec};
+use crate::compressed_page::CompressedPage;
+use crate::error::Result;
+use crate::options::PageFormat;
 use crate::write_and_read::Reader;
+use itertools::Itertools;
+use std::ops::Range;
+use std::sync::Arc;
 
-pub const PAGE_SIZE_AS_PAIR: u64 = (PAGE_SIZE_I64 / 2) as u64;
+pub const PAGE_SIZE_AS_PAIR: u64 = PAIRS_PER_PAGE as u64;
+/// iterates over the pairs of the bottom level of a BTree, a page at a time.
+/// A page that can't be read, or fails its checksum, can't be skipped without losing data, so the
+/// iteration ends with its error.
 pub struct ReaderIterator {
     reader: Reader,
     buffer: Buffer,
-    pub(crate) index: u64,
+    page_format: PageFormat,
+    /// the pairs of the page being read, decoded from `buffer`
+    pairs: Vec<(i64, i64)>,
+    index: usize,
+    /// the next page to read
+    page: u64,
+    /// the number of pages of pairs in the file
+    end_page: u64,
     upper_bound: i64,
     lower_bound: i64,
+    /// the codec and the offsets of the pages, if they were encoded
+    encoded: Option<(&'static dyn Codec, Arc<[u64]>)>,
+    /// the bytes of the encoded page being read
+    bytes: Vec<u8>,
+    /// the cache to read through, pages are read straight from the file without one
+    pages: Option<Arc<dyn ScanPages>>,
 }
 
 impl ReaderIterator {
+    /// iterates over a file made up only of pages of pairs.
     pub(crate) fn new(file_name: String) -> ReaderIterator {
         let reader = Reader::new(file_name.as_str());
-        let buffer = Buffer::new();
-        ReaderIterator {
-            reader,
-            buffer,
-            index: 0,
-            upper_bound: TOMBSTONE,
-            lower_bound: i64::MIN,
-        }
+        let end_page = reader.file_size() / PAGE_SIZE as u64;
+        Self::with_reader(reader, TOMBSTONE, i64::MIN, 0, end_page, PageFormat::Plain)
     }
 
+    /// iterates over the bottom level read by `reader`, sharing its open file, so it keeps
+    /// working after the BTree has been compacted away.
     pub(crate) fn new_with_upper_bound(
-        file_name: String,
+        reader: Reader,
         upper_bound: i64,
         lower_bound: i64,
-        index: u64,
-        buffer: Buffer,
+        page: u64,
+        end_page: u64,
+        page_format: PageFormat,
+    ) -> ReaderIterator {
+        Self::with_reader(
+            reader,
+            upper_bound,
+            lower_bound,
+            page,
+            end_page,
+            page_format,
+        )
+    }
+
+    fn with_reader(
+        reader: Reader,
+        upper_bound: i64,
+        lower_bound: i64,
+        page: u64,
+        end_page: u64,
+        page_format: PageFormat,
     ) -> ReaderIterator {
-        let reader = Reader::new(file_name.as_str());
         ReaderIterator {
             reader,
-            buffer,
-            index,
+            buffer: Buffer::new(),
+            page_format,
+            pairs: Vec::with_capacity(PAIRS_PER_PAGE),
+            index: 0,
+            page,
+            end_page,
             upper_bound,
             lower_bound,
+            encoded: None,
+            bytes: Vec::new(),
+            pages: None,
+        }
+    }
+
+    /// reads pages of pairs that were encoded with `codec` and are found through `offsets`.
+    pub(crate) fn decoding(mut self, codec: &'static dyn Codec, offsets: Arc<[u64]>) -> Self {
+        self.encoded = Some((codec, offsets));
+        self
+    }
+
+    /// reads the pages through `pages`, as the reads of a range scan.
+    pub(crate) fn scanning(mut self, pages: Arc<dyn ScanPages>) -> Self {
+        self.pages = Some(pages);
+        self
+    }
+
+    fn read_page(
+        reader: &Reader,
+        pages: &Option<Arc<dyn ScanPages>>,
+        buffer: &mut Buffer,
+        page: u64,
+    ) -> Result<()> {
+        match pages {
+            Some(pages) => *buffer = pages.scan_page(reader, page)?,
+            None => reader.read_page(buffer, page)?,
         }
+        Ok(())
     }
-    fn next(&mut self) -> Option<(i64, i64)> {
-        if self.index % (PAGE_SIZE_AS_PAIR) == 0 {
-            if self.index == self.reader.file_size() / 16 as u64 {
+
+    fn read_next_page(&mut self) -> Result<()> {
+        self.pairs.clear();
+        if let Some((codec, offsets)) = &self.encoded {
+            // encoded pages follow each other, so the page on disk where one ends is usually the
+            // one the next one starts in
+            let mut last_read = None;
+            let (reader, pages, buffer) = (&self.reader, &self.pages, &mut self.buffer);
+            let read_page = |stored| {
+                if last_read != Some(stored) {
+                    Self::read_page(reader, pages, buffer, stored)?;
+                    last_read = Some(stored);
+                }
+                Ok(buffer.clone())
+            };
+            read_encoded_page(offsets, self.page as usize, read_page, &mut self.bytes)?;
+            codec.decode(&self.bytes, &mut self.pairs);
+            self.page += 1;
+            self.index = self.pairs.partition_point(|pair| pair.0 < self.lower_bound);
+            return Ok(());
+        }
+        Self::read_page(&self.reader, &self.pages, &mut self.buffer, self.page)?;
+        self.page += 1;
+        match self.page_format {
+            PageFormat::Plain => self.pairs.extend(
+                (0..PAIRS_PER_PAGE)
+                    .map(|i| self.buffer.pair(i))
+                    .take_while(|pair| pair.0 != TOMBSTONE),
+            ),
+            PageFormat::Compressed => self
+                .pairs
+                .extend(CompressedPage::new(&self.buffer, true).iter()),
+        }
+        // only the first page can start below the lower bound
+        self.index = self.pairs.partition_point(|pair| pair.0 < self.lower_bound);
+        Ok(())
+    }
+}
+
+impl Iterator for ReaderIterator {
+    type Item = Result<(i64, i64)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index == self.pairs.len() {
+            if self.page == self.end_page {
                 return None;
             }
-            self.reader
-                .read_page(&mut self.buffer, self.index / (PAGE_SIZE_AS_PAIR));
-        }
-        let pair = self.buffer.as_slice_pair()[self.index as usize % PAGE_SIZE_AS_PAIR as usize];
-        if pair.0 == TOMBSTONE {
-            debug_assert!(pair.1 == TOMBSTONE);
-            return None;
+            if let Err(error) = self.read_next_page() {
+                self.page = self.end_page;
+                self.pairs.clear();
+                self.index = 0;
+                return Some(Err(error));
+            }
         }
+        let pair = self.pairs[self.index];
         if pair.0 > self.upper_bound {
             return None;
         }
-        if pair.0 < self.lower_bound {
-            self.index = self
-                .buffer
-                .as_slice_pair()
-                .binary_search(&(self.lower_bound, 0))
-                .unwrap_or_else(|x| x) as u64;
-            return self.next();
-        }
-        let result = Some(pair);
         self.index += 1;
-        result
+        Some(Ok(pair))
     }
 }
 
 pub enum ScanIterator<'a> {
-    Memtable(ScanIter<'a>),
+    Memtable(Box<dyn Iterator<Item = (i64, i64)> + 'a>),
     LevelN(ReaderIterator),
 }
 
 impl<'a> Iterator for ScanIterator<'a> {
-    type Item = (i64, i64);
+    type Item = Result<(i64, i64)>;
     fn next(&mut self) -> Option<Self::Item> {
         match self {
-            ScanIterator::Memtable(iter) => iter.next(),
+            ScanIterator::Memtable(iter) => iter.next().map(Ok),
             ScanIterator::LevelN(iter) => iter.next(),
         }
     }
 }
 
-pub enum LevelIterator {
-    Memtable(NodeIter),
+pub enum LevelIterator<'a> {
+    Memtable(Box<dyn Iterator<Item = (i64, i64)> + 'a>),
     LevelN(ReaderIterator),
 }
 
-impl Iterator for LevelIterator {
-    type Item = (i64, i64);
+impl<'a> Iterator for LevelIterator<'a> {
+    type Item = Result<(i64, i64)>;
     fn next(&mut self) -> Option<Self::Item> {
         match self {
-            LevelIterator::Memtable(iter) => iter.next(),
+            LevelIterator::Memtable(iter) => iter.next().map(Ok),
             LevelIterator::LevelN(iter) => iter.next(),
         }
     }
 }
+
+/// merges sorted iterators into one sorted iterator with a single entry per key.
+/// The iterators must be ordered from newest to oldest: when several of them contain the same key
+/// the value from the newest one wins (this matters for updates and deletes).
+/// An error goes before every pair, so it comes out as soon as an iterator runs into it.
+pub fn merge_newest_first<I: Iterator<Item = Result<(i64, i64)>>>(
+    iterators: Vec<I>,
+) -> impl Iterator<Item = Result<(i64, i64)>> {
+    iterators
+        .into_iter()
+        .enumerate()
+        .map(|(age, iter)| iter.map(move |item| item.map(|(key, value)| (key, age, value))))
+        .kmerge_by(|item1, item2| match (item1, item2) {
+            (Ok(item1), Ok(item2)) => (item1.0, item1.1) < (item2.0, item2.1),
+            (item1, _) => item1.is_err(),
+        })
+        .dedup_by(
+            |item1, item2| matches!((item1, item2), (Ok(item1), Ok(item2)) if item1.0 == item2.0),
+        )
+        .map(|item| item.map(|(key, _, value)| (key, value)))
+}
+
+/// picks which sorted runs a universal compaction should merge. `run_sizes` are ordered from the
+/// newest run to the oldest and the result is a range of runs to merge into one.
+///
+/// The checks are tried in order:
+/// 1. space amplification: if the newer runs are too big compared to the oldest run
+///    (which holds most of the data) everything is merged.
+/// 2. size ratio: starting with the newest run, keep adding the next older run while it isn't much
+///    bigger than everything picked so far.
+/// 3. run count: if there are still too many runs the newest ones are merged.
+pub fn pick_universal_compaction(
+    run_sizes: &[u64],
+    size_ratio_percent: u64,
+    max_size_amplification_percent: u64,
+    max_runs: usize,
+) -> Option<Range<usize>> {
+    if run_sizes.len() < 2 {
+        return None;
+    }
+    let (oldest, newer) = run_sizes.split_last().unwrap();
+    if newer.iter().sum::<u64>() * 100 > oldest * max_size_amplification_percent {
+        return Some(0..run_sizes.len());
+    }
+    let mut picked_size = run_sizes[0];
+    let mut end = 1;
+    while end < run_sizes.len() && run_sizes[end] * 100 <= picked_size * (100 + size_ratio_percent)
+    {
+        picked_size += run_sizes[end];
+        end += 1;
+    }
+    if end >= 2 {
+        return Some(0..end);
+    }
+    if run_sizes.len() > max_runs {
+        return Some(0..run_sizes.len() - max_runs + 1);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_newest_first() {
+        let newer = vec![(1, 10), (3, 30)].into_iter().map(Ok);
+        let older = vec![(1, 1), (2, 2), (3, 3)].into_iter().map(Ok);
+        let merged: Vec<_> = merge_newest_first(vec![newer, older])
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(merged, vec![(1, 10), (2, 2), (3, 30)]);
+    }
+
+    #[test]
+    fn test_pick_universal_compaction() {
+        // similar sizes are merged together
+        assert_eq!(
+            pick_universal_compaction(&[10, 10, 100], 1, 200, 8),
+            Some(0..2)
+        );
+        assert_eq!(
+            pick_universal_compaction(&[10, 10, 20, 100], 1, 200, 8),
+            Some(0..3)
+        );
+        // the newer runs are too large compared to the oldest
+        assert_eq!(
+            pick_universal_compaction(&[10, 40, 20], 1, 200, 8),
+            Some(0..3)
+        );
+        // nothing similar and few enough runs
+        assert_eq!(pick_universal_compaction(&[10, 30, 100], 1, 200, 8), None);
+        // too many runs
+        assert_eq!(
+            pick_universal_compaction(&[1, 3, 9, 27, 81, 243], 1, 200, 4),
+            Some(0..3)
+        );
+        assert_eq!(pick_universal_compaction(&[10], 1, 200, 8), None);
+    }
+}
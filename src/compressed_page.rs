@@ -0,0 +1,287 @@
+use crate::buffer::{Buffer, PAGE_SIZE, PAGE_TRAILER_SIZE};
+
+/// every this many entries the key is stored in full rather than as a difference
+pub const RESTART_INTERVAL: usize = 16;
+const HEADER_SIZE: usize = 16;
+const END: usize = PAGE_SIZE - PAGE_TRAILER_SIZE;
+/// the longest a varint of a u64 can be
+const MAX_VARINT_SIZE: usize = 10;
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+fn write_varint(mut value: u64, bytes: &mut [u8]) -> usize {
+    let mut length = 0;
+    while value >= 0x80 {
+        bytes[length] = value as u8 | 0x80;
+        value >>= 7;
+        length += 1;
+    }
+    bytes[length] = value as u8;
+    length + 1
+}
+
+fn read_varint(bytes: &[u8], offset: &mut usize) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*offset];
+        *offset += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// Fills up one compressed page at a time, for a `Level0Writer` (with values) or a `LevelWriter`.
+///
+/// Compressed pages store each key as the difference from the key before it, and every number as
+/// a varint, so dense increasing keys take a byte or two instead of eight.
+/// Every `RESTART_INTERVAL` entries the key is stored in full (a restart point) so a lookup can
+/// binary search the restart points and only decode the entries after one of them.
+///
+/// The layout of a page, every number little endian:
+/// - a header of `HEADER_SIZE` bytes: the number of entries (u16), the number of restart points
+///   (u16), 4 unused bytes and, for pages of the upper levels, the page number of the child of
+///   the first key (u64)
+/// - the entries. A restart point is the zigzag varint of the key, any other entry the varint of
+///   the difference from the previous key. On the bottom level it's followed by the zigzag varint
+///   of the value.
+/// - the offset of every restart point (u16), ending right before the checksum trailer
+pub struct CompressedPageBuilder {
+    has_values: bool,
+    entries: Vec<u8>,
+    restarts: Vec<u16>,
+    count: usize,
+    last_key: i64,
+    /// see the page header, set by the `LevelWriter` before adding the first key of a page
+    pub first_child: u64,
+}
+
+impl CompressedPageBuilder {
+    pub fn new(has_values: bool) -> CompressedPageBuilder {
+        CompressedPageBuilder {
+            has_values,
+            entries: Vec::with_capacity(PAGE_SIZE),
+            restarts: Vec::new(),
+            count: 0,
+            last_key: 0,
+            first_child: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn last_key(&self) -> i64 {
+        self.last_key
+    }
+
+    /// adds an entry to the page, the value is ignored for the upper levels.
+    /// Returns false if it doesn't fit, then the page should be written out and the entry added
+    /// to the next one.
+    pub fn add(&mut self, key: i64, value: i64) -> bool {
+        let restart = self.count.is_multiple_of(RESTART_INTERVAL);
+        let mut entry = [0; 2 * MAX_VARINT_SIZE];
+        let mut length = if restart {
+            write_varint(zigzag(key), &mut entry)
+        } else {
+            debug_assert!(key >= self.last_key);
+            write_varint(key.wrapping_sub(self.last_key) as u64, &mut entry)
+        };
+        if self.has_values {
+            length += write_varint(zigzag(value), &mut entry[length..]);
+        }
+        let restarts = self.restarts.len() + restart as usize;
+        if HEADER_SIZE + self.entries.len() + length + restarts * 2 > END {
+            debug_assert!(!self.is_empty());
+            return false;
+        }
+        if restart {
+            self.restarts
+                .push((HEADER_SIZE + self.entries.len()) as u16);
+        }
+        self.entries.extend_from_slice(&entry[..length]);
+        self.count += 1;
+        self.last_key = key;
+        true
+    }
+
+    /// writes the page into `buffer` and starts over with an empty page.
+    pub fn write_to(&mut self, buffer: &mut Buffer) {
+        let bytes = buffer.as_mut_slice_u8();
+        bytes[..END].fill(0);
+        bytes[0..2].copy_from_slice(&(self.count as u16).to_le_bytes());
+        bytes[2..4].copy_from_slice(&(self.restarts.len() as u16).to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.first_child.to_le_bytes());
+        bytes[HEADER_SIZE..HEADER_SIZE + self.entries.len()].copy_from_slice(&self.entries);
+        let restarts_start = END - self.restarts.len() * 2;
+        for (i, restart) in self.restarts.iter().enumerate() {
+            bytes[restarts_start + i * 2..][..2].copy_from_slice(&restart.to_le_bytes());
+        }
+        self.entries.clear();
+        self.restarts.clear();
+        self.count = 0;
+    }
+}
+
+/// reads a page written by a `CompressedPageBuilder`.
+pub struct CompressedPage<'a> {
+    bytes: &'a [u8],
+    has_values: bool,
+    count: usize,
+    restarts: usize,
+}
+
+impl<'a> CompressedPage<'a> {
+    pub fn new(buffer: &'a Buffer, has_values: bool) -> CompressedPage<'a> {
+        let bytes = buffer.as_slice_u8();
+        CompressedPage {
+            bytes,
+            has_values,
+            count: u16::from_le_bytes([bytes[0], bytes[1]]) as usize,
+            restarts: u16::from_le_bytes([bytes[2], bytes[3]]) as usize,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn first_child(&self) -> u64 {
+        u64::from_le_bytes(self.bytes[8..16].try_into().unwrap())
+    }
+
+    fn restart_offset(&self, restart: usize) -> usize {
+        let offset = END - (self.restarts - restart) * 2;
+        u16::from_le_bytes([self.bytes[offset], self.bytes[offset + 1]]) as usize
+    }
+
+    /// decodes the entries starting at a restart point
+    fn entries_from(&self, restart: usize) -> impl Iterator<Item = (i64, i64)> + '_ {
+        let mut offset = self.restart_offset(restart);
+        let mut key = 0;
+        (restart * RESTART_INTERVAL..self.count).map(move |i| {
+            let encoded = read_varint(self.bytes, &mut offset);
+            key = if i % RESTART_INTERVAL == 0 {
+                unzigzag(encoded)
+            } else {
+                key.wrapping_add(encoded as i64)
+            };
+            let value = if self.has_values {
+                unzigzag(read_varint(self.bytes, &mut offset))
+            } else {
+                0
+            };
+            (key, value)
+        })
+    }
+
+    /// every entry of the page, the values are 0 for the upper levels
+    pub fn iter(&self) -> impl Iterator<Item = (i64, i64)> + '_ {
+        (self.count > 0)
+            .then(|| self.entries_from(0))
+            .into_iter()
+            .flatten()
+    }
+
+    /// the index of the first entry with a key at least `key`, and that entry if there is one.
+    pub fn lower_bound(&self, key: i64) -> (usize, Option<(i64, i64)>) {
+        if self.count == 0 {
+            return (0, None);
+        }
+        // the last restart point whose key is smaller than `key`
+        let mut low = 0;
+        let mut high = self.restarts;
+        while low < high {
+            let middle = (low + high) / 2;
+            let mut offset = self.restart_offset(middle);
+            if unzigzag(read_varint(self.bytes, &mut offset)) < key {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        let restart = low.saturating_sub(1);
+        let mut index = restart * RESTART_INTERVAL;
+        for entry in self.entries_from(restart) {
+            if entry.0 >= key {
+                return (index, Some(entry));
+            }
+            index += 1;
+        }
+        (index, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, -1, 63, -64, 64, 1 << 40, i64::MAX, i64::MIN] {
+            let mut bytes = [0; MAX_VARINT_SIZE];
+            let length = write_varint(zigzag(value), &mut bytes);
+            let mut offset = 0;
+            assert_eq!(unzigzag(read_varint(&bytes, &mut offset)), value);
+            assert_eq!(offset, length);
+        }
+    }
+
+    #[test]
+    fn test_dense_keys() {
+        let mut builder = CompressedPageBuilder::new(true);
+        let mut count = 0;
+        while builder.add(1_000_000 + count * 2, count) {
+            count += 1;
+        }
+        // a pair of small deltas takes 2 or 3 bytes instead of 16
+        assert!(count > 1000);
+        let mut buffer = Buffer::new();
+        builder.write_to(&mut buffer);
+        assert!(builder.is_empty());
+        let page = CompressedPage::new(&buffer, true);
+        assert_eq!(page.len(), count as usize);
+        let pairs: Vec<_> = page.iter().collect();
+        assert_eq!(
+            pairs,
+            (0..count)
+                .map(|i| (1_000_000 + i * 2, i))
+                .collect::<Vec<_>>()
+        );
+        for i in 0..count {
+            let key = 1_000_000 + i * 2;
+            assert_eq!(page.lower_bound(key), (i as usize, Some((key, i))));
+            let next = (i as usize + 1, pairs.get(i as usize + 1).copied());
+            assert_eq!(page.lower_bound(key + 1), next);
+        }
+        assert_eq!(page.lower_bound(0), (0, Some((1_000_000, 0))));
+    }
+
+    #[test]
+    fn test_keys_without_values() {
+        let keys = [i64::MIN + 1, -5, 0, 0, 7, 1 << 50, i64::MAX - 1];
+        let mut builder = CompressedPageBuilder::new(false);
+        builder.first_child = 42;
+        for key in keys {
+            assert!(builder.add(key, 0));
+        }
+        assert_eq!(builder.last_key(), i64::MAX - 1);
+        let mut buffer = Buffer::new();
+        builder.write_to(&mut buffer);
+        let page = CompressedPage::new(&buffer, false);
+        assert_eq!(page.first_child(), 42);
+        assert_eq!(page.iter().map(|entry| entry.0).collect::<Vec<_>>(), keys);
+        assert_eq!(page.lower_bound(1).0, 4);
+        assert_eq!(page.lower_bound(i64::MAX).0, keys.len());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::mem_table::MemTable;
+use crate::skip_list_levels::{average_links_size, random_height, HEAD, MAX_HEIGHT, NIL};
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+
+/// a node is a run of words in the arena: its key, its value and then its links
+const KEY: usize = 0;
+const VALUE: usize = 1;
+const NEXT: usize = 2;
+const HEAD_WORDS: usize = NEXT + MAX_HEIGHT;
+/// the bytes a node takes up on average
+pub const AVERAGE_CONCURRENT_NODE_SIZE: usize = NEXT * 8 + average_links_size(8);
+
+/// A skip list that any number of threads can insert into and read from at once, without locks.
+///
+/// The nodes are carved out of one arena of atomic words allocated up front, and link to each
+/// other by offset. A node is filled in before it is linked in with a compare and swap, one level
+/// at a time from the bottom up, so readers only ever see whole nodes. Nodes are never removed,
+/// which is what keeps the compare and swaps simple: an insert that loses a race finds the new
+/// predecessors and tries again.
+///
+/// Two threads inserting the same new key at once both allocate a node, and the loser's node is
+/// left unlinked in the arena. It still counts towards the size, which is fine for a memtable.
+pub struct ConcurrentSkipList {
+    arena: Box<[AtomicU64]>,
+    /// the first free word of the arena
+    allocated: AtomicUsize,
+    /// the number of levels in use
+    height: AtomicUsize,
+}
+
+impl ConcurrentSkipList {
+    /// an empty skip list whose nodes take up to `capacity` bytes
+    pub fn new(capacity: usize) -> ConcurrentSkipList {
+        let words = HEAD_WORDS + capacity / 8;
+        let arena = (0..words).map(|_| AtomicU64::new(0)).collect();
+        ConcurrentSkipList {
+            arena,
+            allocated: AtomicUsize::new(HEAD_WORDS),
+            height: AtomicUsize::new(1),
+        }
+    }
+
+    fn key(&self, node: usize) -> i64 {
+        self.arena[node + KEY].load(Relaxed) as i64
+    }
+
+    fn value(&self, node: usize) -> i64 {
+        self.arena[node + VALUE].load(Acquire) as i64
+    }
+
+    fn next(&self, node: usize, level: usize) -> usize {
+        self.arena[node + NEXT + level].load(Acquire) as usize
+    }
+
+    /// reserves the words of a node linked into `height` levels, if there's room for them
+    fn allocate(&self, height: usize) -> Option<usize> {
+        self.allocated
+            .fetch_update(Relaxed, Relaxed, |allocated| {
+                Some(allocated + NEXT + height).filter(|end| *end <= self.arena.len())
+            })
+            .ok()
+    }
+
+    /// for every level, the last node with a key smaller than `key` and the node after it
+    fn find(&self, key: i64) -> ([usize; MAX_HEIGHT], [usize; MAX_HEIGHT]) {
+        let mut predecessors = [HEAD; MAX_HEIGHT];
+        let mut successors = [NIL; MAX_HEIGHT];
+        let mut node = HEAD;
+        for level in (0..self.height.load(Acquire)).rev() {
+            let mut next = self.next(node, level);
+            while next != NIL && self.key(next) < key {
+                node = next;
+                next = self.next(node, level);
+            }
+            predecessors[level] = node;
+            successors[level] = next;
+        }
+        (predecessors, successors)
+    }
+
+    /// the first node with a key at least `key`
+    fn lower_bound(&self, key: i64) -> usize {
+        self.find(key).1[0]
+    }
+
+    /// inserts or overwrites `key` from any thread. Returns false if there's no room left.
+    pub fn insert_shared(&self, key: i64, value: i64) -> bool {
+        let (mut predecessors, mut successors) = self.find(key);
+        if successors[0] != NIL && self.key(successors[0]) == key {
+            self.arena[successors[0] + VALUE].store(value as u64, Release);
+            return true;
+        }
+        let height = random_height();
+        let Some(node) = self.allocate(height) else {
+            return false;
+        };
+        self.arena[node + KEY].store(key as u64, Relaxed);
+        self.arena[node + VALUE].store(value as u64, Relaxed);
+        self.height.fetch_max(height, AcqRel);
+        for level in 0..height {
+            loop {
+                let successor = successors[level];
+                if level == 0 && successor != NIL && self.key(successor) == key {
+                    // another thread inserted the key first, this node stays unlinked
+                    self.arena[successor + VALUE].store(value as u64, Release);
+                    return true;
+                }
+                // the node isn't reachable on this level yet, so its link can be set plainly
+                self.arena[node + NEXT + level].store(successor as u64, Relaxed);
+                let linked = self.arena[predecessors[level] + NEXT + level].compare_exchange(
+                    successor as u64,
+                    node as u64,
+                    AcqRel,
+                    Acquire,
+                );
+                if linked.is_ok() {
+                    break;
+                }
+                // a node was linked in right here, start over from the new predecessors.
+                // Only the bottom level can hold a node with the same key, since the loser of
+                // that race never gets linked into the levels above.
+                (predecessors, successors) = self.find(key);
+            }
+        }
+        true
+    }
+}
+
+impl MemTable for ConcurrentSkipList {
+    fn insert(&mut self, key: i64, value: i64) -> bool {
+        self.insert_shared(key, value)
+    }
+
+    fn insert_concurrently(&self, key: i64, value: i64) -> Option<bool> {
+        Some(self.insert_shared(key, value))
+    }
+
+    fn get(&self, key: i64) -> Option<i64> {
+        let node = self.lower_bound(key);
+        (node != NIL && self.key(node) == key).then(|| self.value(node))
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        (self.allocated.load(Relaxed) - HEAD_WORDS) * 8
+    }
+
+    fn scan(
+        &self,
+        lower_bound: i64,
+        upper_bound: i64,
+    ) -> Box<dyn Iterator<Item = (i64, i64)> + '_> {
+        let mut node = self.lower_bound(lower_bound);
+        Box::new(std::iter::from_fn(move || {
+            if node == NIL || self.key(node) > upper_bound {
+                return None;
+            }
+            let pair = (self.key(node), self.value(node));
+            node = self.next(node, 0);
+            Some(pair)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_inserts() {
+        let skip_list = ConcurrentSkipList::new(100_000 * AVERAGE_CONCURRENT_NODE_SIZE);
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let skip_list = &skip_list;
+                scope.spawn(move || {
+                    // every thread writes every key, interleaved differently
+                    for i in 0..20_000 {
+                        let key = (i * 7919 + thread * 5000) % 20_000;
+                        assert!(skip_list.insert_shared(key, key * 2));
+                    }
+                });
+            }
+        });
+        assert_eq!(skip_list.get(-1), None);
+        for key in 0..20_000 {
+            assert_eq!(skip_list.get(key), Some(key * 2));
+        }
+        assert!(skip_list
+            .scan(i64::MIN, i64::MAX)
+            .eq((0..20_000).map(|key| (key, key * 2))));
+        assert!(skip_list.size_in_bytes() >= 20_000 * (NEXT + 1) * 8);
+    }
+
+    #[test]
+    fn test_full() {
+        let skip_list = ConcurrentSkipList::new(100 * (NEXT + 1) * 8);
+        let mut key = 0;
+        while skip_list.insert_shared(key, key) {
+            key += 1;
+        }
+        assert!(key > 0 && key <= 100);
+        assert!(skip_list.size_in_bytes() <= 100 * (NEXT + 1) * 8);
+        // overwrites always fit
+        assert!(skip_list.insert_shared(0, 7));
+        assert_eq!(skip_list.get(0), Some(7));
+        assert_eq!(skip_list.scan(0, key).count(), key as usize);
+    }
+}
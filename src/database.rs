@@ -1,136 +1,572 @@
-use crate::avl_tree::MemoryTable;
-use crate::b_tree::{BTreeReader, BTreeWriter};
-use crate::cache_trait::Cache;
-use crate::compaction::{LevelIterator, ScanIterator};
-use itertools::Itertools;
+use crate::b_tree::{rename_files, BTreeReader, BTreeWriter, Level0Writer, LevelStats};
+use crate::buffer::{DELETED, TOMBSTONE};
+use crate::cache::{RowCache, RowLookup};
+use crate::cache_trait::{Cache, NoCache, PageCache};
+use crate::compaction::{
+    merge_newest_first, pick_universal_compaction, LevelIterator, ReaderIterator, ScanIterator,
+};
+use crate::error::{Error, Result};
+use crate::ingest::{check_pair, IngestError, SortedFile};
+use crate::mem_table::MemTable;
+use crate::options::{CompactionStyle, DatabaseOptions};
+use crate::wal::{log_number, Wal};
+use crate::write_buffer::WriteBufferManager;
 use serde::{Deserialize, Serialize};
 use std::fs;
 use std::fs::File;
-use std::ops::Index;
+use std::mem;
+use std::ops::Range;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct DatabaseMetadata {
     mem_table_file_name: Option<String>,
     b_trees_file_names_and_levels: Vec<Option<(String, usize)>>,
-    max_mem_table_size: u64,
+    /// the tombstone bookkeeping of each BTree in `b_trees_file_names_and_levels`
+    level_stats: Vec<Option<LevelStats>>,
+    options: DatabaseOptions,
+    #[serde(default)]
+    next_file_number: u64,
 }
 
-struct Database<A: Cache> {
-    mem_table: MemoryTable,
+/// a memtable, shared between the versions that include it
+struct SharedMemTable {
+    table: RwLock<Box<dyn MemTable>>,
+    /// set once a flush starts writing the memtable out. Writers that don't take the writers
+    /// lock must leave it alone from then on.
+    sealed: AtomicBool,
+    /// the log of the pairs in the memtable, when `DatabaseOptions::wal` is set
+    wal: Option<Wal>,
+    write_buffer: Option<Arc<WriteBufferManager>>,
+    /// the bytes counted against `write_buffer` so far
+    charged: AtomicUsize,
+}
+
+impl SharedMemTable {
+    /// waits for the inserts that don't take the writers lock to finish and keeps out new ones
+    fn seal(&self) {
+        let _table = self.table.write().unwrap();
+        self.sealed.store(true, Relaxed);
+    }
+
+    /// inserts a pair with `insert` and adds it to the log, unless `insert` returns false. It's
+    /// only in the log once it's committed. With a log, inserts are serialized with the appends,
+    /// so concurrent writes of a key are logged in the order they went into the memtable.
+    fn insert_logged(
+        self: &Arc<Self>,
+        key: i64,
+        value: i64,
+        insert: impl FnOnce() -> bool,
+    ) -> Result<Option<Logged>> {
+        let group = match &self.wal {
+            Some(wal) => wal.append(key, value, insert)?,
+            None => insert().then_some(0),
+        };
+        Ok(group.map(|group| Logged {
+            mem_table: self.clone(),
+            group,
+        }))
+    }
+
+    /// counts what the memtable has grown by since the last call against the write buffer
+    /// manager. Returns whether the manager is over its limit.
+    fn charge(&self) -> bool {
+        let Some(write_buffer) = &self.write_buffer else {
+            return false;
+        };
+        let size = self.table.read().unwrap().size_in_bytes();
+        let charged = self.charged.fetch_max(size, Relaxed);
+        if size > charged {
+            write_buffer.reserve(size - charged);
+        }
+        write_buffer.over_limit()
+    }
+}
+
+impl Drop for SharedMemTable {
+    /// the memory is only given back once no version holds on to the memtable anymore
+    fn drop(&mut self) {
+        if let Some(write_buffer) = &self.write_buffer {
+            write_buffer.release(*self.charged.get_mut());
+        }
+    }
+}
+
+/// a write that is in the memtable and still has to be committed to its log
+struct Logged {
+    mem_table: Arc<SharedMemTable>,
+    group: u64,
+}
+
+impl Logged {
+    /// waits for the write to be in the log. Writers should let go of the writers lock first,
+    /// so the writes coming in while the log is synced can join the next group.
+    fn commit(self) -> Result<()> {
+        if let Some(wal) = &self.mem_table.wal {
+            wal.commit(self.group)?;
+        }
+        Ok(())
+    }
+}
+
+/// What readers see of the database: the memtable and the BTrees under it.
+/// Writers never change the BTrees of a version, they publish a new version instead, so a reader
+/// holding on to one keeps a consistent view of the levels for as long as it reads (the files of
+/// BTrees compacted away in the meantime stay readable through their open handles).
+struct Version {
+    mem_table: Arc<SharedMemTable>,
+    b_trees: Vec<Option<Arc<BTreeReader>>>,
+}
+
+/// The part of the database only writers touch. Every write holds its lock, so writes are
+/// serialized while reads go on against the current `Version`.
+struct Levels<A: Cache> {
+    /// when the oldest delete that is still in the memtable was issued
+    mem_table_oldest_tombstone: Option<u64>,
     /// second parameter is the number of levels
-    b_trees: Vec<Option<BTreeReader>>,
-    max_mem_table_size: usize,
+    /// With universal compaction these are the sorted runs from newest to oldest, none are empty.
+    b_trees: Vec<Option<Arc<BTreeReader>>>,
+    options: DatabaseOptions,
+    /// used to give every sorted run written by universal compaction a unique name
+    next_file_number: u64,
     path: String,
-    cache: A,
+    cache: Arc<PageCache<A>>,
+    write_buffer: Option<Arc<WriteBufferManager>>,
+    /// the BTrees replaced by the last change, whose files go once readers moved on to the new ones
+    obsolete: Vec<Arc<BTreeReader>>,
+}
+
+/// What several databases of a process can share: the page cache, whose capacity is then split
+/// between them by its policy, and a write buffer manager capping their memtables together.
+pub struct SharedResources<A: Cache> {
+    pub cache: Arc<PageCache<A>>,
+    pub write_buffer: Option<Arc<WriteBufferManager>>,
 }
 
+impl<A: Cache> Default for SharedResources<A> {
+    fn default() -> Self {
+        SharedResources {
+            cache: Arc::new(PageCache::default()),
+            write_buffer: None,
+        }
+    }
+}
+
+impl<A: Cache> Clone for SharedResources<A> {
+    fn clone(&self) -> Self {
+        SharedResources {
+            cache: self.cache.clone(),
+            write_buffer: self.write_buffer.clone(),
+        }
+    }
+}
+
+/// A database that can be shared between threads: any number of them can read while one at a
+/// time writes, and reads never wait for a flush or a compaction.
+struct Database<A: Cache> {
+    /// the version readers use, replaced as a whole whenever a writer changes the levels
+    version: RwLock<Arc<Version>>,
+    levels: Mutex<Levels<A>>,
+    cache: Arc<PageCache<A>>,
+    /// the results of recent lookups, when `DatabaseOptions::row_cache_rows` is set
+    rows: Option<RowCache>,
+}
+
+assert_impl_all!(Database<NoCache>: Send, Sync);
+
 impl<A: Cache> Database<A> {
-    fn create(path: String, max_mem_table_size: usize) -> Self {
-        let mem_table = MemoryTable::new(max_mem_table_size as usize);
-        let b_trees = Vec::new();
-        Database {
-            mem_table,
-            b_trees,
-            max_mem_table_size,
+    fn create(path: String, max_mem_table_bytes: usize) -> Self {
+        Self::create_with_options(
             path,
-            cache: A::default(),
-        }
+            DatabaseOptions {
+                max_mem_table_bytes,
+                ..DatabaseOptions::default()
+            },
+        )
+    }
+
+    fn create_with_options(path: String, options: DatabaseOptions) -> Self {
+        Self::create_with_resources(path, options, SharedResources::default())
+    }
+
+    fn create_with_resources(
+        path: String,
+        options: DatabaseOptions,
+        resources: SharedResources<A>,
+    ) -> Self {
+        let levels = Levels {
+            mem_table_oldest_tombstone: None,
+            b_trees: Vec::new(),
+            options,
+            next_file_number: 0,
+            path,
+            cache: resources.cache,
+            write_buffer: resources.write_buffer,
+            obsolete: Vec::new(),
+        };
+        levels.write_metadata().unwrap();
+        Self::with_levels(levels).unwrap()
+    }
+
+    fn open(path: String) -> Result<Self> {
+        Self::open_with_resources(path, SharedResources::default())
     }
-    fn open(path: String) -> Self {
-        let file = File::open(path.clone() + "/metadata.json").unwrap();
-        let metadata: DatabaseMetadata = serde_json::from_reader(file).unwrap();
+
+    fn open_with_resources(path: String, resources: SharedResources<A>) -> Result<Self> {
+        let metadata_file_name = path.clone() + "/metadata.json";
+        let file = File::open(metadata_file_name.as_str())?;
+        let metadata: DatabaseMetadata = serde_json::from_reader(file)
+            .map_err(|error| Error::invalid_format(&metadata_file_name, error.to_string()))?;
+        let cache = resources.cache;
         let mut b_trees = Vec::new();
-        for i in metadata.b_trees_file_names_and_levels {
+        for (i, stats) in metadata
+            .b_trees_file_names_and_levels
+            .into_iter()
+            .zip(metadata.level_stats)
+        {
             if let Some((file_name, level)) = i {
-                b_trees.push(Some(BTreeReader::new(file_name, level)));
+                let mut b_tree = BTreeReader::open_with_index(file_name, metadata.options.index)?;
+                if b_tree.levels() != level {
+                    return Err(Error::invalid_format(
+                        b_tree.file_name_prefix(),
+                        "height doesn't match the metadata",
+                    ));
+                }
+                b_tree.stats = stats.unwrap_or(b_tree.stats);
+                track_pages(&b_tree, &cache, &metadata.options)?;
+                b_trees.push(Some(Arc::new(b_tree)));
             } else {
                 b_trees.push(None);
             }
         }
-        let mem_table = MemoryTable::new(metadata.max_mem_table_size as usize);
-        Database {
-            mem_table,
+        // the logs of the memtables that weren't flushed, oldest first
+        let mut log_numbers: Vec<u64> = fs::read_dir(&path)?
+            .filter_map(|entry| log_number(entry.ok()?.file_name().to_str()?))
+            .collect();
+        log_numbers.sort();
+        let log_file_names: Vec<String> = log_numbers
+            .iter()
+            .map(|number| log_file_name(&path, *number))
+            .collect();
+        let next_file_number = match log_numbers.last() {
+            Some(last) => metadata.next_file_number.max(last + 1),
+            None => metadata.next_file_number,
+        };
+        let database = Self::with_levels(Levels {
+            mem_table_oldest_tombstone: None,
             b_trees,
-            max_mem_table_size: metadata.max_mem_table_size as usize,
+            options: metadata.options,
+            next_file_number,
             path,
-            cache: A::default(),
+            cache,
+            write_buffer: resources.write_buffer,
+            obsolete: Vec::new(),
+        })?;
+        database.replay_logs(log_file_names)?;
+        Ok(database)
+    }
+
+    /// puts the pairs of the logs back in, then deletes the logs once the pairs are in the log
+    /// of the new memtable (or flushed).
+    fn replay_logs(&self, log_file_names: Vec<String>) -> Result<()> {
+        let mut levels = self.levels.lock().unwrap();
+        let mut last = None;
+        for file_name in log_file_names.iter() {
+            for (key, value) in Wal::read(file_name)? {
+                last = Some(self.put(&mut levels, key, value)?);
+                if value == DELETED {
+                    levels.mem_table_oldest_tombstone.get_or_insert_with(now);
+                }
+            }
+        }
+        drop(levels);
+        if let Some(logged) = last {
+            let mem_table = logged.mem_table.clone();
+            logged.commit()?;
+            self.charge(&mem_table);
         }
+        for file_name in log_file_names {
+            fs::remove_file(file_name)?;
+        }
+        Ok(())
+    }
+
+    fn with_levels(mut levels: Levels<A>) -> Result<Self> {
+        let version = Version {
+            mem_table: levels.new_mem_table()?,
+            b_trees: levels.b_trees.clone(),
+        };
+        Ok(Database {
+            version: RwLock::new(Arc::new(version)),
+            cache: levels.cache.clone(),
+            rows: levels.options.row_cache_rows.map(RowCache::new),
+            levels: Mutex::new(levels),
+        })
     }
 
-    fn get(&mut self, key: i64) -> Option<i64> {
-        if let Some(value) = self.mem_table.get(key) {
-            return Some(value);
+    /// the version to read from. The lock is only held long enough to clone it.
+    fn current(&self) -> Arc<Version> {
+        self.version.read().unwrap().clone()
+    }
+
+    /// makes the levels as they are now visible to readers, along with `mem_table`.
+    fn publish(&self, levels: &Levels<A>, mem_table: Arc<SharedMemTable>) {
+        let version = Version {
+            mem_table,
+            b_trees: levels.b_trees.clone(),
+        };
+        *self.version.write().unwrap() = Arc::new(version);
+    }
+
+    fn get(&self, key: i64) -> Result<Option<i64>> {
+        let Some(rows) = &self.rows else {
+            return self.get_uncached(key);
+        };
+        match rows.get(key) {
+            RowLookup::Cached(value) => Ok(value),
+            RowLookup::Missing { writes } => {
+                let value = self.get_uncached(key)?;
+                rows.fill(key, value, writes);
+                Ok(value)
+            }
         }
-        for b_tree in self.b_trees.iter_mut() {
-            if let Some(b_tree) = b_tree {
-                if let Some(value) = b_tree.get_item(key, &mut self.cache) {
-                    return Some(value);
+    }
+
+    fn get_uncached(&self, key: i64) -> Result<Option<i64>> {
+        let version = self.current();
+        if let Some(value) = version.mem_table.table.read().unwrap().get(key) {
+            return Ok(Some(value).filter(|value| *value != DELETED));
+        }
+        for b_tree in version.b_trees.iter().flatten() {
+            if b_tree.may_contain(key) {
+                if let Some(value) = b_tree.get_item(key, &*self.cache)? {
+                    return Ok(Some(value).filter(|value| *value != DELETED));
                 }
             }
         }
-        None
+        Ok(None)
     }
 
-    fn insert_iter_at_level(&mut self, level: usize, mut iter: Vec<LevelIterator>) -> usize {
-        if level >= self.b_trees.len() {
-            debug_assert!(level == self.b_trees.len());
-            self.b_trees.push(None);
+    /// fails without writing anything if `key` is `TOMBSTONE` or `value` is `DELETED`
+    fn insert(&self, key: i64, value: i64) -> Result<()> {
+        check_write(key, value)?;
+        let logged = match self.insert_concurrently(key, value)? {
+            Some(logged) => logged,
+            None => self.put(&mut self.levels.lock().unwrap(), key, value)?,
+        };
+        self.written(key);
+        let mem_table = logged.mem_table.clone();
+        logged.commit()?;
+        self.charge(&mem_table);
+        Ok(())
+    }
+
+    /// inserts without taking the writers lock, if the memtable allows it and has room.
+    fn insert_concurrently(&self, key: i64, value: i64) -> Result<Option<Logged>> {
+        let mem_table = self.current().mem_table.clone();
+        let table = mem_table.table.read().unwrap();
+        // a flush seals the memtable under the write lock, so until then it can't miss the
+        // insert, and it only deletes the log after that
+        if mem_table.sealed.load(Relaxed) {
+            return Ok(None);
         }
-        if let Some(b_tree) = self.b_trees[level].take() {
-            iter.push(b_tree.into_level_iter());
-            self.insert_iter_at_level(level + 1, iter)
-        } else {
-            let file_name = self.path.clone() + "/b_tree_" + level.to_string().as_str();
-            let mut b_tree_writer = BTreeWriter::new(file_name.clone());
-            let mut iter = iter
-                .into_iter()
-                .kmerge()
-                .dedup_by(|item1, item2| item1.0 == item2.0);
-            for item in iter {
-                b_tree_writer.add_item(item, &mut self.cache);
-            }
-            let btree_level = b_tree_writer.finish(&mut self.cache);
-            self.b_trees[level] = Some(BTreeReader::new(file_name, btree_level));
-            level
-        }
-    }
-    fn insert(&mut self, key: i64, value: i64) {
-        if !self.mem_table.insert(key, value) {
-            let old_meme_table = std::mem::replace(
-                &mut self.mem_table,
-                MemoryTable::new(self.max_mem_table_size),
-            );
-            let last_level = self.insert_iter_at_level(0, vec![old_meme_table.into_level_iter()]);
-            for file in fs::read_dir(self.path.clone()).unwrap() {
-                let file = file.unwrap();
-                if get_level_number(file.file_name().to_str().unwrap()) < last_level {
-                    fs::remove_file(file.path()).unwrap();
-                }
+        mem_table.insert_logged(key, value, || {
+            table.insert_concurrently(key, value) == Some(true)
+        })
+    }
+
+    /// deletes `key` by inserting a tombstone for it. Fails if `key` is `TOMBSTONE`.
+    pub fn delete(&self, key: i64) -> Result<()> {
+        check_write(key, 0)?;
+        let mut levels = self.levels.lock().unwrap();
+        let logged = self.put(&mut levels, key, DELETED)?;
+        levels.mem_table_oldest_tombstone.get_or_insert_with(now);
+        drop(levels);
+        self.written(key);
+        let mem_table = logged.mem_table.clone();
+        logged.commit()?;
+        self.charge(&mem_table);
+        Ok(())
+    }
+
+    /// keeps the row cache in line with a write that lookups can see now
+    fn written(&self, key: i64) {
+        if let Some(rows) = &self.rows {
+            rows.write(key);
+        }
+    }
+
+    /// counts the growth of `mem_table` against the write buffer manager, and flushes it if the
+    /// manager is over its limit (unless another write flushed it first).
+    fn charge(&self, mem_table: &Arc<SharedMemTable>) {
+        if mem_table.charge() {
+            let mut levels = self.levels.lock().unwrap();
+            if Arc::ptr_eq(&self.current().mem_table, mem_table) {
+                // the write this is for went in already. If the flush fails it's tried again
+                // once the memtable is full, and that write gets the error.
+                let _ = self.flush_locked(&mut levels);
+            }
+        }
+    }
+
+    fn put(&self, levels: &mut Levels<A>, key: i64, value: i64) -> Result<Logged> {
+        loop {
+            let mem_table = self.current().mem_table.clone();
+            // the table is locked before the log, like inserts that don't take the writers lock
+            let mut table = mem_table.table.write().unwrap();
+            if let Some(logged) =
+                mem_table.insert_logged(key, value, || table.insert(key, value))?
+            {
+                return Ok(logged);
+            }
+            drop(table);
+            self.flush_locked(levels)?;
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut levels = self.levels.lock().unwrap();
+        self.flush_locked(&mut levels)
+    }
+
+    /// writes the memtable out and starts a new one. If a BTree it's merged with can't be read,
+    /// the levels are left as they were and the memtable stays in place.
+    fn flush_locked(&self, levels: &mut Levels<A>) -> Result<()> {
+        // the next memtable and its log are set up first, so there's nothing to undo if that fails
+        let new_mem_table = levels.new_mem_table()?;
+        let mem_table = self.current().mem_table.clone();
+        mem_table.seal();
+        // readers keep using the full memtable until the BTrees holding its pairs are published,
+        // so its pairs are streamed straight from it. Sealed, it only takes more readers.
+        let table = mem_table.table.read().unwrap();
+        let oldest_tombstone = levels.mem_table_oldest_tombstone;
+        let written = levels.undo_on_error(|levels| {
+            levels.write_mem_table(
+                LevelIterator::Memtable(table.scan(i64::MIN, i64::MAX)),
+                oldest_tombstone,
+            )
+        });
+        drop(table);
+        if let Err(error) = written {
+            if let Some(wal) = &new_mem_table.wal {
+                // it's empty, so replaying it would do nothing anyway
+                let _ = wal.delete();
             }
-            self.insert(key, value);
+            return Err(error);
         }
+        levels.mem_table_oldest_tombstone = None;
+        self.publish(levels, new_mem_table);
+        // the pairs are on disk now, along with the metadata pointing to them
+        if let Some(wal) = &mem_table.wal {
+            wal.delete()?;
+        }
+        levels.delete_obsolete()?;
+        self.compact_tombstones_locked(levels)
+    }
+
+    /// adds a BTree built by a `SortedFileBuilder` to the database, newer than everything in it.
+    /// The files are checked to be sorted and are then moved into the database directory
+    /// (so they must be on the same file system) under a new sequence number.
+    ///
+    /// To avoid rewriting the data, the files are installed as the deepest level that is empty and
+    /// that doesn't have a level above it with overlapping keys (which would wrongly shadow the
+    /// new data). If there isn't one the files are merged into level 0 like a memtable flush.
+    /// The memtable sits above every level, so it's flushed first if it holds keys of the file.
+    pub fn ingest_files(&self, file: SortedFile) -> std::result::Result<(), IngestError> {
+        let mut levels = self.levels.lock().unwrap();
+        let ingested = levels.open_ingested(&file)?;
+        let (min_key, max_key) = ingested.key_range();
+        let mem_table = self.current().mem_table.clone();
+        let overlaps = mem_table
+            .table
+            .read()
+            .unwrap()
+            .scan(min_key, max_key)
+            .next()
+            .is_some();
+        if overlaps {
+            self.flush_locked(&mut levels)
+                .map_err(IngestError::Invalid)?;
+        }
+        levels.undo_on_error(|levels| levels.ingest_files(file, ingested))?;
+        self.publish(&levels, self.current().mem_table.clone());
+        self.clear_rows();
+        levels.delete_obsolete().map_err(IngestError::Invalid)
+    }
+
+    /// loads a large amount of pairs straight into the bottom level, without going through the
+    /// memtable. The pairs don't have to be sorted: they're sorted in runs of
+    /// `bulk_load_run_size` pairs which are written to disk and then merged.
+    /// If a key appears more than once the last pair wins.
+    ///
+    /// The loaded pairs are treated as older than everything already in the database, so they
+    /// never overwrite an existing key. This is meant for filling a new database.
+    ///
+    /// Fails without loading anything if a pair uses a reserved key or value.
+    pub fn bulk_load(&self, pairs: impl IntoIterator<Item = (i64, i64)>) -> Result<()> {
+        let mut levels = self.levels.lock().unwrap();
+        levels.undo_on_error(|levels| levels.bulk_load(pairs))?;
+        self.publish(&levels, self.current().mem_table.clone());
+        self.clear_rows();
+        levels.delete_obsolete()
+    }
+
+    /// forgets the cached rows after keys changed without being written one at a time
+    fn clear_rows(&self) {
+        if let Some(rows) = &self.rows {
+            rows.clear();
+        }
+    }
+
+    /// makes sure deletes get physically persisted: flushes the memtable if it holds a tombstone
+    /// older than `tombstone_deadline`, and compacts the shallowest level whose tombstones are too
+    /// dense or too old all the way into the bottom level, where they are dropped along with the
+    /// data they cover.
+    pub fn compact_tombstones(&self) -> Result<()> {
+        let mut levels = self.levels.lock().unwrap();
+        self.compact_tombstones_locked(&mut levels)
+    }
+
+    fn compact_tombstones_locked(&self, levels: &mut Levels<A>) -> Result<()> {
+        if levels.expired(levels.mem_table_oldest_tombstone) {
+            // flushing calls back into this function once the tombstones are on disk
+            return self.flush_locked(levels);
+        }
+        if levels.undo_on_error(Levels::compact_tombstones)? {
+            self.publish(levels, self.current().mem_table.clone());
+        }
+        levels.delete_obsolete()
     }
 
     fn range(
-        &mut self,
+        &self,
         lower_bound: i64,
         upper_bound: i64,
-    ) -> impl Iterator<Item = (i64, i64)> + use<'_, A> {
-        let mut iterators = Vec::new();
-        for b_tree in self.b_trees.iter_mut() {
-            if let Some(b_tree) = b_tree {
-                iterators.push(b_tree.range(lower_bound, upper_bound, &mut self.cache));
+    ) -> Result<impl Iterator<Item = Result<(i64, i64)>> + use<'_, A>> {
+        let version = self.current();
+        // the pairs are copied out so the memtable isn't locked for as long as the scan goes on
+        let mem_table_pairs: Vec<(i64, i64)> = version
+            .mem_table
+            .table
+            .read()
+            .unwrap()
+            .scan(lower_bound, upper_bound)
+            .collect();
+        // newest first, so the memtable goes before the levels
+        let mut iterators = vec![ScanIterator::Memtable(Box::new(
+            mem_table_pairs.into_iter(),
+        ))];
+        // levels that can't hold any key of the range don't get an iterator
+        for b_tree in version.b_trees.iter().flatten() {
+            if b_tree.overlaps(lower_bound, upper_bound) {
+                iterators.push(b_tree.range(lower_bound, upper_bound, &self.cache)?);
             }
         }
-        iterators.push(ScanIterator::Memtable(
-            self.mem_table.scan(lower_bound, upper_bound),
-        ));
-        iterators
-            .into_iter()
-            .kmerge()
-            .dedup_by(|item1, item2| item1.0 == item2.0)
+        Ok(merge_newest_first(iterators).filter(|item| !matches!(item, Ok((_, DELETED)))))
     }
-
     // fn range(&mut self, lower_bound: i64, upper_bound: i64) -> impl Iterator<Item = (i64, i64)>{
     //     let mut iterators = Vec::new();
     //     for b_tree in self.b_trees.iter_mut(){
@@ -156,28 +592,596 @@ impl<A: Cache> Database<A> {
     // }
 }
 
-fn get_level_number(file_name: &str) -> usize {
-    let mut level = 0;
-    match file_name.strip_prefix("b_tree_") {
-        None => usize::MAX,
-        Some(name) => {
-            let first_non_digit = name
-                .chars()
-                .position(|c| !c.is_digit(10))
-                .unwrap_or(name.len());
-            name[..first_non_digit].parse().unwrap_or(usize::MAX)
+impl<A: Cache> Levels<A> {
+    /// runs `change`, and if it fails puts the BTrees back the way they were: the ones it wrote
+    /// are deleted and the ones it was merging stay. The BTrees a change replaces are only
+    /// deleted by `delete_obsolete` once it's published, so nothing is lost.
+    fn undo_on_error<T, E>(
+        &mut self,
+        change: impl FnOnce(&mut Self) -> std::result::Result<T, E>,
+    ) -> std::result::Result<T, E> {
+        let before = self.b_trees.clone();
+        let result = change(self);
+        if result.is_err() {
+            for b_tree in self.b_trees.iter().flatten() {
+                if !before.iter().flatten().any(|old| Arc::ptr_eq(old, b_tree)) {
+                    // the error of the change is the one to report, a file left behind is
+                    // only wasted space
+                    let _ = b_tree.delete();
+                }
+            }
+            self.b_trees = before;
+            self.obsolete.clear();
+        }
+        result
+    }
+
+    /// deletes the files of the BTrees the last change replaced. Readers still holding on to
+    /// them keep reading through their open handles.
+    fn delete_obsolete(&mut self) -> Result<()> {
+        for b_tree in mem::take(&mut self.obsolete) {
+            b_tree.delete()?;
+        }
+        Ok(())
+    }
+
+    fn new_mem_table(&mut self) -> Result<Arc<SharedMemTable>> {
+        let mem_table = self
+            .options
+            .mem_table
+            .create(self.options.max_mem_table_bytes);
+        let wal = self
+            .options
+            .wal
+            .map(|sync_mode| {
+                let file_name = log_file_name(&self.path, self.next_file_number);
+                self.next_file_number += 1;
+                Wal::create(file_name, sync_mode)
+            })
+            .transpose()?;
+        Ok(Arc::new(SharedMemTable {
+            table: RwLock::new(mem_table),
+            sealed: AtomicBool::new(false),
+            wal,
+            write_buffer: self.write_buffer.clone(),
+            charged: AtomicUsize::new(0),
+        }))
+    }
+
+    /// writes the level layout to `metadata.json`. The file is replaced atomically so a crash
+    /// leaves either the old or the new layout behind.
+    fn write_metadata(&self) -> Result<()> {
+        let metadata = DatabaseMetadata {
+            mem_table_file_name: None,
+            b_trees_file_names_and_levels: self
+                .b_trees
+                .iter()
+                .map(|b_tree| {
+                    b_tree
+                        .as_ref()
+                        .map(|b_tree| (b_tree.file_name_prefix().to_string(), b_tree.levels()))
+                })
+                .collect(),
+            level_stats: self
+                .b_trees
+                .iter()
+                .map(|b_tree| b_tree.as_ref().map(|b_tree| b_tree.stats))
+                .collect(),
+            options: self.options.clone(),
+            next_file_number: self.next_file_number,
+        };
+        let temp_file_name = self.path.clone() + "/metadata.json.tmp";
+        serde_json::to_writer(File::create(temp_file_name.as_str())?, &metadata)
+            .map_err(std::io::Error::from)?;
+        fs::rename(temp_file_name, self.path.clone() + "/metadata.json")?;
+        Ok(())
+    }
+    /// every file written gets a new number, so a file is never replaced by another with the
+    /// same name.
+    fn level_file_name(&self, level: usize, number: u64) -> String {
+        format!("{}/b_tree_{}_{}", self.path, level, number)
+    }
+    fn next_level_file_name(&mut self, level: usize) -> String {
+        let file_name = self.level_file_name(level, self.next_file_number);
+        self.next_file_number += 1;
+        file_name
+    }
+    /// writes the merge of `iter` (ordered from newest to oldest) as a BTree named `file_name`
+    /// that will be at `level`, and returns its stats.
+    fn write_b_tree(
+        &mut self,
+        file_name: &str,
+        level: usize,
+        iter: Vec<LevelIterator<'_>>,
+        drop_tombstones: bool,
+        oldest_tombstone: Option<u64>,
+    ) -> Result<LevelStats> {
+        let mut b_tree_writer = BTreeWriter::with_options(
+            file_name.to_string(),
+            self.options.index,
+            self.options.page_format,
+            self.options.codec_for_level(level),
+        );
+        for item in merge_newest_first(iter) {
+            let item = match item {
+                Ok(item) => item,
+                Err(error) => {
+                    // the error reading is the one to report, a file left behind is only
+                    // wasted space
+                    let _ = b_tree_writer.abandon();
+                    return Err(error);
+                }
+            };
+            if drop_tombstones && item.1 == DELETED {
+                continue;
+            }
+            b_tree_writer.add_item(item, &*self.cache);
+        }
+        b_tree_writer.finish(&*self.cache);
+        Ok(LevelStats {
+            entries: b_tree_writer.entries,
+            tombstones: b_tree_writer.tombstones,
+            oldest_tombstone: oldest_tombstone.filter(|_| b_tree_writer.tombstones > 0),
+        })
+    }
+    /// opens a BTree that was just written. If it can't be tracked by the cache it's deleted.
+    fn open_b_tree(
+        &self,
+        file_name: String,
+        stats: LevelStats,
+    ) -> Result<Option<Arc<BTreeReader>>> {
+        let mut b_tree = BTreeReader::open_with_index(file_name, self.options.index)?;
+        if stats.entries == 0 {
+            // every entry was a tombstone that got dropped, so there is nothing to keep
+            b_tree.delete()?;
+            return Ok(None);
+        }
+        b_tree.stats = stats;
+        if let Err(error) = track_pages(&b_tree, &self.cache, &self.options) {
+            let _ = b_tree.delete();
+            return Err(error);
+        }
+        Ok(Some(Arc::new(b_tree)))
+    }
+    /// merges `iter` into `level`, or further down if `level` is taken. The BTrees merged along
+    /// the way are added to `merged`.
+    fn insert_iter_at_level(
+        &mut self,
+        level: usize,
+        mut iter: Vec<LevelIterator<'_>>,
+        oldest_tombstone: Option<u64>,
+        merged: &mut Vec<Arc<BTreeReader>>,
+    ) -> Result<()> {
+        if level >= self.b_trees.len() {
+            debug_assert!(level == self.b_trees.len());
+            self.b_trees.push(None);
+        }
+        if let Some(b_tree) = self.b_trees[level].take() {
+            let oldest_tombstone = oldest_of(oldest_tombstone, b_tree.stats.oldest_tombstone);
+            iter.push(b_tree.level_iter());
+            merged.push(b_tree);
+            self.insert_iter_at_level(level + 1, iter, oldest_tombstone, merged)
+        } else {
+            let file_name = self.next_level_file_name(level);
+            // tombstones only need to be kept while there is older data below them to shadow
+            let drop_tombstones = self.b_trees[level + 1..].iter().all(Option::is_none);
+            let stats =
+                self.write_b_tree(&file_name, level, iter, drop_tombstones, oldest_tombstone)?;
+            self.b_trees[level] = self.open_b_tree(file_name, stats)?;
+            Ok(())
+        }
+    }
+    /// writes the pairs of a full memtable to disk, compacting the levels as needed.
+    fn write_mem_table(
+        &mut self,
+        iter: LevelIterator<'_>,
+        oldest_tombstone: Option<u64>,
+    ) -> Result<()> {
+        match self.options.compaction_style {
+            CompactionStyle::Leveled => self.insert_at_level_0(iter, oldest_tombstone)?,
+            CompactionStyle::Universal {
+                size_ratio_percent,
+                max_size_amplification_percent,
+                max_runs,
+            } => {
+                self.add_run(iter, oldest_tombstone)?;
+                let run_sizes: Vec<u64> = self
+                    .b_trees
+                    .iter()
+                    .flatten()
+                    .map(|run| run.stats.entries)
+                    .collect();
+                if let Some(runs) = pick_universal_compaction(
+                    &run_sizes,
+                    size_ratio_percent,
+                    max_size_amplification_percent,
+                    max_runs,
+                ) {
+                    self.merge_runs(runs)?;
+                }
+            }
+        }
+        self.write_metadata()
+    }
+
+    /// merges `iter` into level 0, cascading down through the full levels.
+    fn insert_at_level_0(
+        &mut self,
+        iter: LevelIterator<'_>,
+        oldest_tombstone: Option<u64>,
+    ) -> Result<()> {
+        let mut merged = Vec::new();
+        self.insert_iter_at_level(0, vec![iter], oldest_tombstone, &mut merged)?;
+        self.obsolete.extend(merged);
+        Ok(())
+    }
+    /// opens the BTree of `file` where it is and checks that its keys are sorted.
+    fn open_ingested(&self, file: &SortedFile) -> std::result::Result<BTreeReader, IngestError> {
+        let ingested =
+            BTreeReader::open_with_index(file.file_name_prefix.clone(), self.options.index)
+                .map_err(IngestError::Invalid)?;
+        let mut previous_key = None;
+        for pair in ingested.level_iter() {
+            let pair = pair.map_err(IngestError::Invalid)?;
+            check_pair(&file.file_name_prefix, previous_key, pair)?;
+            previous_key = Some(pair.0);
+        }
+        if previous_key.is_none() {
+            return Err(IngestError::Empty {
+                file_name: file.file_name_prefix.clone(),
+            });
+        }
+        Ok(ingested)
+    }
+    /// installs `ingested`, the BTree of `file` as opened by `open_ingested`.
+    fn ingest_files(
+        &mut self,
+        file: SortedFile,
+        ingested: BTreeReader,
+    ) -> std::result::Result<(), IngestError> {
+        let levels = ingested.levels();
+        let (min_key, max_key) = ingested.key_range();
+        let mut first_overlap = self.b_trees.len();
+        for (level, b_tree) in self.b_trees.iter().enumerate() {
+            if let Some(b_tree) = b_tree {
+                let (level_min, level_max) = b_tree.key_range();
+                if level_min <= max_key && min_key <= level_max {
+                    first_overlap = level;
+                    break;
+                }
+            }
+        }
+        let sequence_number = self.next_file_number;
+        self.next_file_number += 1;
+        match self.options.compaction_style {
+            CompactionStyle::Leveled => {
+                let level = if first_overlap == self.b_trees.len() {
+                    // nothing overlaps so it can go anywhere, a new bottom level included
+                    Some(
+                        (0..=self.b_trees.len())
+                            .rev()
+                            .find(|level| self.b_trees.get(*level).is_none_or(Option::is_none))
+                            .unwrap(),
+                    )
+                } else {
+                    (0..first_overlap)
+                        .rev()
+                        .find(|level| self.b_trees[*level].is_none())
+                };
+                if let Some(level) = level {
+                    let file_name = self.level_file_name(level, sequence_number);
+                    let ingested = self
+                        .move_ingested(&file.file_name_prefix, file_name, levels)
+                        .map_err(IngestError::Invalid)?;
+                    if level == self.b_trees.len() {
+                        self.b_trees.push(None);
+                    }
+                    self.b_trees[level] = Some(ingested);
+                } else {
+                    self.insert_at_level_0(ingested.level_iter(), None)
+                        .map_err(IngestError::Invalid)?;
+                    self.obsolete.push(Arc::new(ingested));
+                }
+            }
+            CompactionStyle::Universal { .. } => {
+                // runs are ordered by age, so it goes just before the newest run it overlaps
+                let file_name = self.path.clone() + "/run_" + sequence_number.to_string().as_str();
+                let ingested = self
+                    .move_ingested(&file.file_name_prefix, file_name, levels)
+                    .map_err(IngestError::Invalid)?;
+                self.b_trees.insert(first_overlap, Some(ingested));
+            }
+        }
+        self.write_metadata().map_err(IngestError::Invalid)
+    }
+    /// moves the files of an ingested BTree from `prefix` to `file_name` and opens them there.
+    /// If that fails they are moved back, so the caller still has them.
+    fn move_ingested(
+        &self,
+        prefix: &str,
+        file_name: String,
+        levels: usize,
+    ) -> Result<Arc<BTreeReader>> {
+        rename_files(prefix, &file_name, levels)?;
+        let opened = BTreeReader::open_with_index(file_name.clone(), self.options.index).and_then(
+            |b_tree| {
+                track_pages(&b_tree, &self.cache, &self.options)?;
+                Ok(Arc::new(b_tree))
+            },
+        );
+        if opened.is_err() {
+            let _ = rename_files(&file_name, prefix, levels);
+        }
+        opened
+    }
+    fn next_run_file_name(&mut self) -> String {
+        let file_name = self.path.clone() + "/run_" + self.next_file_number.to_string().as_str();
+        self.next_file_number += 1;
+        file_name
+    }
+    /// writes `iter` as the newest sorted run.
+    fn add_run(&mut self, iter: LevelIterator<'_>, oldest_tombstone: Option<u64>) -> Result<()> {
+        let file_name = self.next_run_file_name();
+        let drop_tombstones = self.b_trees.is_empty();
+        let stats =
+            self.write_b_tree(&file_name, 0, vec![iter], drop_tombstones, oldest_tombstone)?;
+        if let Some(run) = self.open_b_tree(file_name, stats)? {
+            self.b_trees.insert(0, Some(run));
+        }
+        Ok(())
+    }
+    /// replaces the sorted runs in `runs` with a single run holding their merge. If the merge
+    /// fails the runs are put back.
+    fn merge_runs(&mut self, runs: Range<usize>) -> Result<()> {
+        let drop_tombstones = runs.end == self.b_trees.len();
+        let old_runs: Vec<Arc<BTreeReader>> = self.b_trees.drain(runs.clone()).flatten().collect();
+        let oldest_tombstone = old_runs.iter().fold(None, |oldest, run| {
+            oldest_of(oldest, run.stats.oldest_tombstone)
+        });
+        let iter = old_runs.iter().map(|run| run.level_iter()).collect();
+        let file_name = self.next_run_file_name();
+        let stats = match self.write_b_tree(
+            &file_name,
+            runs.start,
+            iter,
+            drop_tombstones,
+            oldest_tombstone,
+        ) {
+            Ok(stats) => stats,
+            Err(error) => {
+                self.b_trees
+                    .splice(runs.start..runs.start, old_runs.into_iter().map(Some));
+                return Err(error);
+            }
+        };
+        if let Some(run) = self.open_b_tree(file_name, stats)? {
+            self.b_trees.insert(runs.start, Some(run));
+        }
+        self.obsolete.extend(old_runs);
+        Ok(())
+    }
+    /// whether a tombstone written at `oldest_tombstone` is past the `tombstone_deadline`
+    fn expired(&self, oldest_tombstone: Option<u64>) -> bool {
+        match (self.options.tombstone_deadline, oldest_tombstone) {
+            (Some(deadline), Some(oldest)) => now().saturating_sub(oldest) >= deadline.as_secs(),
+            _ => false,
+        }
+    }
+
+    /// compacts the shallowest level whose tombstones are too dense or too old into the bottom
+    /// level. Returns whether there was one.
+    fn compact_tombstones(&mut self) -> Result<bool> {
+        let level = self.b_trees.iter().position(|b_tree| match b_tree {
+            Some(b_tree) => {
+                b_tree.stats.tombstones > 0
+                    && (b_tree.stats.tombstone_density() > self.options.tombstone_density_threshold
+                        || self.expired(b_tree.stats.oldest_tombstone))
+            }
+            None => false,
+        });
+        if let Some(level) = level {
+            match self.options.compaction_style {
+                CompactionStyle::Leveled => self.compact_into_bottom(level)?,
+                CompactionStyle::Universal { .. } => self.merge_runs(level..self.b_trees.len())?,
+            }
+            self.write_metadata()?;
+        }
+        Ok(level.is_some())
+    }
+    /// merges every level from `level` down into the deepest non empty level, dropping tombstones.
+    fn compact_into_bottom(&mut self, level: usize) -> Result<()> {
+        let bottom = self.b_trees.iter().rposition(Option::is_some).unwrap();
+        let b_trees: Vec<Arc<BTreeReader>> = self.b_trees[level..=bottom]
+            .iter_mut()
+            .filter_map(Option::take)
+            .collect();
+        let iter = b_trees.iter().map(|b_tree| b_tree.level_iter()).collect();
+        self.rewrite_level(bottom, iter, b_trees)
+    }
+    /// writes the merge of `iter` into `level` without tombstones, replacing `old_b_trees`.
+    /// `iter` may still be reading the files of `old_b_trees` (including the BTree currently at
+    /// `level`), which are only deleted once the result is written.
+    fn rewrite_level(
+        &mut self,
+        level: usize,
+        iter: Vec<LevelIterator<'_>>,
+        old_b_trees: Vec<Arc<BTreeReader>>,
+    ) -> Result<()> {
+        let file_name = self.next_level_file_name(level);
+        let stats = self.write_b_tree(&file_name, level, iter, true, None)?;
+        self.b_trees[level] = self.open_b_tree(file_name, stats)?;
+        self.obsolete.extend(old_b_trees);
+        Ok(())
+    }
+    fn bulk_load(&mut self, pairs: impl IntoIterator<Item = (i64, i64)>) -> Result<()> {
+        let (run_file_names, entries) = self.write_sorted_runs(pairs)?;
+        let loaded = self.load_sorted_runs(&run_file_names, entries);
+        for file_name in run_file_names {
+            fs::remove_file(file_name)?;
+        }
+        loaded?;
+        self.write_metadata()
+    }
+    /// the second half of an external sort: merges the runs written by `write_sorted_runs` into
+    /// the bottom level.
+    fn load_sorted_runs(&mut self, run_file_names: &[String], entries: u64) -> Result<()> {
+        // later runs hold later pairs so they go first
+        let mut iter: Vec<LevelIterator<'_>> = run_file_names
+            .iter()
+            .rev()
+            .map(|file_name| LevelIterator::LevelN(ReaderIterator::new(file_name.clone())))
+            .collect();
+        match self.options.compaction_style {
+            CompactionStyle::Leveled => {
+                // the level the data would have ended up in had it been inserted normally,
+                // so the next flushes don't immediately have to merge with it.
+                let mut level = 0;
+                let mem_table_entries =
+                    self.options.max_mem_table_bytes / self.options.mem_table.entry_size();
+                while ((mem_table_entries as u64) << level) < entries {
+                    level += 1;
+                }
+                let bottom = self.b_trees.iter().rposition(Option::is_some);
+                let level = bottom.map_or(level, |bottom| bottom.max(level));
+                while self.b_trees.len() <= level {
+                    self.b_trees.push(None);
+                }
+                let old_b_trees: Vec<Arc<BTreeReader>> =
+                    self.b_trees[level].take().into_iter().collect();
+                for b_tree in old_b_trees.iter() {
+                    iter.insert(0, b_tree.level_iter());
+                }
+                self.rewrite_level(level, iter, old_b_trees)?;
+            }
+            CompactionStyle::Universal { .. } => {
+                let old_runs: Vec<Arc<BTreeReader>> =
+                    self.b_trees.pop().flatten().into_iter().collect();
+                for run in old_runs.iter() {
+                    iter.insert(0, run.level_iter());
+                }
+                let file_name = self.next_run_file_name();
+                let level = self.b_trees.len();
+                let stats = self.write_b_tree(&file_name, level, iter, true, None)?;
+                if let Some(run) = self.open_b_tree(file_name, stats)? {
+                    self.b_trees.push(Some(run));
+                }
+                self.obsolete.extend(old_runs);
+            }
+        }
+        Ok(())
+    }
+    /// the first half of an external sort: splits `pairs` into chunks of `bulk_load_run_size`,
+    /// sorts each one and writes it to its own file.
+    /// Returns the files in the order they were written along with the number of pairs in them.
+    /// If a pair uses a reserved key or value, the runs written so far are deleted.
+    fn write_sorted_runs(
+        &mut self,
+        pairs: impl IntoIterator<Item = (i64, i64)>,
+    ) -> Result<(Vec<String>, u64)> {
+        let mut run_file_names = Vec::new();
+        let mut entries = 0;
+        let mut pairs = pairs.into_iter().peekable();
+        let mut run = Vec::with_capacity(self.options.bulk_load_run_size);
+        while pairs.peek().is_some() {
+            run.clear();
+            run.extend(pairs.by_ref().take(self.options.bulk_load_run_size));
+            if let Err(error) = run.iter().try_for_each(|pair| check_write(pair.0, pair.1)) {
+                for file_name in run_file_names {
+                    fs::remove_file(file_name)?;
+                }
+                return Err(error);
+            }
+            // the sort is stable so the pairs of a key stay in the order they were given in
+            run.sort_by_key(|pair| pair.0);
+            run.dedup_by(|later, earlier| {
+                if later.0 == earlier.0 {
+                    *earlier = *later;
+                    true
+                } else {
+                    false
+                }
+            });
+            let file_name =
+                self.path.clone() + "/bulk_load_" + self.next_file_number.to_string().as_str();
+            self.next_file_number += 1;
+            let mut writer = Level0Writer::new(file_name.clone());
+            for pair in run.iter() {
+                writer.add_pair(*pair);
+            }
+            writer.finish();
+            entries += run.len() as u64;
+            run_file_names.push(file_name);
         }
+        Ok((run_file_names, entries))
     }
 }
+
+/// hands the pages of a BTree joining the database over to `cache`: they're evicted once its
+/// files are closed, and its upper levels are pinned if the options ask for it.
+fn track_pages<A: Cache>(
+    b_tree: &BTreeReader,
+    cache: &Arc<PageCache<A>>,
+    options: &DatabaseOptions,
+) -> Result<()> {
+    b_tree.evict_on_close(cache);
+    if options.pin_upper_levels {
+        // a BTree whose upper levels don't all fit is left unpinned
+        b_tree.pin_upper_levels(cache)?;
+    }
+    Ok(())
+}
+
+fn log_file_name(path: &str, number: u64) -> String {
+    format!("{}/wal_{}.log", path, number)
+}
+
+/// seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn oldest_of(time1: Option<u64>, time2: Option<u64>) -> Option<u64> {
+    match (time1, time2) {
+        (Some(time1), Some(time2)) => Some(time1.min(time2)),
+        (time1, time2) => time1.or(time2),
+    }
+}
+
+/// `TOMBSTONE` marks the end of the pairs of a page and `DELETED` a deleted key, so neither can
+/// be written
+fn check_write(key: i64, value: i64) -> Result<()> {
+    if key == TOMBSTONE || value == DELETED {
+        return Err(Error::ReservedKeyOrValue { key });
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::buffer::{PAGE_SIZE, PAGE_TRAILER_SIZE};
+    use crate::cache::{ClockCache, LruCache, LruKCache, TwoQueueCache};
     use crate::cache_trait::NoCache;
+    use crate::codec::CodecKind;
+    use crate::ingest::SortedFileBuilder;
+    use crate::mem_table::MemTableKind;
+    use crate::options::{IndexKind, PageFormat, SyncMode};
+    use std::collections::HashSet;
     use std::fs;
+    use std::time::Duration;
 
-    #[test]
-    fn level_number() {
-        assert_eq!(get_level_number("b_tree_10_hello_world.world"), 10);
+    fn empty_directory(path: &str) -> String {
+        let _ = fs::remove_dir_all(path);
+        fs::create_dir_all(path).unwrap();
+        path.to_string()
+    }
+
+    /// the size of a default memtable that holds `entries` entries
+    fn mem_table_bytes(entries: usize) -> usize {
+        entries * MemTableKind::Avl.entry_size()
     }
 
     #[test]
@@ -185,13 +1189,13 @@ mod tests {
         let path = "test_database".to_string();
         fs::remove_dir_all(path.clone());
         fs::create_dir_all(path.clone()).unwrap();
-        let mut database: Database<NoCache> = Database::create(path.clone(), 1000);
+        let database: Database<NoCache> = Database::create(path.clone(), mem_table_bytes(1000));
         for i in 0..2000 {
-            database.insert(i, i);
+            database.insert(i, i).unwrap();
         }
-        println!("{:?}", database.b_trees);
+        println!("{:?}", database.current().b_trees);
         for i in 0..2000 {
-            assert_eq!(database.get(i), Some(i));
+            assert_eq!(database.get(i).unwrap(), Some(i));
         }
     }
     #[test]
@@ -199,13 +1203,13 @@ mod tests {
         let path = "test_database".to_string();
         fs::remove_dir_all(path.clone());
         fs::create_dir_all(path.clone()).unwrap();
-        let mut database: Database<NoCache> = Database::create(path.clone(), 1000);
+        let database: Database<NoCache> = Database::create(path.clone(), mem_table_bytes(1000));
         for i in 0..8000 {
-            database.insert(i, i);
+            database.insert(i, i).unwrap();
         }
-        println!("{:?}", database.b_trees);
+        println!("{:?}", database.current().b_trees);
         for i in 0..8000 {
-            assert_eq!(database.get(i), Some(i));
+            assert_eq!(database.get(i).unwrap(), Some(i));
         }
     }
     #[test]
@@ -213,13 +1217,1051 @@ mod tests {
         let path = "test_database".to_string();
         fs::remove_dir_all(path.clone());
         fs::create_dir_all(path.clone()).unwrap();
-        let mut database: Database<NoCache> = Database::create(path.clone(), 1000);
+        let database: Database<NoCache> = Database::create(path.clone(), mem_table_bytes(1000));
         for i in 0..8001 {
-            database.insert(i, i);
+            database.insert(i, i).unwrap();
         }
-        println!("{:?}", database.b_trees);
+        println!("{:?}", database.current().b_trees);
         for i in 0..8001 {
-            assert_eq!(database.get(i), Some(i));
+            assert_eq!(database.get(i).unwrap(), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_delete() {
+        let path = empty_directory("test_database_delete");
+        let database: Database<NoCache> = Database::create(path, mem_table_bytes(1000));
+        for i in 0..3000 {
+            database.insert(i, i).unwrap();
+        }
+        for i in (0..3000).step_by(2) {
+            database.delete(i).unwrap();
+        }
+        database.insert(10, 11).unwrap();
+        for i in 0..3000 {
+            let expected = match i {
+                10 => Some(11),
+                i if i % 2 == 0 => None,
+                i => Some(i),
+            };
+            assert_eq!(database.get(i).unwrap(), expected);
+        }
+        let range: Vec<_> = database
+            .range(0, 20)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            range,
+            vec![
+                (1, 1),
+                (3, 3),
+                (5, 5),
+                (7, 7),
+                (9, 9),
+                (10, 11),
+                (11, 11),
+                (13, 13),
+                (15, 15),
+                (17, 17),
+                (19, 19)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tombstone_deadline() {
+        let path = empty_directory("test_database_deadline");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            tombstone_density_threshold: 1.0,
+            tombstone_deadline: Some(Duration::ZERO),
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path, options);
+        for i in 0..4000 {
+            database.insert(i, i).unwrap();
+        }
+        database.delete(5).unwrap();
+        assert!(database
+            .levels
+            .lock()
+            .unwrap()
+            .mem_table_oldest_tombstone
+            .is_some());
+        database.compact_tombstones().unwrap();
+        assert_eq!(
+            database.levels.lock().unwrap().mem_table_oldest_tombstone,
+            None
+        );
+        for b_tree in database.current().b_trees.iter().flatten() {
+            assert_eq!(b_tree.stats.tombstones, 0);
+        }
+        assert_eq!(database.get(5).unwrap(), None);
+        assert_eq!(database.get(6).unwrap(), Some(6));
+        let entries: u64 = database
+            .current()
+            .b_trees
+            .iter()
+            .flatten()
+            .map(|b_tree| b_tree.stats.entries)
+            .sum();
+        assert_eq!(entries, 3999);
+    }
+
+    #[test]
+    fn test_tombstone_density() {
+        let path = empty_directory("test_database_density");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            tombstone_density_threshold: 0.5,
+            tombstone_deadline: None,
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path, options);
+        for i in 0..2000 {
+            database.insert(i, i).unwrap();
+        }
+        // a memtable that is mostly tombstones lands in level 0 above the data it deletes
+        for i in 0..900 {
+            database.delete(i).unwrap();
+        }
+        for i in 2000..2101 {
+            database.insert(i, i).unwrap();
+        }
+        for b_tree in database.current().b_trees.iter().flatten() {
+            assert!(b_tree.stats.tombstone_density() <= 0.5);
+        }
+        for i in 0..2101 {
+            assert_eq!(
+                database.get(i).unwrap(),
+                if i < 900 { None } else { Some(i) }
+            );
+        }
+    }
+
+    #[test]
+    fn test_open() {
+        let path = empty_directory("test_database_open");
+        let database: Database<NoCache> = Database::create(path.clone(), mem_table_bytes(1000));
+        for i in 0..3000 {
+            database.insert(i, i * 2).unwrap();
+        }
+        database.delete(7).unwrap();
+        database.flush().unwrap();
+        let database: Database<NoCache> = Database::open(path).unwrap();
+        assert_eq!(
+            database.levels.lock().unwrap().options.max_mem_table_bytes,
+            mem_table_bytes(1000)
+        );
+        assert_eq!(database.get(7).unwrap(), None);
+        for i in 8..3000 {
+            assert_eq!(database.get(i).unwrap(), Some(i * 2));
         }
     }
+
+    #[test]
+    fn test_fence_index() {
+        let path = empty_directory("test_database_fences");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            index: IndexKind::Fences,
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path.clone(), options);
+        for i in 0..10_000 {
+            database.insert(i, i + 1).unwrap();
+        }
+        database.flush().unwrap();
+        assert!(database
+            .current()
+            .b_trees
+            .iter()
+            .flatten()
+            .all(|b_tree| b_tree.fences.is_some()));
+        let database: Database<NoCache> = Database::open(path).unwrap();
+        assert!(database
+            .current()
+            .b_trees
+            .iter()
+            .flatten()
+            .all(|b_tree| b_tree.fences.is_some()));
+        for i in 0..10_000 {
+            assert_eq!(database.get(i).unwrap(), Some(i + 1));
+        }
+    }
+
+    #[test]
+    fn test_learned_index() {
+        let path = empty_directory("test_database_learned");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            index: IndexKind::learned(),
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path.clone(), options);
+        for i in 0..10_000 {
+            database.insert(i * 3, i).unwrap();
+        }
+        database.delete(30).unwrap();
+        database.flush().unwrap();
+        let database: Database<NoCache> = Database::open(path).unwrap();
+        assert!(database
+            .current()
+            .b_trees
+            .iter()
+            .flatten()
+            .all(|b_tree| b_tree.model.is_some()));
+        assert_eq!(database.get(30).unwrap(), None);
+        for i in 11..10_000 {
+            assert_eq!(database.get(i * 3).unwrap(), Some(i));
+            assert_eq!(database.get(i * 3 + 1).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_compressed_pages() {
+        let path = empty_directory("test_database_compressed");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            page_format: PageFormat::Compressed,
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path.clone(), options);
+        for i in 0..10_000 {
+            database.insert(i, -i).unwrap();
+        }
+        for i in 0..10 {
+            database.delete(i * 1000).unwrap();
+        }
+        database.flush().unwrap();
+        let database: Database<NoCache> = Database::open(path).unwrap();
+        for i in 0..10_000 {
+            let expected = if i % 1000 == 0 { None } else { Some(-i) };
+            assert_eq!(database.get(i).unwrap(), expected);
+        }
+        assert_eq!(
+            database
+                .range(999, 1001)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![(999, -999), (1001, -1001)]
+        );
+    }
+
+    #[test]
+    fn test_codecs_by_level() {
+        let path = empty_directory("test_database_codecs");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            deep_codec: CodecKind::FrameOfReference,
+            deep_level: 2,
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path.clone(), options);
+        for i in 0..6000 {
+            database.insert(i, i % 10).unwrap();
+        }
+        database.flush().unwrap();
+        let database: Database<NoCache> = Database::open(path).unwrap();
+        // 6000 entries in levels 1 and 2
+        let codecs: Vec<_> = database
+            .current()
+            .b_trees
+            .iter()
+            .map(|b_tree| b_tree.as_ref().map(|b_tree| b_tree.footer.codec))
+            .collect();
+        assert_eq!(
+            codecs,
+            [
+                None,
+                Some(CodecKind::None),
+                Some(CodecKind::FrameOfReference)
+            ]
+        );
+        for i in 0..6000 {
+            assert_eq!(database.get(i).unwrap(), Some(i % 10));
+        }
+        // the compaction that purges the tombstone rewrites the bottom level with its codec
+        database.delete(5).unwrap();
+        database.flush().unwrap();
+        assert_eq!(database.get(5).unwrap(), None);
+        assert_eq!(
+            database.current().b_trees[2].as_ref().unwrap().footer.codec,
+            CodecKind::FrameOfReference
+        );
+        assert_eq!(
+            database
+                .range(4, 7)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![(4, 4), (6, 6), (7, 7)]
+        );
+    }
+
+    #[test]
+    fn test_concurrent_reads_and_writes() {
+        for mem_table in [MemTableKind::Avl, MemTableKind::ConcurrentSkipList] {
+            let path = empty_directory(&format!("test_database_threads_{:?}", mem_table));
+            let options = DatabaseOptions {
+                max_mem_table_bytes: 500 * mem_table.entry_size(),
+                mem_table,
+                ..DatabaseOptions::default()
+            };
+            let database: Database<NoCache> = Database::create_with_options(path, options);
+            for i in 0..2000 {
+                database.insert(i, i).unwrap();
+            }
+            std::thread::scope(|scope| {
+                // the first keys stay readable while the writers flush and compact under them
+                for reader in 0..4 {
+                    let database = &database;
+                    scope.spawn(move || {
+                        for _ in 0..5 {
+                            for i in (reader..2000).step_by(4) {
+                                assert_eq!(database.get(i).unwrap(), Some(i));
+                            }
+                            let range = database.range(100, 199).unwrap();
+                            assert!(range.map(Result::unwrap).eq((100..200).map(|i| (i, i))));
+                        }
+                    });
+                }
+                for writer in 0..4 {
+                    let database = &database;
+                    scope.spawn(move || {
+                        for i in (2000 + writer..6000).step_by(4) {
+                            database.insert(i, -i).unwrap();
+                        }
+                    });
+                }
+            });
+            for i in 2000..6000 {
+                assert_eq!(database.get(i).unwrap(), Some(-i), "{:?}", mem_table);
+            }
+        }
+    }
+
+    #[test]
+    fn test_page_caches() {
+        check_page_cache::<LruCache>("test_database_lru_cache");
+        check_page_cache::<ClockCache>("test_database_clock_cache");
+        check_page_cache::<TwoQueueCache>("test_database_two_queue_cache");
+        check_page_cache::<LruKCache>("test_database_lru_k_cache");
+    }
+
+    fn check_page_cache<A: Cache>(path: &str) {
+        let path = empty_directory(path);
+        let database: Database<A> = Database::create(path.clone(), mem_table_bytes(500));
+        for i in 0..6000 {
+            database.insert(i, i).unwrap();
+        }
+        // overwrites and deletes land in new files, which must not be served stale pages
+        for i in (0..6000).step_by(3) {
+            database.insert(i, -i).unwrap();
+        }
+        for i in (0..6000).step_by(5) {
+            database.delete(i).unwrap();
+        }
+        std::thread::scope(|scope| {
+            for reader in 0..4 {
+                let database = &database;
+                scope.spawn(move || {
+                    for _ in 0..3 {
+                        for i in (reader..6000).step_by(4) {
+                            let expected = match i {
+                                i if i % 5 == 0 => None,
+                                i if i % 3 == 0 => Some(-i),
+                                i => Some(i),
+                            };
+                            assert_eq!(database.get(i).unwrap(), expected);
+                        }
+                    }
+                });
+            }
+        });
+        // scans read through the cache too
+        let scanned: Vec<_> = database
+            .range(0, 5999)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(scanned.len(), 6000 - 1200);
+        drop(database);
+        let database: Database<A> = Database::open(path).unwrap();
+        let range: Vec<_> = database
+            .range(0, 15)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            range,
+            vec![
+                (1, 1),
+                (2, 2),
+                (3, -3),
+                (4, 4),
+                (6, -6),
+                (7, 7),
+                (8, 8),
+                (9, -9),
+                (11, 11),
+                (12, -12),
+                (13, 13),
+                (14, 14)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_files_are_never_reused() {
+        let path = empty_directory("test_database_file_numbers");
+        let database: Database<LruCache> = Database::create(path.clone(), mem_table_bytes(500));
+        let cache = database.cache.clone();
+        let mut retired = HashSet::new();
+        let mut live = HashSet::new();
+        for i in 0..4000 {
+            database.insert(i, i).unwrap();
+            if i % 250 == 249 {
+                for key in 0..=i {
+                    assert_eq!(database.get(key).unwrap(), Some(key));
+                }
+                let names: HashSet<String> = database
+                    .current()
+                    .b_trees
+                    .iter()
+                    .flatten()
+                    .map(|b_tree| b_tree.file_name_prefix().to_string())
+                    .collect();
+                assert!(names.is_disjoint(&retired));
+                retired.extend(live.difference(&names).cloned());
+                live = names;
+            }
+        }
+        assert!(retired.len() >= 4);
+        // the pages of the compacted files went with them
+        let live_pages: usize = database
+            .current()
+            .b_trees
+            .iter()
+            .flatten()
+            .map(|b_tree| b_tree.footer.data_pages as usize + b_tree.levels())
+            .sum();
+        assert!(cache.lock().len() <= live_pages);
+        drop(database);
+        assert!(cache.lock().is_empty());
+    }
+
+    #[test]
+    fn test_pinned_upper_levels() {
+        for pin_upper_levels in [false, true] {
+            let path = empty_directory("test_database_pinned");
+            let options = DatabaseOptions {
+                max_mem_table_bytes: mem_table_bytes(1000),
+                pin_upper_levels,
+                ..DatabaseOptions::default()
+            };
+            let database: Database<NoCache> = Database::create_with_options(path, options);
+            // four flushes leave a single BTree in level 2, with a root above its pages
+            for i in 0..4001 {
+                database.insert(i, i).unwrap();
+            }
+            assert_eq!(database.current().b_trees.iter().flatten().count(), 1);
+            let cache = database.cache.clone();
+            assert_eq!(cache.pinned_pages(), pin_upper_levels as usize);
+            for i in 0..4000 {
+                let reads = cache.disk_reads();
+                assert_eq!(database.get(i).unwrap(), Some(i));
+                assert_eq!(cache.disk_reads() - reads, 2 - pin_upper_levels as u64);
+            }
+            for i in 4001..10_000 {
+                database.insert(i, i).unwrap();
+            }
+            let upper_pages: u64 = database
+                .current()
+                .b_trees
+                .iter()
+                .flatten()
+                .map(|b_tree| b_tree.footer.level_pages.iter().sum::<u64>())
+                .sum();
+            assert_eq!(
+                cache.pinned_pages(),
+                upper_pages as usize * pin_upper_levels as usize
+            );
+            drop(database);
+            assert_eq!(cache.pinned_pages(), 0);
+        }
+    }
+
+    #[test]
+    fn test_shared_cache() {
+        let resources = SharedResources {
+            cache: Arc::new(PageCache::<LruCache>::with_capacity_bytes(16 * PAGE_SIZE)),
+            write_buffer: None,
+        };
+        let databases: Vec<Database<LruCache>> = (0..4)
+            .map(|i| {
+                let options = DatabaseOptions {
+                    max_mem_table_bytes: mem_table_bytes(1000),
+                    ..DatabaseOptions::default()
+                };
+                let path = empty_directory(&format!("test_database_shared_cache_{i}"));
+                Database::create_with_resources(path, options, resources.clone())
+            })
+            .collect();
+        for i in 0..3000 {
+            for (j, database) in databases.iter().enumerate() {
+                database.insert(i, i * 10 + j as i64).unwrap();
+            }
+        }
+        for i in 0..3000 {
+            for (j, database) in databases.iter().enumerate() {
+                assert_eq!(database.get(i).unwrap(), Some(i * 10 + j as i64));
+            }
+        }
+        // the capacity is for all of them together, an eighth of it is kept for pinned pages
+        assert_eq!(resources.cache.lock().len(), 14);
+        drop(databases);
+        assert!(resources.cache.lock().is_empty());
+    }
+
+    #[test]
+    fn test_write_buffer_manager() {
+        let write_buffer = Arc::new(WriteBufferManager::new(mem_table_bytes(1000)));
+        let resources: SharedResources<NoCache> = SharedResources {
+            write_buffer: Some(write_buffer.clone()),
+            ..SharedResources::default()
+        };
+        let databases: Vec<Database<NoCache>> = (0..4)
+            .map(|i| {
+                // none of the memtables fills up on its own
+                let options = DatabaseOptions {
+                    max_mem_table_bytes: mem_table_bytes(4000),
+                    ..DatabaseOptions::default()
+                };
+                let path = empty_directory(&format!("test_database_write_buffer_{i}"));
+                Database::create_with_resources(path, options, resources.clone())
+            })
+            .collect();
+        let mut max_used = 0;
+        for i in 0..2000 {
+            for database in databases.iter() {
+                database.insert(i, i).unwrap();
+                max_used = max_used.max(write_buffer.used_bytes());
+                assert!(write_buffer.used_bytes() <= write_buffer.limit_bytes());
+            }
+        }
+        assert!(max_used > mem_table_bytes(900));
+        for database in databases.iter() {
+            assert!(database.current().b_trees.iter().flatten().count() > 0);
+            for i in 0..2000 {
+                assert_eq!(database.get(i).unwrap(), Some(i));
+            }
+        }
+        drop(databases);
+        assert_eq!(write_buffer.used_bytes(), 0);
+    }
+
+    #[test]
+    fn test_row_cache() {
+        let path = empty_directory("test_database_row_cache");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            row_cache_rows: Some(100),
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path, options);
+        for i in 0..3000 {
+            database.insert(i, i).unwrap();
+        }
+        let cache = database.cache.clone();
+        assert_eq!(database.get(5).unwrap(), Some(5));
+        let reads = cache.disk_reads();
+        assert_eq!(database.get(5).unwrap(), Some(5));
+        assert_eq!(database.get(-1).unwrap(), None);
+        assert_eq!(database.get(-1).unwrap(), None);
+        assert_eq!(cache.disk_reads(), reads);
+        // writes replace the cached rows, whether the key was found or not
+        database.insert(5, 50).unwrap();
+        assert_eq!(database.get(5).unwrap(), Some(50));
+        database.delete(5).unwrap();
+        database.insert(-1, 1).unwrap();
+        assert_eq!(database.get(5).unwrap(), None);
+        assert_eq!(database.get(-1).unwrap(), Some(1));
+        // flushing leaves them as they were
+        for i in 3000..6000 {
+            database.insert(i, i).unwrap();
+        }
+        assert_eq!(database.get(5).unwrap(), None);
+        assert_eq!(database.get(-1).unwrap(), Some(1));
+        assert_eq!(cache.disk_reads(), reads);
+        assert_eq!(database.get(-2).unwrap(), None);
+        database.bulk_load([(-2, 2)]).unwrap();
+        assert_eq!(database.get(-2).unwrap(), Some(2));
+        assert_eq!(database.rows.as_ref().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_wal_same_key_writers() {
+        let sync_modes = [SyncMode::EveryWrite, SyncMode::Interval { millis: 1 }];
+        for (i, sync_mode) in sync_modes.into_iter().enumerate() {
+            let path = empty_directory(&format!("test_database_wal_same_key{}", i));
+            let options = DatabaseOptions {
+                mem_table: MemTableKind::ConcurrentSkipList,
+                wal: Some(sync_mode),
+                ..DatabaseOptions::default()
+            };
+            let database: Database<NoCache> = Database::create_with_options(path.clone(), options);
+            let barrier = std::sync::Barrier::new(8);
+            std::thread::scope(|scope| {
+                for writer in 0..8 {
+                    let (database, barrier) = (&database, &barrier);
+                    scope.spawn(move || {
+                        // every writer writes each key at once, deletes take the writers lock and
+                        // the inserts race past it
+                        for key in 0..500 {
+                            barrier.wait();
+                            if writer == 0 && key % 3 == 0 {
+                                database.delete(key).unwrap();
+                            } else {
+                                database.insert(key, writer).unwrap();
+                            }
+                        }
+                    });
+                }
+            });
+            let before: Vec<_> = (0..500).map(|key| database.get(key).unwrap()).collect();
+            // dropped without a flush, as if the process crashed
+            drop(database);
+            let database: Database<NoCache> = Database::open(path).unwrap();
+            let after: Vec<_> = (0..500).map(|key| database.get(key).unwrap()).collect();
+            assert_eq!(after, before);
+        }
+    }
+
+    #[test]
+    fn test_wal_recovery() {
+        let path = empty_directory("test_database_wal");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            wal: Some(SyncMode::EveryWrite),
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path.clone(), options);
+        std::thread::scope(|scope| {
+            for writer in 0..4 {
+                let database = &database;
+                scope.spawn(move || {
+                    for i in (writer..2500).step_by(4) {
+                        database.insert(i, i).unwrap();
+                    }
+                });
+            }
+        });
+        database.delete(7).unwrap();
+        database.insert(2400, -1).unwrap();
+        // dropped without a flush, as if the process crashed
+        drop(database);
+        let logs = || {
+            fs::read_dir(&path)
+                .unwrap()
+                .filter(|entry| {
+                    log_number(entry.as_ref().unwrap().file_name().to_str().unwrap()).is_some()
+                })
+                .count()
+        };
+        assert_eq!(logs(), 1);
+        let database: Database<NoCache> = Database::open(path.clone()).unwrap();
+        assert_eq!(logs(), 1);
+        assert_eq!(database.get(7).unwrap(), None);
+        assert_eq!(database.get(2400).unwrap(), Some(-1));
+        for i in (0..2500).filter(|i| *i != 7 && *i != 2400) {
+            assert_eq!(database.get(i).unwrap(), Some(i));
+        }
+        drop(database);
+        let database: Database<NoCache> = Database::open(path).unwrap();
+        assert_eq!(database.get(2400).unwrap(), Some(-1));
+        assert!(database
+            .levels
+            .lock()
+            .unwrap()
+            .mem_table_oldest_tombstone
+            .is_some());
+    }
+
+    #[test]
+    fn test_overwrites_dont_fill_the_mem_table() {
+        let path = empty_directory("test_database_overwrites");
+        let database: Database<NoCache> = Database::create(path, mem_table_bytes(1000));
+        for round in 0..10 {
+            for i in 0..1000 {
+                database.insert(i, round).unwrap();
+            }
+        }
+        assert!(database.current().b_trees.is_empty());
+        assert_eq!(
+            database
+                .current()
+                .mem_table
+                .table
+                .read()
+                .unwrap()
+                .size_in_bytes(),
+            mem_table_bytes(1000)
+        );
+        database.insert(1000, 0).unwrap();
+        assert_eq!(database.current().b_trees.len(), 1);
+        assert_eq!(database.get(999).unwrap(), Some(9));
+    }
+
+    #[test]
+    fn test_mem_table_kinds() {
+        for mem_table in [
+            MemTableKind::Arena,
+            MemTableKind::SkipList,
+            MemTableKind::Vector,
+            MemTableKind::ConcurrentSkipList,
+        ] {
+            let path = empty_directory(&format!("test_database_{:?}", mem_table));
+            let options = DatabaseOptions {
+                max_mem_table_bytes: 1000 * mem_table.entry_size(),
+                mem_table,
+                ..DatabaseOptions::default()
+            };
+            let database: Database<NoCache> = Database::create_with_options(path.clone(), options);
+            for i in 0..5000 {
+                database.insert(i, i).unwrap();
+            }
+            for i in (0..5000).rev().step_by(2) {
+                database.insert(i, -i).unwrap();
+            }
+            database.delete(11).unwrap();
+            for i in 0..5000 {
+                let expected = match i {
+                    11 => None,
+                    i if i % 2 == 1 => Some(-i),
+                    i => Some(i),
+                };
+                assert_eq!(database.get(i).unwrap(), expected);
+            }
+            assert_eq!(
+                database
+                    .range(9, 13)
+                    .unwrap()
+                    .collect::<Result<Vec<_>>>()
+                    .unwrap(),
+                vec![(9, -9), (10, 10), (12, 12), (13, -13)]
+            );
+            database.flush().unwrap();
+            let database: Database<NoCache> = Database::open(path).unwrap();
+            assert_eq!(database.levels.lock().unwrap().options.mem_table, mem_table);
+            assert_eq!(database.get(4999).unwrap(), Some(-4999));
+        }
+    }
+
+    #[test]
+    fn test_universal_compaction() {
+        let path = empty_directory("test_database_universal");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            compaction_style: CompactionStyle::universal(),
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path.clone(), options);
+        for i in 0..20000 {
+            database.insert(i % 15000, i).unwrap();
+        }
+        for i in 0..100 {
+            database.delete(i).unwrap();
+        }
+        assert!(database.current().b_trees.len() <= 8);
+        assert!(database.current().b_trees.iter().all(Option::is_some));
+        for i in 0..15000 {
+            let expected = match i {
+                i if i < 100 => None,
+                i if i < 5000 => Some(i + 15000),
+                i => Some(i),
+            };
+            assert_eq!(database.get(i).unwrap(), expected);
+        }
+        database.flush().unwrap();
+        let database: Database<NoCache> = Database::open(path).unwrap();
+        assert_eq!(database.get(0).unwrap(), None);
+        assert_eq!(database.get(4999).unwrap(), Some(19999));
+        assert_eq!(
+            database
+                .range(5000, 5002)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![(5000, 5000), (5001, 5001), (5002, 5002)]
+        );
+    }
+
+    #[test]
+    fn test_reserved_keys_and_values() {
+        let path = empty_directory("test_database_reserved");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            bulk_load_run_size: 4096,
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path.clone(), options);
+        assert!(matches!(
+            database.insert(1, DELETED),
+            Err(Error::ReservedKeyOrValue { key: 1 })
+        ));
+        assert!(database.insert(TOMBSTONE, 1).is_err());
+        assert!(database.delete(TOMBSTONE).is_err());
+        assert_eq!(database.get(1).unwrap(), None);
+        // the runs written before the bad pair are deleted
+        let pairs = (0..10_000).map(|i| (i, if i == 9000 { DELETED } else { i }));
+        assert!(database.bulk_load(pairs).is_err());
+        assert_eq!(database.get(0).unwrap(), None);
+        assert!(fs::read_dir(path).unwrap().all(|file| !file
+            .unwrap()
+            .file_name()
+            .to_str()
+            .unwrap()
+            .starts_with("bulk_load_")));
+    }
+
+    #[test]
+    fn test_corrupted_page() {
+        let path = empty_directory("test_database_corrupted");
+        let database: Database<NoCache> = Database::create(path.clone(), mem_table_bytes(1000));
+        for i in 0..1001 {
+            database.insert(i, i).unwrap();
+        }
+        let file_name = database.current().b_trees[0]
+            .as_ref()
+            .unwrap()
+            .file_name_prefix()
+            .to_string()
+            + ".items.btree";
+        let mut items = fs::read(&file_name).unwrap();
+        items[PAGE_SIZE * 2 + 16] ^= 1;
+        fs::write(&file_name, items).unwrap();
+        assert!(matches!(
+            database.range(0, 2000).unwrap().collect::<Result<Vec<_>>>(),
+            Err(Error::Corruption { page: 2, .. })
+        ));
+        // the flush has to merge with the corrupted BTree, so it fails and changes nothing
+        let files = |path: &str| {
+            let mut files: Vec<_> = fs::read_dir(path)
+                .unwrap()
+                .map(|file| file.unwrap().file_name())
+                .collect();
+            files.sort();
+            files
+        };
+        let before = files(&path);
+        assert!(matches!(
+            database.flush(),
+            Err(Error::Corruption { page: 2, .. })
+        ));
+        assert_eq!(files(&path), before);
+        assert_eq!(database.current().b_trees.iter().flatten().count(), 1);
+        assert_eq!(database.get(5).unwrap(), Some(5));
+        assert_eq!(database.get(1000).unwrap(), Some(1000));
+    }
+
+    #[test]
+    fn test_failed_metadata_write() {
+        let path = empty_directory("test_database_metadata");
+        let database: Database<NoCache> = Database::create(path.clone(), mem_table_bytes(1000));
+        for i in 0..2000 {
+            database.insert(i, i).unwrap();
+        }
+        let files = || fs::read_dir(&path).unwrap().count();
+        // the metadata can't be replaced, so the flush merging level 0 into level 1 is undone
+        fs::create_dir(path.clone() + "/metadata.json.tmp").unwrap();
+        let before = files();
+        assert!(matches!(database.flush(), Err(Error::Io(_))));
+        assert_eq!(files(), before);
+        assert!(database.current().b_trees[0].is_some());
+        assert_eq!(database.get(5).unwrap(), Some(5));
+        assert_eq!(database.get(1500).unwrap(), Some(1500));
+        fs::remove_dir(path.clone() + "/metadata.json.tmp").unwrap();
+        database.flush().unwrap();
+        drop(database);
+        let database: Database<NoCache> = Database::open(path).unwrap();
+        for i in 0..2000 {
+            assert_eq!(database.get(i).unwrap(), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let path = empty_directory("test_database_bulk_load");
+        let options = DatabaseOptions {
+            max_mem_table_bytes: mem_table_bytes(1000),
+            bulk_load_run_size: 4096,
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path.clone(), options);
+        for i in 0..500 {
+            database.insert(i, -i).unwrap();
+        }
+        database.flush().unwrap();
+        // every key in 0..30000 shows up twice and the second pair should win
+        let pairs = (0..60000).map(|i| ((i * 7919) % 30000, i));
+        let expected: std::collections::HashMap<i64, i64> = pairs.clone().collect();
+        database.bulk_load(pairs).unwrap();
+        for i in 0..30000 {
+            let value = if i < 500 { -i } else { expected[&i] };
+            assert_eq!(database.get(i).unwrap(), Some(value));
+        }
+        assert_eq!(
+            database
+                .range(499, 500)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap(),
+            vec![(499, -499), (500, expected[&500])]
+        );
+        for i in 1000..3000 {
+            database.insert(i, i).unwrap();
+        }
+        assert_eq!(database.get(1000).unwrap(), Some(1000));
+        assert_eq!(database.get(29999).unwrap(), Some(expected[&29999]));
+        let files: Vec<_> = fs::read_dir(path)
+            .unwrap()
+            .map(|file| file.unwrap().file_name())
+            .collect();
+        assert!(files
+            .iter()
+            .all(|file| !file.to_str().unwrap().starts_with("bulk_load_")));
+    }
+
+    fn build_sorted_file(file_name: &str, keys: std::ops::Range<i64>, offset: i64) -> SortedFile {
+        let mut builder = SortedFileBuilder::new(file_name.to_string());
+        for key in keys {
+            builder.add(key, key + offset).unwrap();
+        }
+        builder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_ingest_over_mem_table() {
+        for (i, compaction_style) in [CompactionStyle::Leveled, CompactionStyle::universal()]
+            .into_iter()
+            .enumerate()
+        {
+            let path = empty_directory(&format!("test_database_ingest_mem_table{}", i));
+            let source = empty_directory(&format!("test_database_ingest_mem_table_source{}", i));
+            let options = DatabaseOptions {
+                max_mem_table_bytes: mem_table_bytes(1000),
+                compaction_style,
+                ..DatabaseOptions::default()
+            };
+            let database: Database<NoCache> = Database::create_with_options(path, options);
+            for i in 0..1500 {
+                database.insert(i, i).unwrap();
+            }
+            database.delete(1200).unwrap();
+            // keys 1000..1500 are still in the memtable, the file has to end up above them
+            let file = build_sorted_file(&(source + "/overlapping"), 900..1400, 1);
+            database.ingest_files(file).unwrap();
+            for i in 0..1500 {
+                let expected = if (900..1400).contains(&i) { i + 1 } else { i };
+                assert_eq!(database.get(i).unwrap(), Some(expected));
+            }
+        }
+    }
+
+    #[test]
+    fn test_ingest_corrupted_file() {
+        let path = empty_directory("test_database_ingest_corrupted");
+        let source = empty_directory("test_database_ingest_corrupted_source");
+        let options = DatabaseOptions {
+            pin_upper_levels: true,
+            ..DatabaseOptions::default()
+        };
+        let database: Database<NoCache> = Database::create_with_options(path, options);
+        let prefix = source.clone() + "/corrupted";
+        build_sorted_file(&prefix, 0..1000, 1);
+        // only pinning reads the upper levels, once the files have been moved in
+        let level_file_name = prefix.clone() + ".level0.btree";
+        let mut level = fs::read(&level_file_name).unwrap();
+        level[16] ^= 1;
+        fs::write(&level_file_name, level).unwrap();
+        let file = SortedFile {
+            file_name_prefix: prefix,
+        };
+        assert!(matches!(
+            database.ingest_files(file),
+            Err(IngestError::Invalid(Error::Corruption { page: 0, .. }))
+        ));
+        // the files are given back and the database goes on as before
+        assert_eq!(fs::read_dir(&source).unwrap().count(), 2);
+        assert!(database.current().b_trees.is_empty());
+        database.insert(5, 5).unwrap();
+        assert_eq!(database.get(5).unwrap(), Some(5));
+    }
+
+    #[test]
+    fn test_ingest_files() {
+        let path = empty_directory("test_database_ingest");
+        let source = empty_directory("test_database_ingest_source");
+        let database: Database<NoCache> = Database::create(path, mem_table_bytes(1000));
+        for i in 0..3000 {
+            database.insert(i, i).unwrap();
+        }
+        // only level 1 is full, the file overlaps it so it has to go above it. The flushes took
+        // file numbers 0 and 1.
+        let file = build_sorted_file(&(source.clone() + "/overlapping"), 500..1500, 1);
+        database.ingest_files(file).unwrap();
+        assert!(database.current().b_trees[0]
+            .as_ref()
+            .unwrap()
+            .file_name_prefix()
+            .ends_with("b_tree_0_2"));
+        // level 0 is now taken, so this one gets merged into it
+        let file = build_sorted_file(&(source.clone() + "/overlapping_again"), 1000..1200, 2);
+        database.ingest_files(file).unwrap();
+        assert!(database.current().b_trees[0].is_none());
+        // nothing overlaps so it becomes a new bottom level
+        let file = build_sorted_file(&(source.clone() + "/disjoint"), 10000..20000, 1);
+        database.ingest_files(file).unwrap();
+        let bottom = database.current().b_trees.len() - 1;
+        assert!(database.current().b_trees[bottom]
+            .as_ref()
+            .unwrap()
+            .file_name_prefix()
+            .ends_with(&format!("b_tree_{}_5", bottom)));
+        for i in 0..3000 {
+            let expected = match i {
+                1000..1200 => i + 2,
+                500..1500 => i + 1,
+                _ => i,
+            };
+            assert_eq!(database.get(i).unwrap(), Some(expected));
+        }
+        for i in 10000..20000 {
+            assert_eq!(database.get(i).unwrap(), Some(i + 1));
+        }
+        assert_eq!(fs::read_dir(source).unwrap().count(), 0);
+
+        // the database only accepts files that are actually sorted
+        let mut writer = BTreeWriter::new("test_database_ingest_source/unsorted".to_string());
+        writer.add_item((1, 1), &PageCache::<NoCache>::default());
+        writer.add_item((2, 2), &PageCache::<NoCache>::default());
+        writer.finish(&PageCache::<NoCache>::default());
+        // swap the two pairs behind the footer's back
+        let mut items = fs::read("test_database_ingest_source/unsorted.items.btree").unwrap();
+        let (first, second) = items.split_at_mut(16);
+        first.swap_with_slice(&mut second[..16]);
+        let checksum = crc32c::crc32c(&items[..PAGE_SIZE - PAGE_TRAILER_SIZE]);
+        items[PAGE_SIZE - PAGE_TRAILER_SIZE..][..4].copy_from_slice(&checksum.to_le_bytes());
+        fs::write("test_database_ingest_source/unsorted.items.btree", items).unwrap();
+        let file = SortedFile {
+            file_name_prefix: "test_database_ingest_source/unsorted".to_string(),
+        };
+        assert!(matches!(
+            database.ingest_files(file),
+            Err(IngestError::OutOfOrder { .. })
+        ));
+    }
 }
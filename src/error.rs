@@ -0,0 +1,64 @@
+use std::fmt::{Display, Formatter};
+
+/// errors from reading the files of a database, or from writes it can't take.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// the file isn't something this version of the database can read
+    InvalidFormat {
+        file_name: String,
+        reason: String,
+    },
+    /// a page doesn't match its checksum, it was torn by a crash or damaged on disk
+    Corruption {
+        file_name: String,
+        page: u64,
+    },
+    /// the key is `TOMBSTONE` or the value is `DELETED`, which the database uses itself
+    ReservedKeyOrValue {
+        key: i64,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub(crate) fn invalid_format(file_name: &str, reason: impl Into<String>) -> Error {
+        Error::InvalidFormat {
+            file_name: file_name.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(error) => error.fmt(f),
+            Error::InvalidFormat { file_name, reason } => {
+                write!(f, "{} is not a valid database file: {}", file_name, reason)
+            }
+            Error::Corruption { file_name, page } => {
+                write!(f, "page {} of {} is corrupted", page, file_name)
+            }
+            Error::ReservedKeyOrValue { key } => {
+                write!(f, "key {} uses a reserved key or value", key)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
@@ -0,0 +1,198 @@
+use crate::buffer::{Buffer, PAIRS_PER_PAGE};
+use crate::codec::CodecKind;
+use crate::error::{Error, Result};
+use crate::options::PageFormat;
+use arrayvec::ArrayVec;
+use scroll::{Pread, Pwrite, LE};
+
+/// "LSMBTREE" read as a little endian u64
+pub const MAGIC: u64 = u64::from_le_bytes(*b"LSMBTREE");
+/// version 2 added a checksum to the end of every page, version 3 the learned index,
+/// version 4 compressed pages and version 5 codecs
+pub const VERSION: u32 = 5;
+/// the most levels a BTree can have above the bottom level
+pub const MAX_HEIGHT: usize = 9;
+
+/// The last page of every `.items.btree` file. It describes the whole BTree so it can be opened
+/// knowing only its file name prefix, and lets `BTreeReader::open` check that the files are
+/// complete before using them.
+///
+/// Every field is stored little endian, one after the other, starting at the beginning of the page.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Footer {
+    /// the number of levels above the bottom level
+    pub height: u32,
+    pub entries: u64,
+    /// the smallest key in the BTree, `i64::MAX` if it's empty
+    pub min_key: i64,
+    /// the largest key in the BTree, `i64::MIN` if it's empty
+    pub max_key: i64,
+    /// the number of pages of items, not counting the footer. With a codec these are the pages
+    /// before encoding, see `stored_pages` for what's on disk.
+    pub data_pages: u64,
+    /// the number of pages on disk holding the items, not counting the footer
+    pub stored_pages: u64,
+    /// the number of segments in the `.model.btree` file, 0 if the BTree has no learned index
+    pub model_segments: u64,
+    /// how far off the learned index is allowed to be, in pairs
+    pub model_max_error: u64,
+    /// the format of every page of the BTree, stored as a u32
+    pub page_format: PageFormat,
+    /// the codec the pages of items were encoded with, stored as a u32
+    pub codec: CodecKind,
+    /// the number of pages in each level above the bottom level, from the lowest one up
+    pub level_pages: ArrayVec<u64, MAX_HEIGHT>,
+}
+
+impl Footer {
+    pub fn to_buffer(&self) -> Buffer {
+        let mut buffer = Buffer::new_0();
+        let bytes = buffer.as_mut_slice_u8();
+        let offset = &mut 0;
+        bytes.gwrite_with(MAGIC, offset, LE).unwrap();
+        bytes.gwrite_with(VERSION, offset, LE).unwrap();
+        bytes.gwrite_with(self.height, offset, LE).unwrap();
+        bytes.gwrite_with(self.entries, offset, LE).unwrap();
+        bytes.gwrite_with(self.min_key, offset, LE).unwrap();
+        bytes.gwrite_with(self.max_key, offset, LE).unwrap();
+        bytes.gwrite_with(self.data_pages, offset, LE).unwrap();
+        bytes.gwrite_with(self.stored_pages, offset, LE).unwrap();
+        bytes.gwrite_with(self.model_segments, offset, LE).unwrap();
+        bytes.gwrite_with(self.model_max_error, offset, LE).unwrap();
+        bytes
+            .gwrite_with(self.page_format as u32, offset, LE)
+            .unwrap();
+        bytes.gwrite_with(self.codec as u32, offset, LE).unwrap();
+        for pages in self.level_pages.iter() {
+            bytes.gwrite_with(*pages, offset, LE).unwrap();
+        }
+        buffer
+    }
+
+    /// parses and sanity checks a footer page read from `file_name`.
+    pub fn from_buffer(buffer: &Buffer, file_name: &str) -> Result<Footer> {
+        let bytes = buffer.as_slice_u8();
+        let offset = &mut 0;
+        let read_u32 = |offset: &mut usize| bytes.gread_with::<u32>(offset, LE).unwrap();
+        let read_u64 = |offset: &mut usize| bytes.gread_with::<u64>(offset, LE).unwrap();
+        if read_u64(offset) != MAGIC {
+            return Err(Error::invalid_format(file_name, "bad magic number"));
+        }
+        let version = read_u32(offset);
+        if version != VERSION {
+            return Err(Error::invalid_format(
+                file_name,
+                format!("unsupported version {}", version),
+            ));
+        }
+        let height = read_u32(offset);
+        if height as usize > MAX_HEIGHT {
+            return Err(Error::invalid_format(
+                file_name,
+                format!("height {} is too large", height),
+            ));
+        }
+        let entries = read_u64(offset);
+        let min_key = read_u64(offset) as i64;
+        let max_key = read_u64(offset) as i64;
+        let data_pages = read_u64(offset);
+        let stored_pages = read_u64(offset);
+        let model_segments = read_u64(offset);
+        let model_max_error = read_u64(offset);
+        let page_format = match read_u32(offset) {
+            0 => PageFormat::Plain,
+            1 => PageFormat::Compressed,
+            format => {
+                return Err(Error::invalid_format(
+                    file_name,
+                    format!("unknown page format {}", format),
+                ))
+            }
+        };
+        let codec_id = read_u32(offset);
+        let Some(codec) = CodecKind::from_id(codec_id) else {
+            return Err(Error::invalid_format(
+                file_name,
+                format!("unknown codec {}", codec_id),
+            ));
+        };
+        let level_pages = (0..height).map(|_| read_u64(offset)).collect();
+        let footer = Footer {
+            height,
+            entries,
+            min_key,
+            max_key,
+            data_pages,
+            stored_pages,
+            model_segments,
+            model_max_error,
+            page_format,
+            codec,
+            level_pages,
+        };
+        let expected_pages = match footer.page_format {
+            PageFormat::Plain => footer.entries.div_ceil(PAIRS_PER_PAGE as u64),
+            // a compressed page holds at least one pair
+            PageFormat::Compressed => footer.data_pages.min(footer.entries),
+        };
+        if footer.data_pages != expected_pages {
+            return Err(Error::invalid_format(
+                file_name,
+                "entry count doesn't match the number of pages",
+            ));
+        }
+        // only plain pages are encoded, and without a codec they're stored as they are
+        let codec_fits = match footer.codec {
+            CodecKind::None => footer.stored_pages == footer.data_pages,
+            _ => footer.page_format == PageFormat::Plain,
+        };
+        if !codec_fits {
+            return Err(Error::invalid_format(
+                file_name,
+                "codec doesn't match the pages",
+            ));
+        }
+        Ok(footer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_footer_round_trip() {
+        let footer = Footer {
+            height: 2,
+            entries: 300,
+            min_key: -5,
+            max_key: 1000,
+            data_pages: 2,
+            stored_pages: 2,
+            model_segments: 1,
+            model_max_error: 16,
+            page_format: PageFormat::Compressed,
+            codec: CodecKind::None,
+            level_pages: [1, 1].into_iter().collect(),
+        };
+        let buffer = footer.to_buffer();
+        assert_eq!(&buffer.as_slice_u8()[..8], b"LSMBTREE");
+        assert_eq!(Footer::from_buffer(&buffer, "test").unwrap(), footer);
+        assert!(Footer::from_buffer(&Buffer::new(), "test").is_err());
+        let footer = Footer {
+            page_format: PageFormat::Plain,
+            codec: CodecKind::FrameOfReference,
+            stored_pages: 1,
+            ..footer
+        };
+        assert_eq!(
+            Footer::from_buffer(&footer.to_buffer(), "test").unwrap(),
+            footer
+        );
+        let footer = Footer {
+            page_format: PageFormat::Compressed,
+            ..footer
+        };
+        assert!(Footer::from_buffer(&footer.to_buffer(), "test").is_err());
+    }
+}
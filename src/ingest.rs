@@ -0,0 +1,144 @@
+use crate::b_tree::BTreeWriter;
+use crate::buffer::{DELETED, TOMBSTONE};
+use crate::cache_trait::{NoCache, PageCache};
+use crate::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// why a sorted file couldn't be built or ingested.
+#[derive(Debug)]
+pub enum IngestError {
+    /// the files couldn't be opened as a BTree
+    Invalid(Error),
+    /// keys have to be strictly increasing
+    OutOfOrder {
+        file_name: String,
+        previous_key: i64,
+        key: i64,
+    },
+    /// `TOMBSTONE` can't be used as a key and `DELETED` can't be used as a value
+    ReservedKeyOrValue { file_name: String, key: i64 },
+    /// there is nothing to ingest
+    Empty { file_name: String },
+}
+
+impl Display for IngestError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestError::Invalid(error) => error.fmt(f),
+            IngestError::OutOfOrder {
+                file_name,
+                previous_key,
+                key,
+            } => write!(
+                f,
+                "{}: key {} comes after key {} but isn't larger",
+                file_name, key, previous_key
+            ),
+            IngestError::ReservedKeyOrValue { file_name, key } => {
+                write!(f, "{}: key {} uses a reserved key or value", file_name, key)
+            }
+            IngestError::Empty { file_name } => write!(f, "{}: has no entries", file_name),
+        }
+    }
+}
+
+impl std::error::Error for IngestError {}
+
+/// a set of BTree files built outside of a database, ready for `Database::ingest_files`.
+#[derive(Debug, Clone)]
+pub struct SortedFile {
+    pub(crate) file_name_prefix: String,
+}
+
+/// builds a standalone BTree from pairs given in strictly increasing key order.
+/// The files can be written anywhere on the same file system as the database and are moved into
+/// it by `Database::ingest_files`.
+pub struct SortedFileBuilder {
+    file_name_prefix: String,
+    writer: BTreeWriter,
+    last_key: Option<i64>,
+}
+
+impl SortedFileBuilder {
+    pub fn new(file_name_prefix: String) -> SortedFileBuilder {
+        SortedFileBuilder {
+            writer: BTreeWriter::new(file_name_prefix.clone()),
+            file_name_prefix,
+            last_key: None,
+        }
+    }
+
+    pub fn add(&mut self, key: i64, value: i64) -> Result<(), IngestError> {
+        check_pair(&self.file_name_prefix, self.last_key, (key, value))?;
+        self.last_key = Some(key);
+        // the files aren't part of a database yet so there's no cache to warm up
+        self.writer
+            .add_item((key, value), &PageCache::<NoCache>::default());
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<SortedFile, IngestError> {
+        self.writer.finish(&PageCache::<NoCache>::default());
+        if self.last_key.is_none() {
+            return Err(IngestError::Empty {
+                file_name: self.file_name_prefix,
+            });
+        }
+        Ok(SortedFile {
+            file_name_prefix: self.file_name_prefix,
+        })
+    }
+}
+
+/// checks that `pair` can follow a pair with key `previous_key` in a sorted file.
+pub(crate) fn check_pair(
+    file_name: &str,
+    previous_key: Option<i64>,
+    pair: (i64, i64),
+) -> Result<(), IngestError> {
+    if pair.0 == TOMBSTONE || pair.1 == DELETED {
+        return Err(IngestError::ReservedKeyOrValue {
+            file_name: file_name.to_string(),
+            key: pair.0,
+        });
+    }
+    match previous_key {
+        Some(previous_key) if previous_key >= pair.0 => Err(IngestError::OutOfOrder {
+            file_name: file_name.to_string(),
+            previous_key,
+            key: pair.0,
+        }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_builder_rejects_unsorted_keys() {
+        let _ = fs::remove_dir_all("testing_builder");
+        fs::create_dir_all("testing_builder").unwrap();
+        let mut builder = SortedFileBuilder::new("testing_builder/file".to_string());
+        builder.add(1, 1).unwrap();
+        builder.add(5, 1).unwrap();
+        assert!(matches!(
+            builder.add(5, 2),
+            Err(IngestError::OutOfOrder {
+                previous_key: 5,
+                key: 5,
+                ..
+            })
+        ));
+        assert!(matches!(
+            builder.add(6, DELETED),
+            Err(IngestError::ReservedKeyOrValue { .. })
+        ));
+        builder.add(6, 6).unwrap();
+        let file = builder.finish().unwrap();
+        assert_eq!(file.file_name_prefix, "testing_builder/file");
+        fs::remove_dir_all("testing_builder").unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use crate::buffer::{Buffer, KEYS_PER_PAGE};
+use crate::error::Result;
+use crate::write_and_read::{Reader, Writer};
+use std::ops::Range;
+
+/// the number of i64s a segment takes up on disk
+const SEGMENT_SIZE_I64: usize = 3;
+const SEGMENTS_PER_PAGE: usize = KEYS_PER_PAGE / SEGMENT_SIZE_I64;
+
+/// a line predicting the position of a key in the bottom level, used from `first_key` up to the
+/// `first_key` of the next segment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub first_key: i64,
+    /// the position of `first_key` in the bottom level, counted in pairs
+    pub first_position: u64,
+    pub slope: f64,
+}
+
+impl Segment {
+    fn predict(&self, key: i64) -> f64 {
+        self.first_position as f64 + self.slope * (key as i128 - self.first_key as i128) as f64
+    }
+}
+
+/// Builds a piecewise linear model of key -> position as the keys are streamed in, such that every
+/// key added is predicted within `max_error` positions of where it actually is.
+///
+/// Each segment keeps the range of slopes that still fit all of its points (the "cone" from its
+/// first point). A point that doesn't fit in the cone starts a new segment, so the whole thing is
+/// a single pass with constant memory per segment.
+pub struct ModelBuilder {
+    max_error: u64,
+    segments: Vec<Segment>,
+    /// the first point of the current segment
+    start: Option<(i64, u64)>,
+    min_slope: f64,
+    max_slope: f64,
+}
+
+impl ModelBuilder {
+    pub fn new(max_error: u64) -> ModelBuilder {
+        ModelBuilder {
+            max_error,
+            segments: Vec::new(),
+            start: None,
+            min_slope: 0.0,
+            max_slope: f64::INFINITY,
+        }
+    }
+
+    /// adds the next key, keys have to be added in increasing order with increasing positions.
+    pub fn add(&mut self, key: i64, position: u64) {
+        let Some((first_key, first_position)) = self.start else {
+            self.start = Some((key, position));
+            return;
+        };
+        let dx = (key as i128 - first_key as i128) as f64;
+        let dy = (position - first_position) as f64;
+        let error = self.max_error as f64;
+        let (min_slope, max_slope) = if dx == 0.0 {
+            // a repeated key can only be covered by the window around the first position
+            if dy <= error {
+                (self.min_slope, self.max_slope)
+            } else {
+                (f64::INFINITY, 0.0)
+            }
+        } else {
+            (
+                self.min_slope.max((dy - error) / dx),
+                self.max_slope.min((dy + error) / dx),
+            )
+        };
+        if min_slope <= max_slope {
+            self.min_slope = min_slope;
+            self.max_slope = max_slope;
+        } else {
+            self.end_segment();
+            self.start = Some((key, position));
+        }
+    }
+
+    fn end_segment(&mut self) {
+        if let Some((first_key, first_position)) = self.start.take() {
+            let slope = if self.max_slope.is_finite() {
+                (self.min_slope + self.max_slope) / 2.0
+            } else {
+                // only one point, any slope works
+                0.0
+            };
+            self.segments.push(Segment {
+                first_key,
+                first_position,
+                slope,
+            });
+        }
+        self.min_slope = 0.0;
+        self.max_slope = f64::INFINITY;
+    }
+
+    pub fn finish(mut self) -> LearnedIndex {
+        self.end_segment();
+        LearnedIndex {
+            segments: self.segments,
+            max_error: self.max_error,
+        }
+    }
+}
+
+/// the model for one BTree, kept in memory. See `ModelBuilder`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LearnedIndex {
+    segments: Vec<Segment>,
+    max_error: u64,
+}
+
+impl LearnedIndex {
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn max_error(&self) -> u64 {
+        self.max_error
+    }
+
+    /// the positions in a bottom level of `entries` pairs that could hold `key`.
+    /// If the key is in the BTree it is in the range, if it isn't the range holds the position it
+    /// would be inserted at (or its neighbour), as long as `key` is between the smallest and
+    /// largest key of the BTree.
+    pub fn predict(&self, key: i64, entries: u64) -> Range<u64> {
+        debug_assert!(entries > 0);
+        let index = self
+            .segments
+            .partition_point(|segment| segment.first_key <= key)
+            .max(1)
+            - 1;
+        let segment = &self.segments[index];
+        // keys between two segments are predicted past the end of the first one, keep them
+        // between the two segments
+        let last_position = self
+            .segments
+            .get(index + 1)
+            .map_or(entries - 1, |next| next.first_position);
+        let prediction = segment
+            .predict(key)
+            .round()
+            .clamp(segment.first_position as f64, last_position as f64)
+            as u64;
+        // one more position of slack for the rounding of the floats
+        let error = self.max_error + 1;
+        prediction.saturating_sub(error)..(prediction + error + 1).min(entries)
+    }
+
+    /// writes the segments to a file of their own, returns the number of pages written.
+    pub fn write(&self, file_name: String) -> u64 {
+        let mut writer = Writer::new(file_name);
+        let mut pages = 0;
+        for chunk in self.segments.chunks(SEGMENTS_PER_PAGE) {
+            let mut buffer = Buffer::new();
+            for (i, segment) in chunk.iter().enumerate() {
+                buffer.set_key(i * SEGMENT_SIZE_I64, segment.first_key);
+                buffer.set_key(i * SEGMENT_SIZE_I64 + 1, segment.first_position as i64);
+                buffer.set_key(i * SEGMENT_SIZE_I64 + 2, segment.slope.to_bits() as i64);
+            }
+            writer.write_page(&mut buffer);
+            pages += 1;
+        }
+        pages
+    }
+
+    pub fn read(file_name: &str, segments: u64, max_error: u64) -> Result<LearnedIndex> {
+        let reader = Reader::open(file_name)?;
+        let mut buffer = Buffer::new();
+        let mut result = Vec::with_capacity(segments as usize);
+        let mut page = 0;
+        while (result.len() as u64) < segments {
+            reader.read_page(&mut buffer, page)?;
+            let in_page = (segments as usize - result.len()).min(SEGMENTS_PER_PAGE);
+            result.extend((0..in_page).map(|i| Segment {
+                first_key: buffer.key(i * SEGMENT_SIZE_I64),
+                first_position: buffer.key(i * SEGMENT_SIZE_I64 + 1) as u64,
+                slope: f64::from_bits(buffer.key(i * SEGMENT_SIZE_I64 + 2) as u64),
+            }));
+            page += 1;
+        }
+        Ok(LearnedIndex {
+            segments: result,
+            max_error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(keys: &[i64], max_error: u64) -> LearnedIndex {
+        let mut builder = ModelBuilder::new(max_error);
+        for (position, key) in keys.iter().enumerate() {
+            builder.add(*key, position as u64);
+        }
+        builder.finish()
+    }
+
+    #[test]
+    fn test_dense_keys_use_one_segment() {
+        let keys: Vec<i64> = (1000..100_000).collect();
+        let model = build(&keys, 8);
+        assert_eq!(model.segments().len(), 1);
+        for (position, key) in keys.iter().enumerate() {
+            assert!(model
+                .predict(*key, keys.len() as u64)
+                .contains(&(position as u64)));
+        }
+    }
+
+    #[test]
+    fn test_error_is_bounded() {
+        // gaps that grow and shrink, with a few big jumps
+        let mut keys = Vec::new();
+        let mut key = -1_000_000;
+        for i in 0..50_000_i64 {
+            key += 1 + (i * 7919) % 13 + if i % 5000 == 0 { 1 << 40 } else { 0 };
+            keys.push(key);
+        }
+        let entries = keys.len() as u64;
+        let model = build(&keys, 16);
+        assert!(model.segments().len() > 1);
+        for (position, key) in keys.iter().enumerate() {
+            let range = model.predict(*key, entries);
+            assert!(range.contains(&(position as u64)));
+            assert!(range.end - range.start <= 2 * 17 + 1);
+        }
+        // a missing key lands next to where it would be inserted
+        for position in 1..keys.len() {
+            if keys[position] - keys[position - 1] > 1 {
+                let range = model.predict(keys[position] - 1, entries);
+                assert!(
+                    range.contains(&(position as u64)) || range.contains(&(position as u64 - 1))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let _ = std::fs::remove_file("test_learned_index");
+        let keys: Vec<i64> = (0..2000_i64)
+            .scan(0, |key, i| {
+                *key += 1 + i * 7919 % 1000;
+                Some(*key)
+            })
+            .collect();
+        let model = build(&keys, 1);
+        assert!(model.segments().len() > SEGMENTS_PER_PAGE);
+        let pages = model.write("test_learned_index".to_string());
+        assert_eq!(
+            pages,
+            model.segments().len().div_ceil(SEGMENTS_PER_PAGE) as u64
+        );
+        let read = LearnedIndex::read("test_learned_index", model.segments().len() as u64, 1);
+        assert_eq!(read.unwrap(), model);
+        std::fs::remove_file("test_learned_index").unwrap();
+    }
+}
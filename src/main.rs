@@ -13,14 +13,29 @@ extern crate static_assertions;
 extern crate rand;
 
 // pub mod memtablev2;
+pub mod arena_tree;
 pub mod avl_tree;
 pub mod b_tree;
 pub mod bloom_filter;
 pub mod buffer;
 pub mod cache;
 pub mod cache_trait;
+pub mod codec;
 pub mod compaction;
+mod compressed_page;
+pub mod concurrent_skip_list;
 pub mod database;
+pub mod error;
+pub mod footer;
+pub mod ingest;
+mod learned_index;
+pub mod mem_table;
+pub mod options;
+pub mod skip_list;
+mod skip_list_levels;
+pub mod sorted_vec;
+pub mod wal;
 pub mod write_and_read;
+pub mod write_buffer;
 
 fn main() {}
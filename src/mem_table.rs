@@ -0,0 +1,134 @@
+use crate::arena_tree::{ArenaTree, ARENA_NODE_SIZE};
+use crate::avl_tree::{MemoryTable, NODE_SIZE};
+use crate::concurrent_skip_list::{ConcurrentSkipList, AVERAGE_CONCURRENT_NODE_SIZE};
+use crate::skip_list::{SkipList, AVERAGE_NODE_SIZE};
+use crate::sorted_vec::{SortedVec, PAIR_SIZE};
+use serde::{Deserialize, Serialize};
+
+/// The in memory part of the database, newer than anything on disk.
+/// Every write goes here first and the whole table is written out as a BTree once it's full.
+/// The database shares it between the threads reading and the one writing.
+pub trait MemTable: Send + Sync {
+    /// inserts or overwrites `key`. Returns false if the memtable doesn't have room for it, then
+    /// nothing was inserted and it should be flushed.
+    fn insert(&mut self, key: i64, value: i64) -> bool;
+    /// like `insert`, for memtables that take inserts from many threads at once.
+    /// The others return None and have to be given exclusive access.
+    fn insert_concurrently(&self, _key: i64, _value: i64) -> Option<bool> {
+        None
+    }
+    fn get(&self, key: i64) -> Option<i64>;
+    /// the memory taken by the entries, including the nodes and links holding them together.
+    /// Never more than the capacity the memtable was created with.
+    fn size_in_bytes(&self) -> usize;
+    /// every pair with a key in `lower_bound..=upper_bound`, in order. A flush scans the whole
+    /// table through this, so it should not copy the pairs.
+    fn scan(&self, lower_bound: i64, upper_bound: i64)
+        -> Box<dyn Iterator<Item = (i64, i64)> + '_>;
+}
+
+/// which `MemTable` the database buffers writes in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum MemTableKind {
+    /// a balanced binary tree, see `MemoryTable`. Like every kind but `ConcurrentSkipList`, it
+    /// takes the write lock of the memtable for each insert, so reads of the memtable wait for
+    /// inserts (though never for a flush or a compaction).
+    #[default]
+    Avl,
+    /// see `ArenaTree`, the same tree without an allocation per entry
+    Arena,
+    /// see `SkipList`, cheaper inserts than the tree for random keys
+    SkipList,
+    /// see `SortedVec`, for bulk writes that mostly come in increasing order
+    Vector,
+    /// see `ConcurrentSkipList`, for several threads writing at once. With a write ahead log the
+    /// inserts take turns with the appends to the log, so the log keeps the order of the writes.
+    ConcurrentSkipList,
+}
+
+impl MemTableKind {
+    /// an empty memtable whose entries take up to `capacity` bytes
+    pub fn create(self, capacity: usize) -> Box<dyn MemTable> {
+        match self {
+            MemTableKind::Avl => Box::new(MemoryTable::new(capacity)),
+            MemTableKind::Arena => Box::new(ArenaTree::new(capacity)),
+            MemTableKind::SkipList => Box::new(SkipList::new(capacity)),
+            MemTableKind::Vector => Box::new(SortedVec::new(capacity)),
+            MemTableKind::ConcurrentSkipList => Box::new(ConcurrentSkipList::new(capacity)),
+        }
+    }
+
+    /// about how many bytes an entry takes up, to size a memtable by its number of entries
+    pub fn entry_size(self) -> usize {
+        match self {
+            MemTableKind::Avl => NODE_SIZE,
+            MemTableKind::Arena => ARENA_NODE_SIZE,
+            MemTableKind::SkipList => AVERAGE_NODE_SIZE,
+            MemTableKind::Vector => PAIR_SIZE,
+            MemTableKind::ConcurrentSkipList => AVERAGE_CONCURRENT_NODE_SIZE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use itertools::assert_equal;
+
+    const KINDS: [MemTableKind; 5] = [
+        MemTableKind::Avl,
+        MemTableKind::Arena,
+        MemTableKind::SkipList,
+        MemTableKind::Vector,
+        MemTableKind::ConcurrentSkipList,
+    ];
+
+    #[test]
+    fn test_all_kinds() {
+        for kind in KINDS {
+            let capacity = 4000 * kind.entry_size();
+            let mut mem_table = kind.create(capacity);
+            // increasing, then decreasing, then overwriting
+            for i in 0..1000 {
+                assert!(mem_table.insert(i * 2, i));
+            }
+            for i in (0..1000).rev() {
+                assert!(mem_table.insert(i * 2 + 1, -i));
+            }
+            for i in 0..500 {
+                assert!(mem_table.insert(i * 4, 7));
+            }
+            let expected = |key: i64| match key {
+                key if key % 2 == 1 => -(key / 2),
+                key if key % 4 == 0 && key < 2000 => 7,
+                key => key / 2,
+            };
+            for key in 0..2000 {
+                assert_eq!(mem_table.get(key), Some(expected(key)), "{:?}", kind);
+            }
+            assert_eq!(mem_table.get(-1), None);
+            assert_eq!(mem_table.get(2000), None);
+            assert_equal(
+                mem_table.scan(10, 20),
+                (10..=20).map(|key| (key, expected(key))),
+            );
+            assert_equal(mem_table.scan(1999, 5000), [(1999, -999)]);
+            assert_eq!(mem_table.scan(3000, 4000).count(), 0);
+            // fill it up with new keys, overwrites always fit
+            let mut key = 2000;
+            while mem_table.insert(key, key) {
+                key += 1;
+            }
+            assert!(mem_table.size_in_bytes() <= capacity);
+            assert!(mem_table.size_in_bytes() > capacity - 100 * kind.entry_size());
+            assert!(mem_table.insert(0, 0));
+            assert_equal(
+                mem_table.scan(i64::MIN, i64::MAX),
+                (0..2000)
+                    .map(|key| (key, if key == 0 { 0 } else { expected(key) }))
+                    .chain((2000..key).map(|key| (key, key))),
+            );
+            assert_eq!(kind.create(10).scan(i64::MIN, i64::MAX).count(), 0);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use crate::codec::CodecKind;
+use crate::mem_table::MemTableKind;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Tunables for a `Database`. They are stored in the metadata file so a database reopens with
+/// the same settings it was created with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseOptions {
+    /// the memory the entries of the memtable can take up, in bytes, before it is flushed to
+    /// level 0. Overwriting a key in the memtable takes no more room.
+    pub max_mem_table_bytes: usize,
+    #[serde(default)]
+    pub mem_table: MemTableKind,
+    /// a level is compacted all the way down to the bottom once more than this fraction of its
+    /// entries are tombstones
+    pub tombstone_density_threshold: f64,
+    /// if set, every delete is physically persisted (the data it covers is purged from disk)
+    /// within roughly this much time of being issued.
+    /// The deadline is checked whenever the memtable is flushed and by
+    /// `Database::compact_tombstones`, so an idle database should call that periodically.
+    pub tombstone_deadline: Option<Duration>,
+    #[serde(default)]
+    pub compaction_style: CompactionStyle,
+    /// how many pairs `Database::bulk_load` sorts in memory at a time
+    #[serde(default = "default_bulk_load_run_size")]
+    pub bulk_load_run_size: usize,
+    #[serde(default)]
+    pub index: IndexKind,
+    /// how the pages of newly written BTrees are laid out, existing ones keep their format
+    #[serde(default)]
+    pub page_format: PageFormat,
+    /// the codec for the pages of items of the BTrees above `deep_level`, which are rewritten
+    /// often so a cheap one (or none) pays off. Codecs only apply to plain pages.
+    #[serde(default)]
+    pub shallow_codec: CodecKind,
+    /// the codec for the pages of items of the BTrees in `deep_level` and below, where most of
+    /// the data ends up
+    #[serde(default)]
+    pub deep_codec: CodecKind,
+    /// the first level counted as deep. With universal compaction the runs count as levels,
+    /// newest first.
+    #[serde(default = "default_deep_level")]
+    pub deep_level: usize,
+    /// if set, every write is appended to a write ahead log before it returns, so the memtable
+    /// survives a crash. The log is replayed when the database is opened. If the log can't be
+    /// written, the write fails though readers may already see it, and so do all later writes.
+    #[serde(default)]
+    pub wal: Option<SyncMode>,
+    /// keep the upper levels of every BTree in the reserved part of the page cache, so a lookup
+    /// reads at most one page from disk per level it checks. BTrees that don't fit are cached
+    /// like the rest.
+    ///
+    /// Filter pages are not pinned: a lookup skips a BTree by the key range in its footer and
+    /// never reads a filter page, as no BTree is written with a bloom filter. Pinning them is
+    /// left for when lookups use them.
+    #[serde(default)]
+    pub pin_upper_levels: bool,
+    /// if set, `Database::get` first looks in a cache of the last this many keys looked up and
+    /// what they were found to be, absent keys included
+    #[serde(default)]
+    pub row_cache_rows: Option<usize>,
+}
+
+fn default_deep_level() -> usize {
+    2
+}
+
+fn default_bulk_load_run_size() -> usize {
+    1 << 20
+}
+
+/// how flushed memtables are merged together on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum CompactionStyle {
+    /// level `i` holds at most one BTree of roughly `2^i` memtables and a flush cascades down
+    /// through the full levels like a binary counter. Cheap reads, more write amplification.
+    #[default]
+    Leveled,
+    /// every flush adds a new sorted run and only runs of similar size are merged together.
+    /// Writes each entry far fewer times at the cost of more runs to check on a read.
+    Universal {
+        /// a run is merged with the newer runs before it when it is at most this many percent
+        /// larger than all of them combined
+        size_ratio_percent: u64,
+        /// all runs are merged into one once the newer runs take up more than this many percent
+        /// of the size of the oldest run
+        max_size_amplification_percent: u64,
+        /// the newest runs are merged whenever there are more runs than this
+        max_runs: usize,
+    },
+}
+
+/// when the write ahead log is forced to disk. Writes that come in together share one append
+/// and one sync, done by whichever of them gets there first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SyncMode {
+    /// every write waits for its record to be synced, so nothing acknowledged is ever lost
+    EveryWrite,
+    /// the log is synced every `millis` milliseconds by a thread of its own, as long as there
+    /// are writes it hasn't synced, and once more when the log is closed. Writes still wait for
+    /// their records to be written, but a crash of the machine can lose the writes of the last
+    /// `millis` milliseconds.
+    Interval { millis: u64 },
+    /// the log is written but left to the OS to sync, so it only survives a crash of the process
+    Never,
+}
+
+/// how a lookup finds the page of the bottom level of a BTree that could hold a key.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum IndexKind {
+    /// descend the upper levels of the BTree one page at a time, through the cache
+    #[default]
+    BTree,
+    /// keep the largest key of every bottom page in memory, loaded from the lowest upper level
+    /// when the BTree is opened. Costs 8 bytes per page of data but any lookup reads a single page.
+    Fences,
+    /// a piecewise linear model from keys to positions in the bottom level, built as the BTree
+    /// is written. It predicts where a key is to within `max_error` pairs, so for dense keys it
+    /// replaces the upper levels with a handful of segments. BTrees written without a model
+    /// (like ingested files) fall back to their upper levels.
+    Learned { max_error: u64 },
+}
+
+/// the layout of the pages of a BTree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PageFormat {
+    /// fixed size keys and pairs, a page holds 511 keys or 255 pairs
+    #[default]
+    Plain,
+    /// keys delta encoded and everything varint encoded, see `CompressedPageBuilder`.
+    /// Dense increasing keys with small values fit several times more entries in a page, at the
+    /// cost of decoding on every read.
+    Compressed,
+}
+
+impl IndexKind {
+    /// a model whose error window is at most two pages wide
+    pub fn learned() -> IndexKind {
+        IndexKind::Learned { max_error: 32 }
+    }
+}
+
+impl DatabaseOptions {
+    /// the codec for a BTree written at `level`
+    pub fn codec_for_level(&self, level: usize) -> CodecKind {
+        if level < self.deep_level {
+            self.shallow_codec
+        } else {
+            self.deep_codec
+        }
+    }
+}
+
+impl CompactionStyle {
+    pub fn universal() -> CompactionStyle {
+        CompactionStyle::Universal {
+            size_ratio_percent: 1,
+            max_size_amplification_percent: 200,
+            max_runs: 8,
+        }
+    }
+}
+
+impl Default for DatabaseOptions {
+    fn default() -> Self {
+        DatabaseOptions {
+            max_mem_table_bytes: 4 << 20,
+            mem_table: MemTableKind::Avl,
+            tombstone_density_threshold: 0.5,
+            tombstone_deadline: None,
+            compaction_style: CompactionStyle::Leveled,
+            bulk_load_run_size: default_bulk_load_run_size(),
+            index: IndexKind::BTree,
+            page_format: PageFormat::Plain,
+            shallow_codec: CodecKind::None,
+            deep_codec: CodecKind::None,
+            deep_level: default_deep_level(),
+            wal: None,
+            pin_upper_levels: false,
+            row_cache_rows: None,
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use crate::mem_table::MemTable;
+use crate::skip_list_levels::{average_links_size, random_height, HEAD, MAX_HEIGHT, NIL};
+
+/// the bytes a node takes up on average
+pub const AVERAGE_NODE_SIZE: usize =
+    std::mem::size_of::<SkipNode>() + average_links_size(std::mem::size_of::<usize>());
+
+struct SkipNode {
+    key: i64,
+    value: i64,
+    /// the next node on each level this node is linked into
+    next: Vec<usize>,
+}
+
+/// A memtable kept as a skip list: a sorted linked list where each node is also linked into a
+/// random number of express lanes above it, a quarter as many nodes per lane.
+/// Inserts and lookups take O(log n) expected steps without any rebalancing.
+///
+/// The nodes live in a `Vec` and link to each other by index, the head being node 0.
+pub struct SkipList {
+    /// the most bytes the nodes can take up
+    capacity: usize,
+    /// the bytes taken by the nodes holding entries
+    size: usize,
+    nodes: Vec<SkipNode>,
+    /// the number of levels in use
+    height: usize,
+}
+
+impl SkipList {
+    pub fn new(capacity: usize) -> SkipList {
+        SkipList {
+            capacity,
+            size: 0,
+            nodes: vec![SkipNode {
+                key: i64::MIN,
+                value: 0,
+                next: vec![NIL; MAX_HEIGHT],
+            }],
+            height: 1,
+        }
+    }
+
+    /// the bytes taken by a node linked into `height` levels
+    fn node_size(height: usize) -> usize {
+        std::mem::size_of::<SkipNode>() + height * std::mem::size_of::<usize>()
+    }
+
+    /// for every level in use, the last node with a key smaller than `key`
+    fn predecessors(&self, key: i64) -> [usize; MAX_HEIGHT] {
+        let mut predecessors = [HEAD; MAX_HEIGHT];
+        let mut node = HEAD;
+        for level in (0..self.height).rev() {
+            loop {
+                let next = self.nodes[node].next[level];
+                if next == NIL || self.nodes[next].key >= key {
+                    break;
+                }
+                node = next;
+            }
+            predecessors[level] = node;
+        }
+        predecessors
+    }
+
+    /// the first node with a key at least `key`
+    fn lower_bound(&self, key: i64) -> usize {
+        self.nodes[self.predecessors(key)[0]].next[0]
+    }
+}
+
+impl MemTable for SkipList {
+    fn insert(&mut self, key: i64, value: i64) -> bool {
+        let predecessors = self.predecessors(key);
+        let next = self.nodes[predecessors[0]].next[0];
+        if next != NIL && self.nodes[next].key == key {
+            self.nodes[next].value = value;
+            return true;
+        }
+        let height = random_height();
+        if self.size + Self::node_size(height) > self.capacity {
+            return false;
+        }
+        self.size += Self::node_size(height);
+        // new levels start out with only the head
+        self.height = self.height.max(height);
+        let index = self.nodes.len();
+        let next = (0..height)
+            .map(|level| {
+                let next = self.nodes[predecessors[level]].next[level];
+                self.nodes[predecessors[level]].next[level] = index;
+                next
+            })
+            .collect();
+        self.nodes.push(SkipNode { key, value, next });
+        true
+    }
+
+    fn get(&self, key: i64) -> Option<i64> {
+        let node = self.lower_bound(key);
+        (node != NIL && self.nodes[node].key == key).then(|| self.nodes[node].value)
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        self.size
+    }
+
+    fn scan(
+        &self,
+        lower_bound: i64,
+        upper_bound: i64,
+    ) -> Box<dyn Iterator<Item = (i64, i64)> + '_> {
+        let mut node = self.lower_bound(lower_bound);
+        Box::new(std::iter::from_fn(move || {
+            let current = &self.nodes[node];
+            if node == NIL || current.key > upper_bound {
+                return None;
+            }
+            node = current.next[0];
+            Some((current.key, current.value))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_keys() {
+        let mut skip_list = SkipList::new(100_000 * AVERAGE_NODE_SIZE);
+        let keys: Vec<i64> = (0..50_000).map(|i| (i * 7919) % 50_000 - 25_000).collect();
+        for key in keys.iter() {
+            assert!(skip_list.insert(*key, key * 2));
+        }
+        assert!(skip_list.height > 3);
+        assert_eq!(skip_list.nodes.len(), 50_001);
+        let size: usize = skip_list
+            .nodes
+            .iter()
+            .skip(1)
+            .map(|node| SkipList::node_size(node.next.len()))
+            .sum();
+        assert_eq!(skip_list.size_in_bytes(), size);
+        for key in -25_000..25_000 {
+            assert_eq!(skip_list.get(key), Some(key * 2));
+        }
+        assert_eq!(skip_list.get(i64::MIN), None);
+        assert!(skip_list
+            .scan(i64::MIN, i64::MAX)
+            .eq((-25_000..25_000).map(|key| (key, key * 2))));
+    }
+}
@@ -0,0 +1,22 @@
+/// the most levels a node can be linked into, plenty for 4^12 entries
+pub(crate) const MAX_HEIGHT: usize = 12;
+/// where the head of a list is, in the storage of its nodes
+pub(crate) const HEAD: usize = 0;
+/// the head is never the next node of anything, so its place doubles as the end of a list
+pub(crate) const NIL: usize = HEAD;
+
+/// the number of levels a new node is linked into. Each level above the bottom one holds a
+/// quarter as many nodes as the one below it.
+pub(crate) fn random_height() -> usize {
+    let mut height = 1;
+    while height < MAX_HEIGHT && rand::random::<u32>().is_multiple_of(4) {
+        height += 1;
+    }
+    height
+}
+
+/// the bytes the links of a node take up on average, for links of `link_size` bytes: with a
+/// quarter as many nodes per level, a node has 4/3 links
+pub(crate) const fn average_links_size(link_size: usize) -> usize {
+    link_size * 4 / 3
+}
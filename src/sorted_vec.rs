@@ -0,0 +1,145 @@
+use crate::mem_table::MemTable;
+use itertools::Itertools;
+
+/// the most pairs kept out of order before they are sorted into the rest
+const MAX_UNSORTED: usize = 1024;
+/// the bytes every entry of a `SortedVec` takes up
+pub const PAIR_SIZE: usize = std::mem::size_of::<(i64, i64)>();
+
+/// A memtable for bulk writes: pairs are appended to a `Vec`, which is as cheap as an insert
+/// gets. While the keys keep increasing the `Vec` stays sorted and a lookup is a binary search.
+///
+/// Pairs that arrive out of order go to the unsorted tail, which is searched linearly and sorted
+/// into the rest once it holds `MAX_UNSORTED` pairs. So random keys still work, just slower than
+/// with the other memtables.
+pub struct SortedVec {
+    /// the most bytes the pairs can take up
+    capacity: usize,
+    /// sorted up to `sorted`, then in the order they were inserted. Every key is there once.
+    pairs: Vec<(i64, i64)>,
+    sorted: usize,
+}
+
+impl SortedVec {
+    pub fn new(capacity: usize) -> SortedVec {
+        SortedVec {
+            capacity,
+            pairs: Vec::new(),
+            sorted: 0,
+        }
+    }
+
+    /// sorts the unsorted tail into the rest of the pairs
+    fn sort(&mut self) {
+        // quick on the sorted run at the front
+        self.pairs.sort_by_key(|pair| pair.0);
+        self.sorted = self.pairs.len();
+    }
+
+    /// the index of the pair with `key`
+    fn find(&self, key: i64) -> Option<usize> {
+        if let Some(i) = self.pairs[self.sorted..]
+            .iter()
+            .position(|pair| pair.0 == key)
+        {
+            return Some(self.sorted + i);
+        }
+        self.pairs[..self.sorted]
+            .binary_search_by_key(&key, |pair| pair.0)
+            .ok()
+    }
+}
+
+impl MemTable for SortedVec {
+    fn insert(&mut self, key: i64, value: i64) -> bool {
+        // overwrites take no more room
+        if let Some(i) = self.find(key) {
+            self.pairs[i].1 = value;
+            return true;
+        }
+        if self.size_in_bytes() + PAIR_SIZE > self.capacity {
+            return false;
+        }
+        let in_order = self.pairs.last().is_none_or(|last| last.0 < key);
+        if self.sorted == self.pairs.len() && in_order {
+            self.sorted += 1;
+        }
+        self.pairs.push((key, value));
+        if self.pairs.len() - self.sorted >= MAX_UNSORTED {
+            self.sort();
+        }
+        true
+    }
+
+    fn get(&self, key: i64) -> Option<i64> {
+        self.find(key).map(|i| self.pairs[i].1)
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        self.pairs.len() * PAIR_SIZE
+    }
+
+    fn scan(
+        &self,
+        lower_bound: i64,
+        upper_bound: i64,
+    ) -> Box<dyn Iterator<Item = (i64, i64)> + '_> {
+        let sorted = &self.pairs[..self.sorted];
+        let start = sorted.partition_point(|pair| pair.0 < lower_bound);
+        let end = sorted.partition_point(|pair| pair.0 <= upper_bound);
+        if self.sorted == self.pairs.len() {
+            return Box::new(sorted[start..end].iter().copied());
+        }
+        // few enough pairs are out of order to sort the ones in range on the spot and merge
+        // them with the rest, which a flush scans without copying
+        let mut unsorted: Vec<(i64, i64)> = self.pairs[self.sorted..]
+            .iter()
+            .copied()
+            .filter(|pair| lower_bound <= pair.0 && pair.0 <= upper_bound)
+            .collect();
+        unsorted.sort_by_key(|pair| pair.0);
+        Box::new(
+            sorted[start..end]
+                .iter()
+                .copied()
+                .merge_by(unsorted, |sorted, unsorted| sorted.0 < unsorted.0),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sorted_appends() {
+        let mut sorted_vec = SortedVec::new(6100 * PAIR_SIZE);
+        for i in 0..5000 {
+            assert!(sorted_vec.insert(i * 2, i));
+        }
+        assert!(sorted_vec.insert(9998, -1));
+        assert_eq!(sorted_vec.sorted, 5000);
+        // out of order pairs wait in the tail until there are enough of them
+        for i in 0..MAX_UNSORTED as i64 - 1 {
+            assert!(sorted_vec.insert(i * 2 + 1, -i));
+        }
+        assert_eq!(sorted_vec.sorted, 5000);
+        assert_eq!(sorted_vec.get(3), Some(-1));
+        assert_eq!(sorted_vec.get(4), Some(2));
+        // overwrites stay where they are
+        assert!(sorted_vec.insert(3, 3));
+        assert!(sorted_vec.insert(4, 4));
+        assert_eq!(sorted_vec.pairs.len(), 5000 + MAX_UNSORTED - 1);
+        assert!(sorted_vec.insert(-1, -1));
+        assert_eq!(sorted_vec.sorted, sorted_vec.pairs.len());
+        assert_eq!(
+            sorted_vec.size_in_bytes(),
+            (5000 + MAX_UNSORTED) * PAIR_SIZE
+        );
+        assert_eq!(sorted_vec.get(-1), Some(-1));
+        assert_eq!(sorted_vec.get(3), Some(3));
+        assert_eq!(sorted_vec.get(4), Some(4));
+        assert_eq!(sorted_vec.get(9998), Some(-1));
+        assert!(sorted_vec.pairs.is_sorted_by_key(|pair| pair.0));
+    }
+}
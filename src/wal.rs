@@ -0,0 +1,376 @@
+use crate::options::SyncMode;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// a record is a key, a value and the CRC32C of both, padded to a whole number of words
+const RECORD_SIZE: usize = 24;
+
+/// A write ahead log of the pairs in a memtable, deleted once the memtable is flushed.
+///
+/// Writes are committed in groups: a writer appends its record to a shared buffer and then waits
+/// for it to be committed. The first writer to find no commit in progress becomes the leader and
+/// writes (and syncs) everything buffered so far in one go, while the writers arriving in the
+/// meantime buffer up the next group. So however many writers there are, there is at most one
+/// sync in flight and each sync covers every write that came in while the previous one ran.
+/// A leader only takes a group once the one before it is written, so whatever the `SyncMode`,
+/// the log holds the records in the order they were appended.
+///
+/// With `SyncMode::Interval` the leaders don't sync, a thread of the log does so every interval
+/// while there are records it hasn't synced, and once more when the log is dropped.
+///
+/// Once a write or a sync fails the log can't be trusted to hold what was committed, so every
+/// commit waiting on it and every later append fails too.
+pub struct Wal {
+    file_name: String,
+    log: Arc<Log>,
+    /// the thread syncing the log, with `SyncMode::Interval`
+    syncer: Option<JoinHandle<()>>,
+}
+
+/// the part of a `Wal` its syncing thread shares
+struct Log {
+    file: File,
+    sync_mode: SyncMode,
+    group: Mutex<Group>,
+    /// signalled whenever a group has been committed
+    committed: Condvar,
+    /// signalled when the log is dropped, to wake up the syncing thread
+    closed: Condvar,
+}
+
+struct Group {
+    /// the records of the group being buffered
+    records: Vec<u8>,
+    /// the number of the group being buffered, the groups before it have been handed to a leader
+    open: u64,
+    /// every group up to this one has been committed
+    committed: u64,
+    /// whether a leader is writing a group
+    writing: bool,
+    /// whether records were written without being synced, for the syncing thread to sync
+    unsynced: bool,
+    /// whether the log is being dropped
+    closed: bool,
+    /// what went wrong with the first write or sync that failed
+    failed: Option<io::ErrorKind>,
+}
+
+impl Wal {
+    pub fn create(file_name: String, sync_mode: SyncMode) -> io::Result<Wal> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&file_name)?;
+        Ok(Wal::with_file(file_name, file, sync_mode))
+    }
+
+    fn with_file(file_name: String, file: File, sync_mode: SyncMode) -> Wal {
+        let log = Arc::new(Log {
+            file,
+            sync_mode,
+            group: Mutex::new(Group {
+                records: Vec::new(),
+                open: 1,
+                committed: 0,
+                writing: false,
+                unsynced: false,
+                closed: false,
+                failed: None,
+            }),
+            committed: Condvar::new(),
+            closed: Condvar::new(),
+        });
+        let syncer = match sync_mode {
+            SyncMode::Interval { millis } => {
+                let log = log.clone();
+                Some(std::thread::spawn(move || {
+                    log.sync_every(Duration::from_millis(millis))
+                }))
+            }
+            SyncMode::EveryWrite | SyncMode::Never => None,
+        };
+        Wal {
+            file_name,
+            log,
+            syncer,
+        }
+    }
+
+    /// stores the pair with `store` and buffers a record of it, returns the group to commit for
+    /// it to be in the log. Nothing is buffered if `store` returns false.
+    ///
+    /// `store` runs under the lock of the buffer, so records are buffered (and written) in the
+    /// order the pairs were stored, and replaying the log leaves the last value stored for a key.
+    /// Fails without calling `store` if the log failed.
+    pub fn append(
+        &self,
+        key: i64,
+        value: i64,
+        store: impl FnOnce() -> bool,
+    ) -> io::Result<Option<u64>> {
+        let mut group = self.log.group.lock().unwrap();
+        if let Some(kind) = group.failed {
+            return Err(failed(kind));
+        }
+        if !store() {
+            return Ok(None);
+        }
+        let start = group.records.len();
+        group.records.extend_from_slice(&key.to_le_bytes());
+        group.records.extend_from_slice(&value.to_le_bytes());
+        let crc = crc32c::crc32c(&group.records[start..]);
+        group.records.extend_from_slice(&(crc as u64).to_le_bytes());
+        Ok(Some(group.open))
+    }
+
+    /// waits until the records of `group` are in the log (and synced, as the `SyncMode` asks),
+    /// committing them and whatever else is buffered if no one else is at it.
+    pub fn commit(&self, group_number: u64) -> io::Result<()> {
+        let log = &*self.log;
+        let mut group = log.group.lock().unwrap();
+        while group.writing && group.committed < group_number {
+            group = log.committed.wait(group).unwrap();
+        }
+        if group.committed >= group_number {
+            return Ok(());
+        }
+        if let Some(kind) = group.failed {
+            return Err(failed(kind));
+        }
+        group.writing = true;
+        let records = std::mem::take(&mut group.records);
+        let leading = group.open;
+        group.open += 1;
+        let sync = log.sync_mode == SyncMode::EveryWrite;
+        // the next group buffers up while this one is written
+        drop(group);
+        let written = (&log.file).write_all(&records).and_then(|()| {
+            if sync {
+                log.file.sync_data()
+            } else {
+                Ok(())
+            }
+        });
+        let mut group = log.group.lock().unwrap();
+        group.writing = false;
+        match &written {
+            Ok(()) => {
+                group.committed = leading;
+                group.unsynced |= !sync;
+            }
+            // the writers of this group and the ones buffered behind it find out when they wake
+            Err(error) => group.failed = Some(error.kind()),
+        }
+        log.committed.notify_all();
+        written
+    }
+
+    /// the pairs in the log at `file_name`, in the order they were written. A record torn by a
+    /// crash can only be at the end, where it and anything after it are ignored.
+    pub fn read(file_name: &str) -> std::io::Result<Vec<(i64, i64)>> {
+        let bytes = std::fs::read(file_name)?;
+        let word =
+            |record: &[u8], i: usize| u64::from_le_bytes(record[i..i + 8].try_into().unwrap());
+        Ok(bytes
+            .chunks_exact(RECORD_SIZE)
+            .take_while(|record| crc32c::crc32c(&record[..16]) as u64 == word(record, 16))
+            .map(|record| (word(record, 0) as i64, word(record, 8) as i64))
+            .collect())
+    }
+
+    pub fn delete(&self) -> io::Result<()> {
+        std::fs::remove_file(&self.file_name)
+    }
+}
+
+impl Log {
+    /// syncs the records written since the last sync every `interval`, and once more when the
+    /// log is closed. A sync that fails fails the log.
+    fn sync_every(&self, interval: Duration) {
+        let mut group = self.group.lock().unwrap();
+        loop {
+            let closed = group.closed;
+            if group.unsynced && group.failed.is_none() {
+                // records written from here on are left for the next sync
+                group.unsynced = false;
+                drop(group);
+                let synced = self.file.sync_data();
+                group = self.group.lock().unwrap();
+                if let Err(error) = synced {
+                    group.failed = Some(error.kind());
+                }
+            }
+            if closed {
+                return;
+            }
+            group = self.closed.wait_timeout(group, interval).unwrap().0;
+        }
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        if let Some(syncer) = self.syncer.take() {
+            self.log.group.lock().unwrap().closed = true;
+            self.log.closed.notify_all();
+            let _ = syncer.join();
+        }
+    }
+}
+
+/// the error of a commit or an append to a log whose write or sync failed earlier
+fn failed(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "an earlier write to the log failed")
+}
+
+/// the number of the log at `file_name`, if it's the name of a log
+pub fn log_number(file_name: &str) -> Option<u64> {
+    file_name
+        .strip_prefix("wal_")?
+        .strip_suffix(".log")?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_commit() {
+        let _ = std::fs::remove_file("test_wal_group");
+        let wal = Wal::create("test_wal_group".to_string(), SyncMode::EveryWrite).unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let wal = &wal;
+                scope.spawn(move || {
+                    for i in 0..100 {
+                        let group = wal.append(thread, i, || true).unwrap().unwrap();
+                        wal.commit(group).unwrap();
+                    }
+                });
+            }
+        });
+        let group = wal.log.group.lock().unwrap();
+        assert_eq!(group.committed, group.open - 1);
+        assert!(group.records.is_empty());
+        let mut pairs = Wal::read("test_wal_group").unwrap();
+        assert_eq!(pairs.len(), 800);
+        // each writer's records are in the order it wrote them
+        pairs.sort_by_key(|pair| pair.0);
+        for (thread, records) in pairs.chunks(100).enumerate() {
+            assert!(records.iter().map(|pair| pair.1).eq(0..100));
+            assert!(records.iter().all(|pair| pair.0 == thread as i64));
+        }
+        wal.delete().unwrap();
+    }
+
+    #[test]
+    fn test_append_order() {
+        for sync_mode in [SyncMode::EveryWrite, SyncMode::Interval { millis: 1 }] {
+            let _ = std::fs::remove_file("test_wal_order");
+            let wal = Wal::create("test_wal_order".to_string(), sync_mode).unwrap();
+            let stored = Mutex::new(Vec::new());
+            std::thread::scope(|scope| {
+                for thread in 0..8 {
+                    let (wal, stored) = (&wal, &stored);
+                    scope.spawn(move || {
+                        for i in 0..100 {
+                            let store = || {
+                                stored.lock().unwrap().push((i % 3, thread));
+                                true
+                            };
+                            let group = wal.append(i % 3, thread, store).unwrap().unwrap();
+                            wal.commit(group).unwrap();
+                        }
+                    });
+                }
+            });
+            // the groups are written one after the other, in the order the pairs were stored
+            assert_eq!(
+                Wal::read("test_wal_order").unwrap(),
+                stored.into_inner().unwrap()
+            );
+            wal.delete().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_interval_sync() {
+        let _ = std::fs::remove_file("test_wal_interval");
+        let wal = Wal::create(
+            "test_wal_interval".to_string(),
+            SyncMode::Interval { millis: 10 },
+        )
+        .unwrap();
+        wal.commit(wal.append(1, 1, || true).unwrap().unwrap())
+            .unwrap();
+        // a quiet log still gets synced by its thread
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(!wal.log.group.lock().unwrap().unsynced);
+        wal.commit(wal.append(2, 2, || true).unwrap().unwrap())
+            .unwrap();
+        let log = wal.log.clone();
+        wal.delete().unwrap();
+        drop(wal);
+        // and once more when it's dropped
+        let group = log.group.lock().unwrap();
+        assert!(!group.unsynced && group.failed.is_none());
+    }
+
+    #[test]
+    fn test_failed_write() {
+        // every write to /dev/full fails with a full disk
+        let file = OpenOptions::new().append(true).open("/dev/full").unwrap();
+        let wal = Wal::with_file("/dev/full".to_string(), file, SyncMode::EveryWrite);
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let wal = &wal;
+                scope.spawn(move || {
+                    // a writer either finds the log failed already or waits for a leader that
+                    // fails, but none of them is left waiting
+                    match wal.append(thread, 0, || true) {
+                        Ok(group) => assert!(wal.commit(group.unwrap()).is_err()),
+                        Err(error) => assert_eq!(error.kind(), io::ErrorKind::StorageFull),
+                    }
+                });
+            }
+        });
+        let mut stored = false;
+        assert!(wal
+            .append(8, 0, || {
+                stored = true;
+                true
+            })
+            .is_err());
+        assert!(!stored);
+        let group = wal.log.group.lock().unwrap();
+        assert!(!group.writing);
+        assert_eq!(group.committed, 0);
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let _ = std::fs::remove_file("test_wal_torn");
+        let wal = Wal::create("test_wal_torn".to_string(), SyncMode::Never).unwrap();
+        for i in 0..10 {
+            wal.commit(wal.append(i, -i, || true).unwrap().unwrap())
+                .unwrap();
+        }
+        let mut bytes = std::fs::read("test_wal_torn").unwrap();
+        bytes.truncate(9 * RECORD_SIZE + 20);
+        bytes[5 * RECORD_SIZE + 3] ^= 1;
+        std::fs::write("test_wal_torn", &bytes).unwrap();
+        assert!(Wal::read("test_wal_torn")
+            .unwrap()
+            .into_iter()
+            .eq((0..5).map(|i| (i, -i))));
+        wal.delete().unwrap();
+        assert_eq!(log_number("wal_12.log"), Some(12));
+        assert_eq!(log_number("b_tree_1"), None);
+    }
+}
@@ -2,47 +2,108 @@
 #![cfg_attr(target_os = "linux", feature(unix_file_ext))]
 
 use positioned_io::{RandomAccessFile, ReadAt, Size};
+use std::fmt::{Debug, Formatter};
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 
 // use std::fs::OpenOptions;
 // use std::os::windows::fs::{FileExt, OpenOptionsExt};
 // use std::os::unix::fs::{FileExt, OpenOptionsExt};
 use crate::buffer::{Buffer, PAGE_SIZE};
+use crate::error::{Error, Result};
 
-#[derive(Debug)]
+/// numbers the files opened by the process, see `Reader::id`
+static NEXT_READER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// told when the last `Reader` of a file is dropped, so it can forget the pages of the file
+pub trait FileClosed: Send + Sync {
+    fn file_closed(&self, file_id: u64);
+}
+
+/// the open file shared by the clones of a `Reader`
+struct OpenFile {
+    file: RandomAccessFile,
+    id: u64,
+    on_close: OnceLock<Arc<dyn FileClosed>>,
+}
+
+impl Debug for OpenFile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OpenFile({})", self.id)
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        if let Some(on_close) = self.on_close.get() {
+            on_close.file_closed(self.id);
+        }
+    }
+}
+
+/// Reads pages with positioned reads, so one file can be read from many threads at once.
+/// Clones share the open file, which stays readable even after it has been deleted.
+#[derive(Debug, Clone)]
 pub struct Reader {
-    pub(crate) file: RandomAccessFile,
+    file: Arc<OpenFile>,
     pub(crate) file_name: String,
+    /// different for every file opened, even if a file later takes the name of one that was
+    /// deleted, so pages can be cached by it
+    pub(crate) id: u64,
 }
 
 impl Reader {
     pub(crate) fn new(file_name: &str) -> Self {
+        Self::open(file_name).unwrap()
+    }
+
+    pub(crate) fn open(file_name: &str) -> std::io::Result<Self> {
         #[cfg(target_os = "windows")]
-        let file = OpenOptions::new().read(true).open(file_name).unwrap();
+        let file = OpenOptions::new().read(true).open(file_name)?;
 
         #[cfg(target_os = "linux")]
         let file = OpenOptions::new()
             .read(true)
             .custom_flags(libc::O_DIRECT)
-            .open(file_name)
-            .unwrap();
-        let file = RandomAccessFile::try_new(file).unwrap();
-        Self {
+            .open(file_name)?;
+        let id = NEXT_READER_ID.fetch_add(1, Ordering::Relaxed);
+        let file = Arc::new(OpenFile {
+            file: RandomAccessFile::try_new(file)?,
+            id,
+            on_close: OnceLock::new(),
+        });
+        Ok(Self {
             file,
             file_name: file_name.to_string(),
-        }
+            id,
+        })
+    }
+
+    /// has `on_close` told once this reader and all its clones are dropped. Only the first call
+    /// for a file counts.
+    pub(crate) fn on_close(&self, on_close: Arc<dyn FileClosed>) {
+        let _ = self.file.on_close.set(on_close);
     }
 
     pub fn file_size(&self) -> u64 {
-        self.file.size().unwrap().unwrap()
+        self.file.file.size().unwrap().unwrap()
     }
 
-    pub fn read_page(&mut self, buffer: &mut Buffer, page_num: u64) {
+    /// reads a page and checks it against its checksum.
+    pub fn read_page(&self, buffer: &mut Buffer, page_num: u64) -> Result<()> {
         self.file
-            .read_exact_at(page_num * PAGE_SIZE as u64, buffer)
-            .unwrap();
+            .file
+            .read_exact_at(page_num * PAGE_SIZE as u64, buffer)?;
+        if !buffer.is_intact() {
+            return Err(Error::Corruption {
+                file_name: self.file_name.clone(),
+                page: page_num,
+            });
+        }
+        Ok(())
     }
 }
 
@@ -85,13 +146,16 @@ impl Writer {
         }
     }
 
-    pub fn write_page(&mut self, buffer: &Buffer) {
+    /// seals the page with its checksum and appends it to the file.
+    pub fn write_page(&mut self, buffer: &mut Buffer) {
+        buffer.seal();
         self.file.write_all(buffer).unwrap();
     }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::buffer::KEYS_PER_PAGE;
 
     // #[test]
     // fn unsafe_test(){
@@ -115,13 +179,37 @@ mod tests {
         let mut writer = Writer::new("test".to_string());
         let mut buffer = Buffer::new();
         for i in 0..10 {
-            buffer.as_mut_slice_i64().fill(i);
-            writer.write_page(&buffer);
+            for j in 0..KEYS_PER_PAGE {
+                buffer.set_key(j, i);
+            }
+            writer.write_page(&mut buffer);
         }
-        let mut reader = Reader::new("test");
+        let reader = Reader::new("test");
         for i in 0..10 {
-            reader.read_page(&mut buffer, i);
-            assert_eq!(buffer.as_slice_i64(), &[i as i64; PAGE_SIZE / 8]);
+            reader.read_page(&mut buffer, i).unwrap();
+            assert!((0..KEYS_PER_PAGE).all(|j| buffer.key(j) == i as i64));
+        }
+    }
+
+    #[cfg_attr(miri, ignore)]
+    #[test]
+    fn read_corrupted_page() {
+        let _ = std::fs::remove_file("test_corrupted");
+        let mut writer = Writer::new("test_corrupted".to_string());
+        let mut buffer = Buffer::new();
+        for _ in 0..3 {
+            writer.write_page(&mut buffer);
         }
+        let mut bytes = std::fs::read("test_corrupted").unwrap();
+        bytes[PAGE_SIZE + 10] ^= 0x10;
+        std::fs::write("test_corrupted", bytes).unwrap();
+        let reader = Reader::new("test_corrupted");
+        reader.read_page(&mut buffer, 0).unwrap();
+        assert!(matches!(
+            reader.read_page(&mut buffer, 1),
+            Err(Error::Corruption { page: 1, .. })
+        ));
+        reader.read_page(&mut buffer, 2).unwrap();
+        std::fs::remove_file("test_corrupted").unwrap();
     }
 }
@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Caps the memory the memtables of several databases take up together.
+///
+/// Every memtable counts its bytes against the manager as it grows and gives them back once it
+/// has been flushed and no reader holds on to it anymore. A database that finds the manager over
+/// its limit after a write flushes its memtable, so memory is given back by the databases being
+/// written to. Each memtable is still flushed on its own once it reaches `max_mem_table_bytes`.
+#[derive(Debug)]
+pub struct WriteBufferManager {
+    limit_bytes: usize,
+    used_bytes: AtomicUsize,
+}
+
+impl WriteBufferManager {
+    pub fn new(limit_bytes: usize) -> WriteBufferManager {
+        WriteBufferManager {
+            limit_bytes,
+            used_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// the bytes taken up by the memtables of all the databases
+    pub fn used_bytes(&self) -> usize {
+        self.used_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn limit_bytes(&self) -> usize {
+        self.limit_bytes
+    }
+
+    pub(crate) fn reserve(&self, bytes: usize) {
+        self.used_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn release(&self, bytes: usize) {
+        self.used_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn over_limit(&self) -> bool {
+        self.used_bytes() > self.limit_bytes
+    }
+}